@@ -0,0 +1,332 @@
+//! Python bindings for the emulator core, built as the `chip8` extension
+//! module with maturin.
+
+use numpy::{PyArray1, PyArray2, PyArrayMethods};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use rust_chip8::chip::{self, Platform, Quirks, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use rust_chip8::keypad::KeyEvent;
+use rust_chip8::savestate;
+
+create_exception!(
+    chip8,
+    MachineFault,
+    PyRuntimeError,
+    "The program faulted, as by running data or reaching past the end of \
+     memory, and the machine has halted until the next `load` or \
+     `load_state`."
+);
+
+/// A CHIP-8 machine.
+///
+/// `Chip(platform="modernChip8", quirks=None, instructions_per_frame=10)`
+/// takes a community database platform id and a dict overriding any of the
+/// platform's quirks by name, such as `{"shift": True}`.
+#[pyclass(name = "Chip", module = "chip8")]
+struct Chip {
+    chip: chip::Chip,
+    instructions_per_frame: u32,
+}
+
+/// A copy of a whole machine from `Chip.save_state`.
+///
+/// States pickle, and `to_bytes` and `State.from_bytes` turn them into
+/// bytes and back, so they can be written to disk or sent to another
+/// process.
+#[pyclass(module = "chip8", frozen)]
+struct State {
+    chip: chip::Chip,
+}
+
+#[pymethods]
+impl State {
+    fn to_bytes<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let bytes =
+            savestate::save(&self.chip).map_err(|err| PyValueError::new_err(err.to_string()))?;
+        Ok(PyBytes::new(py, &bytes))
+    }
+
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<State> {
+        let chip = savestate::load(data).map_err(|err| PyValueError::new_err(err.to_string()))?;
+        Ok(State { chip })
+    }
+
+    /// Pickles as the bytes from `to_bytes`.
+    fn __reduce__<'py>(
+        slf: &Bound<'py, Self>,
+    ) -> PyResult<(Bound<'py, PyAny>, (Bound<'py, PyBytes>,))> {
+        let from_bytes = slf.get_type().getattr("from_bytes")?;
+        Ok((from_bytes, (slf.get().to_bytes(slf.py())?,)))
+    }
+}
+
+#[pymethods]
+impl Chip {
+    #[new]
+    #[pyo3(signature = (
+        platform = "modernChip8",
+        quirks = None,
+        instructions_per_frame = DEFAULT_INSTRUCTIONS_PER_FRAME,
+    ))]
+    fn new(
+        platform: &str,
+        quirks: Option<&Bound<'_, PyDict>>,
+        instructions_per_frame: u32,
+    ) -> PyResult<Chip> {
+        let platform = Platform::from_id(platform)
+            .ok_or_else(|| PyValueError::new_err(format!("unknown platform '{}'", platform)))?;
+        let mut chip = chip::Chip::with_platform(platform);
+        if let Some(quirks) = quirks {
+            override_quirks(&mut chip.quirks, quirks)?;
+        }
+
+        Ok(Chip {
+            chip,
+            instructions_per_frame,
+        })
+    }
+
+    /// Starts `rom` on a fresh machine of the same platform and quirks.
+    fn load(&mut self, rom: &[u8]) -> PyResult<()> {
+        let mut chip = chip::Chip::with_platform(self.chip.platform);
+        chip.quirks = self.chip.quirks;
+        chip.load_bytes(rom)
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        self.chip = chip;
+        Ok(())
+    }
+
+    /// Executes `count` instructions without running the timers. Raises
+    /// `MachineFault` if the program faults.
+    #[pyo3(signature = (count = 1))]
+    fn step(&mut self, count: u32) -> PyResult<()> {
+        for _ in 0..count {
+            self.chip.cycle();
+        }
+        self.check_fault()
+    }
+
+    /// Runs `count` frames of `instructions_per_frame` instructions, each
+    /// followed by a timer tick. Raises `MachineFault` if the program
+    /// faults.
+    #[pyo3(signature = (count = 1))]
+    fn run_frames(&mut self, count: u32) -> PyResult<()> {
+        for _ in 0..count {
+            self.chip
+                .run_frame_until(self.instructions_per_frame, |_| false);
+            self.check_fault()?;
+        }
+        self.check_fault()
+    }
+
+    /// Why the machine halted, or `None` while it runs.
+    #[getter]
+    fn fault(&self) -> Option<String> {
+        self.chip.fault.map(|fault| fault.to_string())
+    }
+
+    fn set_key(&mut self, key: u8, down: bool) -> PyResult<()> {
+        let key = check_key(key)?;
+        self.chip.keypad.apply(if down {
+            KeyEvent::Press(key)
+        } else {
+            KeyEvent::Release(key)
+        });
+        Ok(())
+    }
+
+    /// Holds exactly the keys in `keys` and lets go of the rest.
+    fn set_keys(&mut self, keys: Vec<u8>) -> PyResult<()> {
+        for key in &keys {
+            check_key(*key)?;
+        }
+        for key in 0..16 {
+            let down = keys.contains(&key);
+            if self.chip.keypad.is_down(key) != down {
+                self.set_key(key, down)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_key_down(&self, key: u8) -> PyResult<bool> {
+        Ok(self.chip.keypad.is_down(check_key(key)?))
+    }
+
+    fn read_memory<'py>(
+        &self,
+        py: Python<'py>,
+        address: usize,
+        length: usize,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let bytes = self
+            .chip
+            .memory()
+            .get(address..address.saturating_add(length))
+            .ok_or_else(|| PyValueError::new_err("range is outside memory"))?;
+        Ok(PyBytes::new(py, bytes))
+    }
+
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> PyResult<()> {
+        let in_memory = address
+            .checked_add(data.len())
+            .is_some_and(|end| end <= self.chip.memory().len());
+        let address = u16::try_from(address)
+            .ok()
+            .filter(|_| in_memory)
+            .ok_or_else(|| PyValueError::new_err("range is outside memory"))?;
+        self.chip.write_memory(address, data);
+        Ok(())
+    }
+
+    /// The display as a `(height, width)` NumPy array with a byte per
+    /// pixel, nonzero where lit.
+    fn framebuffer<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyArray2<u8>>> {
+        PyArray1::from_slice(py, &self.chip.video)
+            .reshape([self.chip.video_height, self.chip.video_width])
+    }
+
+    /// Copies the whole machine, to go back to with `load_state`.
+    fn save_state(&self) -> State {
+        State {
+            chip: self.chip.clone(),
+        }
+    }
+
+    fn load_state(&mut self, state: &State) {
+        self.chip = state.chip.clone();
+    }
+
+    #[getter]
+    fn platform(&self) -> &'static str {
+        self.chip.platform.id()
+    }
+
+    #[getter]
+    fn width(&self) -> usize {
+        self.chip.video_width
+    }
+
+    #[getter]
+    fn height(&self) -> usize {
+        self.chip.video_height
+    }
+
+    #[getter]
+    fn registers(&self) -> [u8; 16] {
+        self.chip.registers
+    }
+
+    #[setter]
+    fn set_registers(&mut self, registers: [u8; 16]) {
+        self.chip.registers = registers;
+    }
+
+    #[getter]
+    fn i(&self) -> u32 {
+        self.chip.index
+    }
+
+    #[setter]
+    fn set_i(&mut self, index: u32) -> PyResult<()> {
+        if index as usize >= self.chip.memory().len() {
+            return Err(PyValueError::new_err("I is outside memory"));
+        }
+        self.chip.index = index;
+        Ok(())
+    }
+
+    #[getter]
+    fn pc(&self) -> u16 {
+        self.chip.pc
+    }
+
+    #[setter]
+    fn set_pc(&mut self, pc: u16) -> PyResult<()> {
+        if pc as usize + 1 >= chip::MEMORY_SIZE {
+            return Err(PyValueError::new_err("PC is outside memory"));
+        }
+        self.chip.pc = pc;
+        Ok(())
+    }
+
+    #[getter]
+    fn sp(&self) -> u8 {
+        self.chip.sp
+    }
+
+    #[getter]
+    fn stack(&self) -> Vec<u16> {
+        self.chip.stack[..self.chip.sp as usize].to_vec()
+    }
+
+    #[getter]
+    fn delay_timer(&self) -> u8 {
+        self.chip.delay_timer
+    }
+
+    #[setter]
+    fn set_delay_timer(&mut self, value: u8) {
+        self.chip.delay_timer = value;
+    }
+
+    #[getter]
+    fn sound_timer(&self) -> u8 {
+        self.chip.sound_timer
+    }
+
+    #[setter]
+    fn set_sound_timer(&mut self, value: u8) {
+        self.chip.sound_timer = value;
+    }
+}
+
+impl Chip {
+    fn check_fault(&self) -> PyResult<()> {
+        match self.chip.fault {
+            Some(fault) => Err(MachineFault::new_err(format!(
+                "halted at {:03X}: {}",
+                self.chip.pc, fault
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn check_key(key: u8) -> PyResult<u8> {
+    match key {
+        0..=15 => Ok(key),
+        _ => Err(PyValueError::new_err("key must be 0 to 15")),
+    }
+}
+
+fn override_quirks(quirks: &mut Quirks, overrides: &Bound<'_, PyDict>) -> PyResult<()> {
+    for (name, value) in overrides.iter() {
+        let name: String = name.extract()?;
+        let value: bool = value.extract()?;
+        let quirk = match name.as_str() {
+            "shift" => &mut quirks.shift,
+            "memory_increment_by_x" => &mut quirks.memory_increment_by_x,
+            "memory_leave_i_unchanged" => &mut quirks.memory_leave_i_unchanged,
+            "wrap" => &mut quirks.wrap,
+            "jump" => &mut quirks.jump,
+            "vblank" => &mut quirks.vblank,
+            "logic" => &mut quirks.logic,
+            _ => return Err(PyValueError::new_err(format!("unknown quirk '{}'", name))),
+        };
+        *quirk = value;
+    }
+    Ok(())
+}
+
+#[pymodule]
+fn chip8(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Chip>()?;
+    module.add_class::<State>()?;
+    module.add("MachineFault", module.py().get_type::<MachineFault>())?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+"""Tests for the chip8 extension. Build it into the environment with
+`maturin develop`, then run `python -m unittest discover python/tests`."""
+
+import pickle
+import unittest
+
+import chip8
+
+# Draws random digits forever, storing each set of random numbers at 0x300.
+ROM = bytes([
+    0xC0, 0x3F,  # 200: V0 = random & 3F
+    0xC1, 0x1F,  # 202: V1 = random & 1F
+    0xC2, 0x0F,  # 204: V2 = random & 0F
+    0xF2, 0x29,  # 206: I = glyph V2
+    0xD0, 0x15,  # 208: draw it at V0, V1
+    0xA3, 0x00,  # 20A: I = 300
+    0xF2, 0x55,  # 20C: store V0-V2
+    0x12, 0x00,  # 20E: loop
+])
+
+
+def machine_state(chip):
+    return (chip.registers, chip.i, chip.pc, chip.read_memory(0, 4096))
+
+
+class StateTest(unittest.TestCase):
+    def setUp(self):
+        self.chip = chip8.Chip()
+        self.chip.load(ROM)
+        self.chip.run_frames(10)
+
+    def assert_carries_on(self, state):
+        self.chip.run_frames(10)
+        expected = machine_state(self.chip)
+
+        self.chip.load_state(state)
+        self.chip.run_frames(10)
+        self.assertEqual(machine_state(self.chip), expected)
+
+    def test_load_state_goes_back(self):
+        self.assert_carries_on(self.chip.save_state())
+
+    def test_states_pickle(self):
+        state = pickle.loads(pickle.dumps(self.chip.save_state()))
+        self.assert_carries_on(state)
+
+    def test_states_round_trip_through_bytes(self):
+        data = self.chip.save_state().to_bytes()
+        self.assert_carries_on(chip8.State.from_bytes(data))
+
+    def test_corrupt_bytes_are_refused(self):
+        with self.assertRaises(ValueError):
+            chip8.State.from_bytes(b"not a state")
+
+
+class RangeTest(unittest.TestCase):
+    def setUp(self):
+        self.chip = chip8.Chip()
+
+    def test_write_memory_stays_in_memory(self):
+        start = self.chip.read_memory(0, 2)
+        self.chip.write_memory(4094, b"\x01\x02")
+        self.assertEqual(self.chip.read_memory(4094, 2), b"\x01\x02")
+        with self.assertRaises(ValueError):
+            self.chip.write_memory(4095, b"\x01\x02")
+        with self.assertRaises(ValueError):
+            self.chip.write_memory(70000, b"\x01")
+        self.assertEqual(self.chip.read_memory(0, 2), start)
+
+    def test_read_memory_stays_in_memory(self):
+        with self.assertRaises(ValueError):
+            self.chip.read_memory(4095, 2)
+
+    def test_i_stays_in_memory(self):
+        self.chip.i = 4095
+        self.assertEqual(self.chip.i, 4095)
+        with self.assertRaises(ValueError):
+            self.chip.i = 4096
+
+    def test_pc_stays_in_memory(self):
+        with self.assertRaises(ValueError):
+            self.chip.pc = 4095
+
+
+class FaultTest(unittest.TestCase):
+    def test_running_data_raises(self):
+        chip = chip8.Chip()
+        chip.load(bytes([0x12, 0x04, 0x00, 0x00, 0xFF, 0xFF]))
+        with self.assertRaises(chip8.MachineFault):
+            chip.run_frames(1)
+        self.assertEqual(chip.pc, 0x204)
+        self.assertEqual(chip.fault, "undefined instruction FFFF")
+
+        chip.load(ROM)
+        self.assertIsNone(chip.fault)
+        chip.run_frames(1)
+
+
+if __name__ == "__main__":
+    unittest.main()
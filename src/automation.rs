@@ -0,0 +1,515 @@
+use serde_json::{json, Value};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::chip::{Chip, MEMORY_SIZE};
+use crate::debugger::{Debugger, Stop};
+use crate::keypad::KeyEvent;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The ROM named by `load` or `reset` couldn't be started.
+const LOAD_FAILED: i64 = -32000;
+/// The program faulted and the machine has halted until the next `load`
+/// or `reset`.
+const MACHINE_FAULT: i64 = -32001;
+/// How far a client can fall behind reading before it is dropped.
+const MAX_OUTGOING: usize = 1 << 24;
+
+/// Something only the main loop can do for a client.
+pub enum Control {
+    /// Start the ROM at this path, as `--rom` would.
+    Load(String),
+    /// Start the running ROM over.
+    Reset,
+}
+
+type RpcResult = Result<Value, (i64, String)>;
+
+/// A JSON-RPC 2.0 server on a local TCP port, for driving the emulator from
+/// test tools. Requests and responses are one JSON object per line. Like
+/// the GDB and DAP servers it is polled between frames and drives the
+/// shared `Debugger`.
+///
+/// Methods, with their params:
+///
+/// - `load {path}` and `reset` start a ROM, or the running one over,
+///   paused.
+/// - `pause`, `resume` and `step {count}` run and stop the machine, with
+///   `count` in instructions. `runFrames {count}` runs that many frames,
+///   or to a breakpoint, and answers once paused again. All three answer
+///   with an error once the program has faulted.
+/// - `getRegisters` and `setRegisters {v, i, pc, sp, delayTimer,
+///   soundTimer}`, where every param is optional. `getRegisters` also
+///   gives `fault`, the reason the machine halted or null.
+/// - `readMemory {address, length}` and `writeMemory {address, data}` with
+///   the bytes as an array of numbers.
+/// - `setKeys {down}` holds exactly the keys listed; `setKey {key, down}`
+///   presses or releases one.
+/// - `getFramebuffer` returns `width`, `height` and a byte per pixel.
+/// - `subscribe` and `unsubscribe` turn `frame {frame, pc}` notifications
+///   on and off.
+pub struct AutomationServer {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    buffer: Vec<u8>,
+    /// Responses and notifications the client hasn't taken yet. The socket
+    /// doesn't block, so they're queued and written out as it drains.
+    outgoing: Vec<u8>,
+    subscribed: bool,
+    /// Frames run since the server started.
+    frame: u64,
+    /// The `load` or `reset` request waiting on the main loop.
+    pending_load: Option<Value>,
+    /// The `runFrames` request waiting, and how many frames it has left.
+    pending_frames: Option<(Value, u64)>,
+}
+
+impl AutomationServer {
+    pub fn bind(port: u16) -> io::Result<AutomationServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        println!("Automation server listening on 127.0.0.1:{}", port);
+
+        Ok(AutomationServer {
+            listener,
+            client: None,
+            buffer: Vec::new(),
+            outgoing: Vec::new(),
+            subscribed: false,
+            frame: 0,
+            pending_load: None,
+            pending_frames: None,
+        })
+    }
+
+    /// Handles client requests. Returns a ROM to start when the client
+    /// asked for one.
+    pub fn poll(&mut self, chip: &mut Chip, debugger: &mut Debugger) -> Option<Control> {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, address)) if stream.set_nonblocking(true).is_ok() => {
+                    println!("Automation client connected from {}", address);
+                    self.client = Some(stream);
+                    self.buffer.clear();
+                    self.outgoing.clear();
+                    self.subscribed = false;
+                }
+                Ok(_) => return None,
+                Err(err) => {
+                    if err.kind() != ErrorKind::WouldBlock {
+                        eprintln!("Automation server: {}", err);
+                    }
+                    return None;
+                }
+            }
+        }
+
+        let control = self.serve(chip, debugger);
+        match control.and_then(|control| self.flush().map(|()| control)) {
+            Ok(control) => control,
+            Err(err) => {
+                println!("Automation client disconnected: {}", err);
+                self.client = None;
+                self.pending_load = None;
+                self.pending_frames = None;
+                None
+            }
+        }
+    }
+
+    /// Answers the waiting `load` or `reset` once the main loop has tried
+    /// to start the ROM.
+    pub fn finish_load(
+        &mut self,
+        result: Result<(), String>,
+        chip: &Chip,
+        debugger: &mut Debugger,
+    ) {
+        let Some(id) = self.pending_load.take() else {
+            return;
+        };
+
+        let reply = match result {
+            Ok(()) => {
+                self.finish_frames(chip);
+                debugger.paused = true;
+                Ok(json!({}))
+            }
+            Err(message) => Err((LOAD_FAILED, message)),
+        };
+        self.reply(id, reply);
+    }
+
+    /// Called after each frame the machine runs, to notify subscribers and
+    /// count down `runFrames`.
+    pub fn end_frame(&mut self, chip: &Chip, debugger: &mut Debugger) {
+        self.frame += 1;
+        if self.subscribed {
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "frame",
+                "params": { "frame": self.frame, "pc": chip.pc },
+            });
+            self.send(&notification);
+        }
+
+        match &mut self.pending_frames {
+            Some((_, left)) if *left > 1 && !debugger.paused => *left -= 1,
+            Some(_) => {
+                if !debugger.paused {
+                    debugger.pause(Stop::Pause);
+                }
+                self.finish_frames(chip);
+            }
+            None => {}
+        }
+    }
+
+    /// Answers the waiting `runFrames`, if any, with where the machine got
+    /// to.
+    fn finish_frames(&mut self, chip: &Chip) {
+        if let Some((id, _)) = self.pending_frames.take() {
+            let result = match fault(chip) {
+                Some(err) => Err(err),
+                None => Ok(json!({ "frame": self.frame, "pc": chip.pc })),
+            };
+            self.reply(id, result);
+        }
+    }
+
+    fn serve(&mut self, chip: &mut Chip, debugger: &mut Debugger) -> io::Result<Option<Control>> {
+        let mut chunk = [0; 4096];
+        loop {
+            let client = self.client.as_mut().unwrap();
+            match client.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "closed")),
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut control = None;
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            let request: Value = match serde_json::from_slice(&line) {
+                Ok(request) => request,
+                Err(err) => {
+                    self.reply(Value::Null, Err((PARSE_ERROR, err.to_string())));
+                    continue;
+                }
+            };
+            let id = request["id"].clone();
+            let method = request["method"].as_str().unwrap_or("");
+            let params = &request["params"];
+
+            match method {
+                "load" | "reset" if self.pending_load.is_some() => {
+                    let message = "a load is already in progress".to_owned();
+                    self.reply(id, Err((LOAD_FAILED, message)));
+                }
+                "load" => match params["path"].as_str() {
+                    Some(path) => {
+                        self.pending_load = Some(id);
+                        control = Some(Control::Load(path.to_owned()));
+                    }
+                    None => self.reply(id, Err(invalid("missing 'path'"))),
+                },
+                "reset" => {
+                    self.pending_load = Some(id);
+                    control = Some(Control::Reset);
+                }
+                "runFrames" if self.pending_frames.is_some() => {
+                    let message = "frames are already running".to_owned();
+                    self.reply(id, Err((INVALID_PARAMS, message)));
+                }
+                "runFrames" => match fault(chip).map_or_else(|| count(params), Err) {
+                    Ok(count) => {
+                        debugger.resume(chip);
+                        self.pending_frames = Some((id, count));
+                    }
+                    Err(err) => self.reply(id, Err(err)),
+                },
+                _ => {
+                    let result = self.handle(method, params, chip, debugger);
+                    self.reply(id, result);
+                }
+            }
+
+            // Leave the rest for the ROM being started.
+            if control.is_some() {
+                break;
+            }
+        }
+
+        Ok(control)
+    }
+
+    fn handle(
+        &mut self,
+        method: &str,
+        params: &Value,
+        chip: &mut Chip,
+        debugger: &mut Debugger,
+    ) -> RpcResult {
+        match method {
+            "pause" => {
+                debugger.pause(Stop::Pause);
+                self.finish_frames(chip);
+                Ok(json!({ "pc": chip.pc }))
+            }
+            "resume" => {
+                if let Some(err) = fault(chip) {
+                    return Err(err);
+                }
+                debugger.resume(chip);
+                Ok(json!({}))
+            }
+            "step" => {
+                let count =
+                    u16::try_from(count(params)?).map_err(|_| invalid("count too large"))?;
+                debugger.step(chip, count);
+                match fault(chip) {
+                    Some(err) => Err(err),
+                    None => Ok(json!({ "pc": chip.pc })),
+                }
+            }
+            "getRegisters" => Ok(json!({
+                "v": chip.registers,
+                "i": chip.index,
+                "pc": chip.pc,
+                "sp": chip.sp,
+                "stack": chip.stack,
+                "delayTimer": chip.delay_timer,
+                "soundTimer": chip.sound_timer,
+                "fault": chip.fault.map(|fault| fault.to_string()),
+            })),
+            "setRegisters" => set_registers(chip, params),
+            "readMemory" => {
+                let address = number(params, "address")? as usize;
+                let length = number(params, "length")? as usize;
+                let end = address.saturating_add(length);
+                let bytes = chip
+                    .memory()
+                    .get(address..end)
+                    .ok_or_else(|| invalid("range is outside memory"))?;
+                Ok(json!({ "data": bytes }))
+            }
+            "writeMemory" => {
+                let address = number(params, "address")?;
+                let address = u16::try_from(address).map_err(|_| invalid("address too large"))?;
+                let data = bytes(&params["data"])?;
+                if address as usize + data.len() > chip.memory().len() {
+                    return Err(invalid("range is outside memory"));
+                }
+                chip.write_memory(address, &data);
+                Ok(json!({ "written": data.len() }))
+            }
+            "setKeys" => {
+                let keys = bytes(&params["down"])?;
+                for key in 0..16 {
+                    let down = keys.contains(&key);
+                    if chip.keypad.is_down(key) != down {
+                        chip.keypad.apply(if down {
+                            KeyEvent::Press(key)
+                        } else {
+                            KeyEvent::Release(key)
+                        });
+                    }
+                }
+                Ok(json!({}))
+            }
+            "setKey" => {
+                let key = number(params, "key")?;
+                let key = u8::try_from(key)
+                    .ok()
+                    .filter(|key| *key < 16)
+                    .ok_or_else(|| invalid("key must be 0 to 15"))?;
+                chip.keypad.apply(match params["down"].as_bool() {
+                    Some(true) => KeyEvent::Press(key),
+                    Some(false) => KeyEvent::Release(key),
+                    None => return Err(invalid("missing 'down'")),
+                });
+                Ok(json!({}))
+            }
+            "getFramebuffer" => Ok(json!({
+                "width": chip.video_width,
+                "height": chip.video_height,
+                "pixels": chip.video,
+            })),
+            "subscribe" => {
+                self.subscribed = true;
+                Ok(json!({}))
+            }
+            "unsubscribe" => {
+                self.subscribed = false;
+                Ok(json!({}))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method '{}'", method))),
+        }
+    }
+
+    /// Queues `message` for the next `flush`, if a client is connected.
+    fn send(&mut self, message: &Value) {
+        if self.client.is_some() {
+            self.outgoing
+                .extend_from_slice(message.to_string().as_bytes());
+            self.outgoing.push(b'\n');
+        }
+    }
+
+    /// Writes as much of the queue as the client will take without
+    /// blocking.
+    fn flush(&mut self) -> io::Result<()> {
+        let Some(client) = self.client.as_mut() else {
+            return Ok(());
+        };
+
+        let mut written = 0;
+        while written < self.outgoing.len() {
+            match client.write(&self.outgoing[written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(len) => written += len,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.outgoing.drain(..written);
+
+        if self.outgoing.len() > MAX_OUTGOING {
+            return Err(io::Error::other("client stopped reading"));
+        }
+        Ok(())
+    }
+
+    fn reply(&mut self, id: Value, result: RpcResult) {
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        self.send(&response);
+    }
+}
+
+/// The error for a machine halted on a fault, if it is.
+fn fault(chip: &Chip) -> Option<(i64, String)> {
+    let message = |fault| format!("halted at {:03X}: {}", chip.pc, fault);
+    chip.fault.map(|fault| (MACHINE_FAULT, message(fault)))
+}
+
+fn invalid(message: &str) -> (i64, String) {
+    (INVALID_PARAMS, message.to_owned())
+}
+
+fn number(params: &Value, name: &str) -> Result<u64, (i64, String)> {
+    params[name]
+        .as_u64()
+        .ok_or_else(|| invalid(&format!("'{}' must be a number", name)))
+}
+
+/// The optional `count` param, 1 when left out.
+fn count(params: &Value) -> Result<u64, (i64, String)> {
+    match &params["count"] {
+        Value::Null => Ok(1),
+        _ => number(params, "count").and_then(|count| match count {
+            0 => Err(invalid("'count' must be at least 1")),
+            count => Ok(count),
+        }),
+    }
+}
+
+fn bytes(value: &Value) -> Result<Vec<u8>, (i64, String)> {
+    value
+        .as_array()
+        .ok_or_else(|| invalid("expected an array of bytes"))?
+        .iter()
+        .map(|byte| {
+            byte.as_u64()
+                .and_then(|byte| u8::try_from(byte).ok())
+                .ok_or_else(|| invalid("expected an array of bytes"))
+        })
+        .collect()
+}
+
+fn set_registers(chip: &mut Chip, params: &Value) -> RpcResult {
+    let optional = |name: &str, limit: u64| match &params[name] {
+        Value::Null => Ok(None),
+        value => value
+            .as_u64()
+            .filter(|value| *value <= limit)
+            .map(Some)
+            .ok_or_else(|| invalid(&format!("'{}' must be at most {}", name, limit))),
+    };
+
+    // Check everything before changing anything.
+    let v = match &params["v"] {
+        Value::Null => None,
+        value => match bytes(value)? {
+            v if v.len() == 16 => Some(v),
+            _ => return Err(invalid("'v' must be 16 bytes")),
+        },
+    };
+    let i = optional("i", chip.memory().len() as u64 - 1)?;
+    let pc = optional("pc", MEMORY_SIZE as u64 - 2)?;
+    let sp = optional("sp", chip.stack.len() as u64)?;
+    let delay_timer = optional("delayTimer", u8::MAX as u64)?;
+    let sound_timer = optional("soundTimer", u8::MAX as u64)?;
+
+    if let Some(v) = v {
+        chip.registers.copy_from_slice(&v);
+    }
+    if let Some(i) = i {
+        chip.index = i as u32;
+    }
+    if let Some(pc) = pc {
+        chip.pc = pc as u16;
+    }
+    if let Some(sp) = sp {
+        chip.sp = sp as u8;
+    }
+    if let Some(delay_timer) = delay_timer {
+        chip.delay_timer = delay_timer as u8;
+    }
+    if let Some(sound_timer) = sound_timer {
+        chip.sound_timer = sound_timer as u8;
+    }
+    Ok(json!({}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faults_answer_with_errors() {
+        let mut server = AutomationServer::bind(0).unwrap();
+        let mut chip = Chip::new();
+        chip.load_bytes(&[0x12, 0x04, 0x00, 0x00, 0xFF, 0xFF])
+            .unwrap();
+        let mut debugger = Debugger::new();
+
+        let count = json!({ "count": 2 });
+        let (code, message) = server
+            .handle("step", &count, &mut chip, &mut debugger)
+            .unwrap_err();
+        assert_eq!(code, MACHINE_FAULT);
+        assert_eq!(message, "halted at 204: undefined instruction FFFF");
+        assert_eq!(debugger.last_stop, Some(Stop::Fault(chip.fault.unwrap())));
+
+        let resumed = server.handle("resume", &Value::Null, &mut chip, &mut debugger);
+        assert_eq!(resumed.unwrap_err().0, MACHINE_FAULT);
+        let registers = server.handle("getRegisters", &Value::Null, &mut chip, &mut debugger);
+        assert_eq!(registers.unwrap()["fault"], "undefined instruction FFFF");
+    }
+}
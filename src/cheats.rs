@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::chip::{Chip, MEMORY_SIZE};
+use crate::romdb::sha1_hex;
+use crate::symbols::SymbolTable;
+
+pub const DEFAULT_CHEATS_PATH: &str = "cheats.toml";
+
+/// A byte a cheat can search or freeze: a memory address or a V register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Memory(u16),
+    Register(u8),
+}
+
+impl Location {
+    /// Parses `V0`-`VF`, or a label or hex address.
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Option<Location> {
+        let register = text
+            .strip_prefix(['V', 'v'])
+            .filter(|digit| digit.len() == 1)
+            .and_then(|digit| u8::from_str_radix(digit, 16).ok());
+
+        match register {
+            Some(register) => Some(Location::Register(register)),
+            None => symbols
+                .resolve(text)
+                .filter(|address| (*address as usize) < MEMORY_SIZE)
+                .map(Location::Memory),
+        }
+    }
+
+    pub fn read(self, chip: &Chip) -> u8 {
+        match self {
+            Location::Memory(address) => chip.memory[address as usize],
+            Location::Register(register) => chip.registers[register as usize],
+        }
+    }
+
+    pub fn write(self, chip: &mut Chip, value: u8) {
+        match self {
+            Location::Memory(address) => chip.write_memory(address, &[value]),
+            Location::Register(register) => chip.registers[register as usize] = value,
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Memory(address) => write!(f, "{:03X}", address),
+            Location::Register(register) => write!(f, "V{:X}", register),
+        }
+    }
+}
+
+/// How a search narrows its candidates against the previous snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Filter {
+    fn keeps(self, before: u8, now: u8) -> bool {
+        match self {
+            Filter::Equal(value) => now == value,
+            Filter::Changed => now != before,
+            Filter::Unchanged => now == before,
+            Filter::Increased => now > before,
+            Filter::Decreased => now < before,
+        }
+    }
+}
+
+/// Candidate locations with the values they held when last narrowed.
+#[derive(Debug, Clone)]
+pub struct Search {
+    pub candidates: Vec<(Location, u8)>,
+}
+
+impl Search {
+    /// Starts a search over all of memory and the V registers.
+    pub fn new(chip: &Chip) -> Search {
+        let locations = (0..MEMORY_SIZE as u16)
+            .map(Location::Memory)
+            .chain((0..16).map(Location::Register));
+
+        Search {
+            candidates: locations
+                .map(|location| (location, location.read(chip)))
+                .collect(),
+        }
+    }
+
+    /// Keeps the candidates that pass `filter` and snapshots their values.
+    pub fn narrow(&mut self, chip: &Chip, filter: Filter) {
+        self.candidates.retain_mut(|(location, before)| {
+            let now = location.read(chip);
+            let keep = filter.keeps(*before, now);
+            *before = now;
+            keep
+        });
+    }
+}
+
+/// A location held at a constant value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub name: String,
+    pub location: Location,
+    pub value: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CheatEntry {
+    location: String,
+    value: u8,
+}
+
+/// Cheats by ROM hash, then by name.
+type CheatFile = BTreeMap<String, BTreeMap<String, CheatEntry>>;
+
+/// The cheat state for the running ROM: the current search and the frozen
+/// locations. Named cheats are saved per ROM hash to a TOML file:
+///
+/// ```toml
+/// [f1cfcffe1937ed6dd6eeed1a7f85dfc777bda700]
+/// lives = { location = "V3", value = 9 }
+/// ```
+#[derive(Debug, Default)]
+pub struct Cheats {
+    pub search: Option<Search>,
+    pub frozen: Vec<Cheat>,
+    path: PathBuf,
+    rom_hash: String,
+}
+
+impl Cheats {
+    /// No cheats for `rom`, saving to the file at `path`.
+    pub fn new<P: AsRef<Path>>(path: P, rom: &[u8]) -> Cheats {
+        Cheats {
+            path: path.as_ref().to_path_buf(),
+            rom_hash: sha1_hex(rom),
+            ..Cheats::default()
+        }
+    }
+
+    /// Loads the cheats saved for `rom` in the file at `path`, if any.
+    pub fn load<P: AsRef<Path>>(path: P, rom: &[u8]) -> Result<Cheats, Box<dyn Error>> {
+        let mut cheats = Cheats::new(path, rom);
+
+        let symbols = SymbolTable::default();
+        if let Some(entries) = read_file(&cheats.path)?.remove(&cheats.rom_hash) {
+            for (name, entry) in entries {
+                let location = Location::parse(&entry.location, &symbols)
+                    .ok_or_else(|| format!("bad location '{}' for '{}'", entry.location, name))?;
+                cheats.frozen.push(Cheat {
+                    name,
+                    location,
+                    value: entry.value,
+                });
+            }
+        }
+
+        Ok(cheats)
+    }
+
+    /// Writes the frozen cheats back under the ROM's hash, keeping other
+    /// ROMs' cheats in the file.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut file = read_file(&self.path)?;
+        let entries = self
+            .frozen
+            .iter()
+            .map(|cheat| {
+                let entry = CheatEntry {
+                    location: cheat.location.to_string(),
+                    value: cheat.value,
+                };
+                (cheat.name.clone(), entry)
+            })
+            .collect();
+        file.insert(self.rom_hash.clone(), entries);
+        file.retain(|_, entries| !entries.is_empty());
+
+        fs::write(&self.path, toml::to_string(&file)?)?;
+        Ok(())
+    }
+
+    /// Freezes `location` at `value`, replacing any cheat with the same name
+    /// or location.
+    pub fn freeze(&mut self, cheat: Cheat) {
+        self.frozen
+            .retain(|other| other.name != cheat.name && other.location != cheat.location);
+        self.frozen.push(cheat);
+    }
+
+    /// Removes the cheat called `name` or freezing the location `name`
+    /// parses as, returning whether there was one.
+    pub fn unfreeze(&mut self, name: &str, symbols: &SymbolTable) -> bool {
+        let location = Location::parse(name, symbols);
+        let before = self.frozen.len();
+        self.frozen
+            .retain(|cheat| cheat.name != name && Some(cheat.location) != location);
+        self.frozen.len() != before
+    }
+
+    pub fn apply(&self, chip: &mut Chip) {
+        for cheat in &self.frozen {
+            cheat.location.write(chip, cheat.value);
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<CheatFile, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let contents = fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|err| format!("{}: {}", path.display(), err).into())
+}
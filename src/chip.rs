@@ -1,28 +1,362 @@
-use rand::prelude::random;
-use std::fmt::Debug;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
 use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::coverage::{self, Coverage};
 use crate::debugger;
+use crate::keypad::Keypad;
+use crate::megachip::{self, Blend, MegaChip};
+use crate::profiler::Profiler;
+use crate::symbols::SymbolTable;
+use crate::timing::{self, Timing};
+use crate::vip::Vip;
 
 const START_ADDRESS: u16 = 0x200;
+pub const MEMORY_SIZE: usize = 4096;
 const FONT_SET_SIZE: u32 = 80;
 const FONT_SET_START_ADDRESS: u32 = 0x50;
 pub const VIDEO_WIDTH: u8 = 64;
 pub const VIDEO_HEIGHT: u8 = 32;
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+/// The eight colours of the VP-590 colour board used by CHIP-8X, by colour
+/// code: black, red, blue, violet, green, yellow, aqua and white.
+pub const CHIP8X_COLORS: [(u8, u8, u8); 8] = [
+    (0x00, 0x00, 0x00),
+    (0xFF, 0x00, 0x00),
+    (0x00, 0x00, 0xFF),
+    (0xFF, 0x00, 0xFF),
+    (0x00, 0xFF, 0x00),
+    (0xFF, 0xFF, 0x00),
+    (0x00, 0xFF, 0xFF),
+    (0xFF, 0xFF, 0xFF),
+];
+/// Colour codes of the backgrounds `02A0` steps through, starting with the
+/// one the board powers up with.
+const CHIP8X_BACKGROUNDS: [u8; 4] = [2, 0, 4, 1];
+/// Foreground colour code of every zone until a ROM sets it.
+const CHIP8X_DEFAULT_COLOR: u8 = 1;
+/// Where the HiRes interpreter starts a program that opens with `1260`.
+const HIRES_ENTRY: u16 = 0x2C0;
+
+/// Sample rate of `Chip::audio_frame`.
+pub const AUDIO_RATE: u32 = 44_100;
+/// Pitch of the sound timer's beep in hertz.
+const BEEP_FREQUENCY: u32 = 440;
+/// Distance of the beep's square wave from silence.
+const BEEP_VOLUME: u8 = 0x20;
 
 #[derive(Debug)]
+pub enum RomError {
+    NotFound(PathBuf),
+    Empty,
+    TooLarge { size: usize, max: usize },
+    InvalidLoadAddress(u16),
+    Io(io::Error),
+}
+
+impl Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::NotFound(path) => write!(f, "ROM not found: {}", path.display()),
+            RomError::Empty => write!(f, "ROM is empty"),
+            RomError::TooLarge { size, max } => {
+                write!(f, "ROM is {} bytes, at most {} bytes fit", size, max)
+            }
+            RomError::InvalidLoadAddress(address) => {
+                write!(f, "load address {:#05X} is outside memory", address)
+            }
+            RomError::Io(err) => write!(f, "failed to read ROM: {}", err),
+        }
+    }
+}
+
+impl Error for RomError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RomError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
+
+/// Something a program did that no machine could carry on from. The
+/// machine halts with PC on the instruction at fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Fault {
+    /// The opcode isn't an instruction on this platform, as when a program
+    /// jumps into data.
+    UndefinedInstruction(u16),
+    /// PC ran off the end of memory.
+    PcOutOfRange,
+    /// An instruction reached past the end of memory from this I.
+    IndexOutOfRange(u32),
+    /// A call with all 16 stack entries in use.
+    StackOverflow,
+    /// A return with nothing on the stack.
+    StackUnderflow,
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::UndefinedInstruction(opcode) => {
+                write!(f, "undefined instruction {:04X}", opcode)
+            }
+            Fault::PcOutOfRange => write!(f, "PC ran past the end of memory"),
+            Fault::IndexOutOfRange(index) => {
+                write!(f, "I at {:#05X} reaches past the end of memory", index)
+            }
+            Fault::StackOverflow => write!(f, "call with a full stack"),
+            Fault::StackUnderflow => write!(f, "return with an empty stack"),
+        }
+    }
+}
+
+impl Error for Fault {}
+
+/// Behaviours that differ between CHIP-8 interpreters. The names follow the
+/// quirk names used by the community CHIP-8 database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quirks {
+    /// `8XY6`/`8XYE` shift VX in place instead of shifting VY into VX.
+    pub shift: bool,
+    /// `FX55`/`FX65` advance I by X instead of X + 1.
+    pub memory_increment_by_x: bool,
+    /// `FX55`/`FX65` leave I unchanged.
+    pub memory_leave_i_unchanged: bool,
+    /// Sprites wrap around the screen edges instead of being clipped.
+    pub wrap: bool,
+    /// `BNNN` jumps to XNN + VX instead of NNN + V0.
+    pub jump: bool,
+    /// `DXYN` waits for the next frame before drawing.
+    pub vblank: bool,
+    /// `8XY1`/`8XY2`/`8XY3` reset VF to zero.
+    pub logic: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift: true,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: false,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Platform {
+    OriginalChip8,
+    ModernChip8,
+    Chip48,
+    /// Two-page HiRes CHIP-8 with a 64x64 screen. Programs open with a
+    /// `1260` jump that enters the interpreter's 64x64 mode, and clear the
+    /// screen with `0230`.
+    HiresChip8,
+    /// CHIP-8X on a VIP with the VP-590 colour board and a second keypad.
+    /// Programs load at 0x300. The sound and I/O port instructions `FXF8`
+    /// and `FXFB` are accepted but do nothing.
+    Chip8X,
+    /// The ETI-660 interpreter: programs load at 0x600 and the screen is
+    /// 64x48.
+    Eti660,
+    /// MEGA-CHIP 8, with 16 MB of memory. It runs as CHIP-8 until `0011`
+    /// switches on its 256x192 colour mode.
+    MegaChip,
+}
+
+impl Platform {
+    /// Maps a community CHIP-8 database platform id to a platform we can run.
+    pub fn from_id(id: &str) -> Option<Platform> {
+        match id {
+            "originalChip8" => Some(Platform::OriginalChip8),
+            "modernChip8" => Some(Platform::ModernChip8),
+            "chip48" => Some(Platform::Chip48),
+            "hiresChip8" => Some(Platform::HiresChip8),
+            "chip8x" => Some(Platform::Chip8X),
+            "eti660" => Some(Platform::Eti660),
+            "megachip8" => Some(Platform::MegaChip),
+            _ => None,
+        }
+    }
+
+    pub fn id(self) -> &'static str {
+        match self {
+            Platform::OriginalChip8 => "originalChip8",
+            Platform::ModernChip8 => "modernChip8",
+            Platform::Chip48 => "chip48",
+            Platform::HiresChip8 => "hiresChip8",
+            Platform::Chip8X => "chip8x",
+            Platform::Eti660 => "eti660",
+            Platform::MegaChip => "megachip8",
+        }
+    }
+
+    /// Where programs are loaded and start running.
+    pub fn load_address(self) -> u16 {
+        match self {
+            Platform::Chip8X => 0x300,
+            Platform::Eti660 => 0x600,
+            _ => START_ADDRESS,
+        }
+    }
+
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::MegaChip => megachip::MEMORY_SIZE,
+            _ => MEMORY_SIZE,
+        }
+    }
+
+    /// Screen width and height in pixels, outside any mode a program can
+    /// switch to.
+    pub fn screen_size(self) -> (usize, usize) {
+        match self {
+            Platform::HiresChip8 => (64, 64),
+            Platform::Eti660 => (64, 48),
+            _ => (VIDEO_WIDTH as usize, VIDEO_HEIGHT as usize),
+        }
+    }
+
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::OriginalChip8
+            | Platform::HiresChip8
+            | Platform::Chip8X
+            | Platform::Eti660 => Quirks {
+                shift: false,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: false,
+                wrap: false,
+                jump: false,
+                vblank: true,
+                logic: true,
+            },
+            Platform::ModernChip8 => Quirks {
+                shift: false,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: false,
+                wrap: false,
+                jump: false,
+                vblank: false,
+                logic: false,
+            },
+            Platform::Chip48 | Platform::MegaChip => Quirks {
+                shift: true,
+                memory_increment_by_x: true,
+                memory_leave_i_unchanged: false,
+                wrap: false,
+                jump: true,
+                vblank: false,
+                logic: false,
+            },
+        }
+    }
+}
+
+/// An opcode together with the handler that executes it, so a cached
+/// instruction runs without being decoded again.
+#[derive(Clone, Copy)]
+pub struct Decoded {
+    pub opcode: u16,
+    pub execute: fn(&mut Chip),
+}
+
+impl Debug for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Decoded({:04X})", self.opcode)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Chip {
-    pub memory: [u8; 4096],
+    /// `MEMORY_SIZE` bytes, or more on platforms with wider addresses.
+    /// Programs only run from the first `MEMORY_SIZE`. Read through
+    /// `memory` and written through `write_memory`, which keeps the
+    /// instruction cache in step.
+    pub(crate) memory: Vec<u8>,
     pub registers: [u8; 16],
-    pub index: u16,
+    /// Only MEGA-CHIP sets bits above the twelfth.
+    pub index: u32,
     pub pc: u16,
     pub sp: u8,
     pub stack: [u16; 16],
     pub delay_timer: u8,
     pub sound_timer: u8,
-    pub keypad: [u8; 16],
-    pub video: [u8; VIDEO_WIDTH as usize * VIDEO_HEIGHT as usize],
+    pub keypad: Keypad,
+    /// The CHIP-8X's second keypad.
+    pub extra_keypad: Keypad,
+    /// One byte per pixel, `video_width` pixels to a row.
+    pub video: Vec<u8>,
+    pub video_width: usize,
+    pub video_height: usize,
+    /// CHIP-8X background, as an index into the backgrounds `02A0` steps
+    /// through.
+    pub background: usize,
+    /// CHIP-8X foreground colour code of each 8-pixel run of each row.
+    pub colors: Vec<u8>,
     pub opcode: u16,
+    /// Set when the program faults. The machine then stays halted, running
+    /// no instructions, until a ROM is loaded or this is cleared.
+    pub fault: Option<Fault>,
+    pub platform: Platform,
+    /// MEGA-CHIP state, only present on that platform.
+    pub mega: Option<Box<MegaChip>>,
+    /// A whole COSMAC VIP standing in for the instruction set, when the
+    /// interpreter itself is emulated. Frames and `cycle` run it instead.
+    pub vip: Option<Box<Vip>>,
+    pub quirks: Quirks,
+    pub load_address: u16,
+    pub symbols: Arc<SymbolTable>,
+    /// Opt-in execution profiling, off when `None`.
+    pub profiler: Option<Box<Profiler>>,
+    /// Opt-in record of executed, read and written memory, off when `None`.
+    pub coverage: Option<Box<Coverage>>,
+    pub timing: Timing,
+    /// Print every instruction as it runs.
+    pub trace: bool,
+    /// Reuse decoded instructions. Only turned off to measure the cache.
+    pub cache_decoding: bool,
+    /// Draws `CXNN`'s numbers. Seeded from the OS unless `reseed`, and
+    /// cloned and saved with the machine, so a copy draws the same numbers.
+    pub(crate) rng: ChaCha12Rng,
+    pub(crate) rom_len: usize,
+    /// Where the beep's square wave is through its cycle, in `1/AUDIO_RATE`
+    /// steps of `BEEP_FREQUENCY`.
+    pub(crate) beep_phase: u32,
+    /// Microseconds of VIP time the last frame ran over, taken out of the
+    /// next one.
+    pub(crate) vip_overrun: u32,
+    /// Whether the last instruction was a skip that skipped, which VIP
+    /// timing charges extra for.
+    pub(crate) skipped: bool,
+    /// Decoded instruction starting at each address, filled when the ROM
+    /// loads or the address first runs. Writes through `write_memory`,
+    /// `FX33` and `FX55` clear the entries they overlap.
+    decoded: Vec<Option<Decoded>>,
+}
+
+impl Default for Chip {
+    fn default() -> Self {
+        Chip::new()
+    }
 }
 
 impl Chip {
@@ -48,16 +382,39 @@ impl Chip {
 
         let mut chip = Chip {
             registers: [0; 16],
-            memory: [0; 4096],
+            memory: vec![0; MEMORY_SIZE],
             index: 0,
             pc: START_ADDRESS,
             sp: 0,
             stack: [0; 16],
             delay_timer: 0,
             sound_timer: 0,
-            keypad: [0; 16],
-            video: [0; 64 * 32],
+            keypad: Keypad::default(),
+            extra_keypad: Keypad::default(),
+            video: vec![0; VIDEO_WIDTH as usize * VIDEO_HEIGHT as usize],
+            video_width: VIDEO_WIDTH as usize,
+            video_height: VIDEO_HEIGHT as usize,
+            background: 0,
+            colors: vec![CHIP8X_DEFAULT_COLOR; VIDEO_WIDTH as usize / 8 * VIDEO_HEIGHT as usize],
             opcode: 0,
+            platform: Platform::ModernChip8,
+            mega: None,
+            vip: None,
+            quirks: Quirks::default(),
+            load_address: START_ADDRESS,
+            symbols: Arc::default(),
+            profiler: None,
+            coverage: None,
+            timing: Timing::Fixed,
+            trace: false,
+            cache_decoding: true,
+            rng: ChaCha12Rng::from_entropy(),
+            rom_len: 0,
+            beep_phase: 0,
+            vip_overrun: 0,
+            skipped: false,
+            fault: None,
+            decoded: vec![None; MEMORY_SIZE],
         };
 
         for (i, item) in font_set.iter().enumerate().take(FONT_SET_SIZE as usize) {
@@ -67,77 +424,265 @@ impl Chip {
         chip
     }
 
-    pub fn load_rom(&mut self, filename: &str) {
-        let contents = fs::read(filename).unwrap();
-        for (i, item) in contents.iter().enumerate() {
-            self.memory[START_ADDRESS as usize + i] = *item;
+    /// Creates a machine that loads ROMs at, and starts executing from,
+    /// `address` instead of 0x200.
+    pub fn with_load_address(address: u16) -> Self {
+        let mut chip = Chip::new();
+        chip.load_address = address;
+        chip.pc = address;
+        chip
+    }
+
+    /// Creates a machine with `platform`'s load address, screen, quirks and
+    /// instruction set.
+    pub fn with_platform(platform: Platform) -> Self {
+        let mut chip = Chip::with_load_address(platform.load_address());
+        let (width, height) = platform.screen_size();
+        chip.platform = platform;
+        chip.quirks = platform.quirks();
+        chip.memory.resize(platform.memory_size(), 0);
+        if platform == Platform::MegaChip {
+            chip.mega = Some(Box::new(MegaChip::new()));
+        }
+        chip.video = vec![0; width * height];
+        chip.video_width = width;
+        chip.video_height = height;
+        chip.colors = vec![CHIP8X_DEFAULT_COLOR; width / 8 * height];
+        chip
+    }
+
+    pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) -> Result<(), RomError> {
+        self.load_bytes(&read_rom(path)?)
+    }
+
+    pub fn load_from_reader<R: Read>(&mut self, mut reader: R) -> Result<(), RomError> {
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
+
+        self.load_bytes(&contents)
+    }
+
+    /// Copies `rom` into memory at the load address and points PC at it.
+    /// Also suits ROMs embedded with `include_bytes!`.
+    pub fn load_bytes(&mut self, rom: &[u8]) -> Result<(), RomError> {
+        let start = self.load_address as usize;
+        if start >= MEMORY_SIZE {
+            return Err(RomError::InvalidLoadAddress(self.load_address));
+        }
+
+        let max = self.memory.len() - start;
+        if rom.is_empty() {
+            return Err(RomError::Empty);
+        }
+        if rom.len() > max {
+            return Err(RomError::TooLarge {
+                size: rom.len(),
+                max,
+            });
+        }
+
+        self.memory[start..start + rom.len()].copy_from_slice(rom);
+        self.rom_len = rom.len();
+        self.pc = self.load_address;
+        self.fault = None;
+
+        self.decoded.fill(None);
+        for address in start..(start + rom.len()).min(MEMORY_SIZE - 1) {
+            self.decoded[address] = Some(decode_or_undefined(self.fetch(address), self.platform));
         }
+
+        Ok(())
+    }
+
+    /// Makes `CXNN` draw a repeatable sequence of numbers.
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = ChaCha12Rng::seed_from_u64(seed);
     }
 
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// The bytes of the most recently loaded ROM, as they sit in memory.
+    pub fn rom(&self) -> &[u8] {
+        let start = self.load_address as usize;
+        &self.memory[start..start + self.rom_len]
+    }
+
+    /// Writes `bytes` from `address` on, wrapping at the end of memory, and
+    /// drops any cached instructions they overlap, so self-modified code
+    /// runs correctly.
+    pub fn write_memory(&mut self, address: u16, bytes: &[u8]) {
+        let size = self.memory.len();
+        for (i, byte) in bytes.iter().enumerate() {
+            self.memory[(address as usize + i) % size] = *byte;
+        }
+        self.invalidate(address as usize, bytes.len());
+    }
+
+    /// Runs one instruction, unless the machine has faulted.
     pub fn cycle(&mut self) {
-        let hi_byte = self.memory[self.pc as usize];
-        let lo_byte = self.memory[self.pc as usize + 1];
+        if let Some(mut vip) = self.vip.take() {
+            vip.step_instruction(self);
+            self.vip = Some(vip);
+            return;
+        }
+        if self.fault.is_some() {
+            return;
+        }
 
-        self.opcode = (hi_byte as u16) << 8 | lo_byte as u16;
+        let pc = self.pc as usize;
+        if pc + 1 >= MEMORY_SIZE {
+            self.fault = Some(Fault::PcOutOfRange);
+            return;
+        }
+        let decoded = match self.decoded[pc] {
+            Some(decoded) if self.cache_decoding => decoded,
+            _ => {
+                let decoded = decode_or_undefined(self.fetch(pc), self.platform);
+                self.decoded[pc] = Some(decoded);
+                decoded
+            }
+        };
 
-        let digit1 = (self.opcode & 0xF000) >> 12;
-        let digit2 = (self.opcode & 0x0F00) >> 8;
-        let digit3 = (self.opcode & 0x00F0) >> 4;
-        let digit4 = self.opcode & 0x000F;
+        self.opcode = decoded.opcode;
 
-        let mut cpu_state = debugger::CpuState::new();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(self.pc, self.opcode);
+        }
+        self.track(self.pc as usize, 2, coverage::EXECUTED);
 
-        cpu_state.show_cpu_state(&*self, digit1, digit2, digit3, digit4);
+        if self.trace {
+            debugger::trace(self);
+        }
 
         self.pc += 2;
+        self.skipped = false;
 
-        match (self.opcode & 0xF000) >> 12 {
-            0x0000 => match self.opcode & 0x000F {
-                0x0000 => self.op_00e0(),
-                0x000e => self.op_00ee(),
-                _ => unreachable!(),
-            },
-            0x0001 => self.op_1nnn(),
-            0x0002 => self.op_2nnn(),
-            0x0003 => self.op_3xkk(),
-            0x0004 => self.op_4xkk(),
-            0x0005 => self.op_5xy0(),
-            0x0006 => self.op_6xkk(),
-            0x0007 => self.op_7xkk(),
-            0x0008 => match self.opcode & 0x000F {
-                0x0000 => self.op_8xy0(),
-                0x0001 => self.op_8xy1(),
-                0x0002 => self.op_8xy2(),
-                0x0003 => self.op_8xy3(),
-                0x0004 => self.op_8xy4(),
-                0x0005 => self.op_8xy5(),
-                0x0006 => self.op_8xy6(),
-                0x0007 => self.op_8xy7(),
-                0x000e => self.op_8xye(),
-                _ => unreachable!(),
-            },
-            0x0009 => self.op_9xy0(),
-            0x000a => self.op_annn(),
-            0x000b => self.op_bnnn(),
-            0x000c => self.op_cxnn(),
-            0x000d => self.op_dxyn(),
-            0x000e | 0x000f => match self.opcode & 0x00FF {
-                0x00a1 => self.op_exa1(),
-                0x009e => self.op_ex9e(),
-                0x0007 => self.op_fx07(),
-                0x000a => self.op_fx0a(),
-                0x0015 => self.op_fx15(),
-                0x0018 => self.op_fx18(),
-                0x001e => self.op_fx1e(),
-                0x0029 => self.op_fx29(),
-                0x0033 => self.op_fx33(),
-                0x0055 => self.op_fx55(),
-                0x0065 => self.op_fx65(),
-                _ => unreachable!(),
-            },
-            _ => unreachable!("Ran undefined instruction {:x}", self.opcode),
+        (decoded.execute)(self);
+    }
+
+    /// Runs one 60 Hz frame: up to `instructions` cycles followed by a timer
+    /// tick. With the `vblank` quirk the frame ends at the first draw. With
+    /// VIP timing `instructions` is ignored and the frame runs as many
+    /// instructions as a VIP would, always ending at a draw.
+    ///
+    /// Queued key events are applied between instructions as the frame
+    /// reaches them.
+    ///
+    /// `stop` is checked before every instruction and may change the machine.
+    /// If it returns true the frame is abandoned without ticking the timers,
+    /// and this returns true. A fault ends the frame early, with the timers
+    /// still ticked.
+    pub fn run_frame_until<F: FnMut(&mut Chip) -> bool>(
+        &mut self,
+        instructions: u32,
+        mut stop: F,
+    ) -> bool {
+        if let Some(mut vip) = self.vip.take() {
+            let stopped = vip.run_frame_until(self, &mut stop);
+            self.vip = Some(vip);
+            return stopped;
         }
 
+        self.keypad.begin_frame();
+        self.extra_keypad.begin_frame();
+
+        match self.timing {
+            Timing::Fixed => {
+                for i in 0..instructions {
+                    self.apply_keys(i as f32 / instructions as f32);
+                    if stop(self) {
+                        return true;
+                    }
+
+                    self.cycle();
+
+                    if self.fault.is_some() || self.quirks.vblank && self.opcode & 0xF000 == 0xD000
+                    {
+                        break;
+                    }
+                }
+            }
+            Timing::Vip => {
+                let mut spent = std::mem::take(&mut self.vip_overrun);
+                while spent < timing::VIP_FRAME_MICROS {
+                    self.apply_keys(spent as f32 / timing::VIP_FRAME_MICROS as f32);
+                    if stop(self) {
+                        // Pick the frame up where it stopped next time.
+                        self.vip_overrun = spent;
+                        return true;
+                    }
+
+                    self.cycle();
+                    if self.fault.is_some() {
+                        break;
+                    }
+                    let cost = timing::vip_cost(self.opcode, self.skipped);
+
+                    // The draw itself happens after the display interrupt,
+                    // so its cost comes out of the next frame.
+                    if self.opcode & 0xF000 == 0xD000 {
+                        spent = timing::VIP_FRAME_MICROS + cost;
+                        break;
+                    }
+                    spent += cost;
+                }
+                self.vip_overrun = spent.saturating_sub(timing::VIP_FRAME_MICROS);
+            }
+        }
+
+        self.apply_keys(1.0);
+        self.tick_timers();
+        false
+    }
+
+    /// Applies the key events queued for the first `progress` of the frame.
+    pub fn apply_keys(&mut self, progress: f32) {
+        self.keypad.apply_until(progress);
+        self.extra_keypad.apply_until(progress);
+    }
+
+    /// The background colour on a platform with colour, `None` on a
+    /// monochrome one.
+    pub fn background_color(&self) -> Option<(u8, u8, u8)> {
+        (self.platform == Platform::Chip8X)
+            .then(|| CHIP8X_COLORS[CHIP8X_BACKGROUNDS[self.background] as usize])
+    }
+
+    /// The colour of `video[pixel]` when lit on a platform with colour,
+    /// `None` on a monochrome one.
+    pub fn foreground_color(&self, pixel: usize) -> Option<(u8, u8, u8)> {
+        (self.platform == Platform::Chip8X).then(|| CHIP8X_COLORS[self.colors[pixel / 8] as usize])
+    }
+
+    /// The frame's sound as `AUDIO_RATE / 60` unsigned 8-bit samples:
+    /// MEGA-CHIP's digitised sound on that platform, elsewhere a square wave
+    /// while the sound timer runs.
+    pub fn audio_frame(&mut self) -> Vec<u8> {
+        let count = (AUDIO_RATE / 60) as usize;
+        if let Some(mega) = &mut self.mega {
+            return mega.mix(&self.memory, AUDIO_RATE, count);
+        }
+        if self.sound_timer == 0 {
+            self.beep_phase = 0;
+            return vec![megachip::SILENCE; count];
+        }
+
+        (0..count)
+            .map(|_| {
+                let high = self.beep_phase < AUDIO_RATE / 2;
+                self.beep_phase = (self.beep_phase + BEEP_FREQUENCY) % AUDIO_RATE;
+                if high {
+                    megachip::SILENCE + BEEP_VOLUME
+                } else {
+                    megachip::SILENCE - BEEP_VOLUME
+                }
+            })
+            .collect()
+    }
+
+    pub fn tick_timers(&mut self) {
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
@@ -147,33 +692,202 @@ impl Chip {
         }
     }
 
+    pub fn fetch(&self, address: usize) -> u16 {
+        (self.memory[address] as u16) << 8 | self.memory[address + 1] as u16
+    }
+
+    /// Drops the cached instructions overlapping `len` bytes from `address`,
+    /// including the one starting in the byte before.
+    fn invalidate(&mut self, address: usize, len: usize) {
+        let size = self.memory.len();
+        for i in 0..=len {
+            if let Some(decoded) = self.decoded.get_mut((address + size - 1 + i) % size) {
+                *decoded = None;
+            }
+        }
+    }
+
+    fn track(&mut self, address: usize, len: usize, flag: u8) {
+        if let Some(coverage) = &mut self.coverage {
+            if address < MEMORY_SIZE {
+                coverage.mark(address as u16, len, flag);
+            }
+        }
+    }
+
+    /// Halts on `fault`, putting PC back on the instruction that caused
+    /// it.
+    fn halt(&mut self, fault: Fault) {
+        self.pc = self.pc.wrapping_sub(2);
+        self.fault = Some(fault);
+    }
+
+    /// Whether `len` bytes from I lie in memory, halting if not.
+    fn index_reaches(&mut self, len: usize) -> bool {
+        let fits = self.index as usize + len <= self.memory.len();
+        if !fits {
+            self.halt(Fault::IndexOutOfRange(self.index));
+        }
+        fits
+    }
+
+    fn op_undefined(&mut self) {
+        self.halt(Fault::UndefinedInstruction(self.opcode));
+    }
+
+    /// Clears the screen. In MEGA-CHIP mode it first shows what was drawn
+    /// since the last clear.
     fn op_00e0(&mut self) {
-        self.video = [0; VIDEO_WIDTH as usize * VIDEO_HEIGHT as usize];
+        if let Some(mega) = self.mega.as_mut().filter(|mega| mega.enabled) {
+            mega.present();
+        }
+        self.video.fill(0);
+    }
+
+    /// MEGA-CHIP `0010`/`0011`: leaves or enters 256x192 colour mode.
+    fn op_001n(&mut self) {
+        let enable = self.opcode == 0x0011;
+        let (width, height) = if enable {
+            (megachip::SCREEN_WIDTH, megachip::SCREEN_HEIGHT)
+        } else {
+            self.platform.screen_size()
+        };
+
+        if let Some(mega) = &mut self.mega {
+            mega.enabled = enable;
+            mega.reset_screen();
+        }
+        self.video = vec![0; width * height];
+        self.video_width = width;
+        self.video_height = height;
+    }
+
+    /// MEGA-CHIP `01NN NNNN`: loads a 24-bit address into I.
+    fn op_01nn(&mut self) {
+        let low = self.fetch(self.pc as usize) as u32;
+        self.index = (self.opcode as u32 & 0xFF) << 16 | low;
+        self.pc += 2;
+    }
+
+    /// MEGA-CHIP `02NN`: loads NN ARGB palette colours from I.
+    fn op_02nn(&mut self) {
+        let count = (self.opcode & 0xFF) as usize;
+        if let Some(mega) = &mut self.mega {
+            mega.load_palette(&self.memory, self.index as usize, count);
+        }
+    }
+
+    /// MEGA-CHIP `03NN`: sets the sprite width, 0 meaning 256.
+    fn op_03nn(&mut self) {
+        let width = match self.opcode & 0xFF {
+            0 => 256,
+            width => width as usize,
+        };
+        if let Some(mega) = &mut self.mega {
+            mega.sprite_width = width;
+        }
+    }
+
+    /// MEGA-CHIP `04NN`: sets the sprite height, 0 meaning 256.
+    fn op_04nn(&mut self) {
+        let height = match self.opcode & 0xFF {
+            0 => 256,
+            height => height as usize,
+        };
+        if let Some(mega) = &mut self.mega {
+            mega.sprite_height = height;
+        }
+    }
+
+    /// MEGA-CHIP `05NN`: sets the opacity of the whole screen.
+    fn op_05nn(&mut self) {
+        if let Some(mega) = &mut self.mega {
+            mega.alpha = self.opcode as u8;
+        }
+    }
+
+    /// MEGA-CHIP `060N`: plays the sound at I, looping unless N is 1.
+    fn op_060n(&mut self) {
+        let looping = self.opcode & 0x000F == 0;
+        if let Some(mega) = &mut self.mega {
+            mega.play(&self.memory, self.index as usize, looping);
+        }
+    }
+
+    /// MEGA-CHIP `0700`: stops the sound.
+    fn op_0700(&mut self) {
+        if let Some(mega) = &mut self.mega {
+            mega.stop();
+        }
+    }
+
+    /// MEGA-CHIP `080N`: sets how sprites blend with the screen.
+    fn op_080n(&mut self) {
+        if let Some(mega) = &mut self.mega {
+            mega.blend = Blend::from_mode(self.opcode as u8 & 0x0F);
+        }
+    }
+
+    /// MEGA-CHIP `09NN`: sets the palette index sprites collide with.
+    fn op_09nn(&mut self) {
+        if let Some(mega) = &mut self.mega {
+            mega.collision_color = self.opcode as u8;
+        }
+    }
+
+    /// CHIP-8X: steps the background colour.
+    fn op_02a0(&mut self) {
+        self.background = (self.background + 1) % CHIP8X_BACKGROUNDS.len();
     }
 
     fn op_00ee(&mut self) {
+        if self.sp == 0 {
+            self.halt(Fault::StackUnderflow);
+            return;
+        }
         self.sp -= 1;
         self.pc = self.stack[self.sp as usize];
     }
 
     fn op_1nnn(&mut self) {
         let address = self.opcode & 0xFFF;
+
+        // The jump opening a HiRes program leads into the interpreter's own
+        // 1802 code, which enables 64x64 mode and carries on at 0x2C0.
+        if self.platform == Platform::HiresChip8
+            && self.opcode == 0x1260
+            && self.pc == self.load_address + 2
+        {
+            self.pc = HIRES_ENTRY;
+            return;
+        }
+
         self.pc = address;
     }
 
     fn op_2nnn(&mut self) {
         let address = self.opcode & 0xFFF;
+        if self.sp as usize == self.stack.len() {
+            self.halt(Fault::StackOverflow);
+            return;
+        }
         self.stack[self.sp as usize] = self.pc;
         self.sp += 1;
         self.pc = address;
     }
 
+    /// Steps over the next instruction, for the skip instructions.
+    fn skip(&mut self) {
+        self.pc += 2;
+        self.skipped = true;
+    }
+
     fn op_3xkk(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
         let byte: u8 = (self.opcode & 0x00FF) as u8;
 
         if self.registers[vx as usize] == byte {
-            self.pc += 2;
+            self.skip();
         }
     }
 
@@ -182,7 +896,7 @@ impl Chip {
         let byte: u8 = (self.opcode & 0x00FF) as u8;
 
         if self.registers[vx as usize] != byte {
-            self.pc += 2;
+            self.skip();
         }
     }
 
@@ -191,10 +905,19 @@ impl Chip {
         let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
 
         if self.registers[vx as usize] == self.registers[vy as usize] {
-            self.pc += 2;
+            self.skip();
         }
     }
 
+    /// CHIP-8X: adds VY to VX nibble by nibble, each nibble wrapping at 8,
+    /// to move colour zone coordinates.
+    fn op_5xy1(&mut self) {
+        let vx = ((self.opcode & 0x0F00) >> 8) as usize;
+        let vy = ((self.opcode & 0x00F0) >> 4) as usize;
+
+        self.registers[vx] = ((self.registers[vx] & 0x77) + (self.registers[vy] & 0x77)) & 0x77;
+    }
+
     fn op_6xkk(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
         let byte: u8 = (self.opcode & 0x00FF) as u8;
@@ -222,6 +945,10 @@ impl Chip {
         let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
 
         self.registers[vx as usize] |= self.registers[vy as usize];
+
+        if self.quirks.logic {
+            self.registers[0xF] = 0;
+        }
     }
 
     fn op_8xy2(&mut self) {
@@ -229,6 +956,10 @@ impl Chip {
         let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
 
         self.registers[vx as usize] &= self.registers[vy as usize];
+
+        if self.quirks.logic {
+            self.registers[0xF] = 0;
+        }
     }
 
     fn op_8xy3(&mut self) {
@@ -236,6 +967,10 @@ impl Chip {
         let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
 
         self.registers[vx as usize] ^= self.registers[vy as usize];
+
+        if self.quirks.logic {
+            self.registers[0xF] = 0;
+        }
     }
 
     fn op_8xy4(&mut self) {
@@ -270,10 +1005,16 @@ impl Chip {
 
     fn op_8xy6(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
+        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
 
-        self.registers[0xF] = self.registers[vx as usize] & 0x1;
+        let value = if self.quirks.shift {
+            self.registers[vx as usize]
+        } else {
+            self.registers[vy as usize]
+        };
 
-        self.registers[vx as usize] >>= 1;
+        self.registers[vx as usize] = value >> 1;
+        self.registers[0xF] = value & 0x1;
     }
 
     fn op_8xy7(&mut self) {
@@ -291,10 +1032,16 @@ impl Chip {
 
     fn op_8xye(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
+        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
 
-        self.registers[0xF] = (self.registers[vx as usize] & 0x80) >> 7;
+        let value = if self.quirks.shift {
+            self.registers[vx as usize]
+        } else {
+            self.registers[vy as usize]
+        };
 
-        self.registers[vx as usize] <<= 1;
+        self.registers[vx as usize] = value << 1;
+        self.registers[0xF] = (value & 0x80) >> 7;
     }
 
     fn op_9xy0(&mut self) {
@@ -302,27 +1049,63 @@ impl Chip {
         let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
 
         if self.registers[vx as usize] != self.registers[vy as usize] {
-            self.pc += 2;
+            self.skip();
         }
     }
 
     fn op_annn(&mut self) {
         let address = self.opcode & 0x0FFF;
 
-        self.index = address;
+        self.index = address as u32;
     }
 
     fn op_bnnn(&mut self) {
         let address = self.opcode & 0x0FFF;
 
-        self.pc = self.registers[0] as u16 + address;
+        let offset = if self.quirks.jump {
+            self.registers[((self.opcode & 0x0F00) >> 8) as usize]
+        } else {
+            self.registers[0]
+        };
+
+        self.pc = offset as u16 + address;
+    }
+
+    /// CHIP-8X: colours part of the screen. `BXY0` sets the 8x4-pixel zones
+    /// from the one whose column and row are in VX's low and high nibbles,
+    /// and as many more across and down as VX+1's nibbles say, to colour VY.
+    /// `BXYN` sets the 8 pixels from (VX, VY) on N rows to colour VX+1.
+    fn op_bxyn(&mut self) {
+        let vx = ((self.opcode & 0x0F00) >> 8) as usize;
+        let vy = ((self.opcode & 0x00F0) >> 4) as usize;
+        let rows = (self.opcode & 0x000F) as usize;
+        let next = self.registers[(vx + 1) % 16];
+        let columns = self.video_width / 8;
+
+        let (left, right, top, bottom, color) = if rows == 0 {
+            let left = (self.registers[vx] & 0x0F) as usize;
+            let top = (self.registers[vx] >> 4) as usize * 4;
+            let right = left + (next & 0x0F) as usize;
+            let bottom = top + (next >> 4) as usize * 4 + 3;
+            (left, right, top, bottom, self.registers[vy])
+        } else {
+            let left = self.registers[vx] as usize / 8;
+            let top = self.registers[vy] as usize;
+            (left, left, top, top + rows - 1, next)
+        };
+
+        for row in top..=bottom.min(self.video_height - 1) {
+            for column in left..=right.min(columns - 1) {
+                self.colors[row * columns + column] = color & 0x07;
+            }
+        }
     }
 
     fn op_cxnn(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
         let byte: u8 = (self.opcode & 0x00FF) as u8;
 
-        self.registers[vx as usize] = random::<u8>() & byte;
+        self.registers[vx as usize] = self.rng.gen::<u8>() & byte;
     }
 
     fn op_dxyn(&mut self) {
@@ -331,18 +1114,46 @@ impl Chip {
 
         let height = self.opcode & 0x000F;
 
-        let x_pos = self.registers[vx as usize] % VIDEO_WIDTH;
-        let y_pos = self.registers[vy as usize] % VIDEO_HEIGHT;
+        if let Some(mega) = self.mega.as_mut().filter(|mega| mega.enabled) {
+            let sprite = self.memory.get(self.index as usize..).unwrap_or_default();
+            let x = self.registers[vx as usize];
+            let y = self.registers[vy as usize];
+            let collision = mega.draw(&mut self.video, sprite, x, y, self.quirks.wrap);
+            self.registers[0xF] = collision as u8;
+            return;
+        }
+
+        if !self.index_reaches(height as usize) {
+            return;
+        }
+        let x_pos = self.registers[vx as usize] as usize % self.video_width;
+        let y_pos = self.registers[vy as usize] as usize % self.video_height;
 
         self.registers[0xF] = 0;
+        self.track(self.index as usize, height as usize, coverage::READ);
 
         for row in 0..height as usize {
-            let sprite_byte = self.memory[self.index as usize + row as usize];
+            let sprite_byte = self.memory[self.index as usize + row];
+
+            let mut y = y_pos + row;
+            if y >= self.video_height {
+                if !self.quirks.wrap {
+                    break;
+                }
+                y %= self.video_height;
+            }
 
             for col in 0..8_usize {
+                let mut x = x_pos + col;
+                if x >= self.video_width {
+                    if !self.quirks.wrap {
+                        break;
+                    }
+                    x %= self.video_width;
+                }
+
                 let sprite_pixel = sprite_byte & (0x80 >> col);
-                let screen_pixel = &mut self.video
-                    [(y_pos as usize + row) * VIDEO_WIDTH as usize + (x_pos as usize + col)];
+                let screen_pixel = &mut self.video[y * self.video_width + x];
 
                 if sprite_pixel != 0 {
                     if *screen_pixel == 0xFF {
@@ -360,8 +1171,8 @@ impl Chip {
 
         let key = self.registers[vx as usize];
 
-        if self.keypad[key as usize] == 1 {
-            self.pc += 2;
+        if self.keypad.is_down(key) {
+            self.skip();
         }
     }
 
@@ -370,30 +1181,50 @@ impl Chip {
 
         let key = self.registers[vx as usize];
 
-        if !self.keypad[key as usize] == 1 {
-            self.pc += 2;
+        if !self.keypad.is_down(key) {
+            self.skip();
+        }
+    }
+
+    /// CHIP-8X: skips if key VX of the second keypad is down.
+    fn op_exf2(&mut self) {
+        let key = self.registers[((self.opcode & 0x0F00) >> 8) as usize];
+
+        if self.extra_keypad.is_down(key) {
+            self.skip();
         }
     }
 
+    /// CHIP-8X: skips if key VX of the second keypad is up.
+    fn op_exf5(&mut self) {
+        let key = self.registers[((self.opcode & 0x0F00) >> 8) as usize];
+
+        if !self.extra_keypad.is_down(key) {
+            self.skip();
+        }
+    }
+
+    /// CHIP-8X: sends VX to the output port, which sets the VP-595 sound
+    /// board's pitch. The board isn't emulated, so this does nothing.
+    fn op_fxf8(&mut self) {}
+
+    /// CHIP-8X: waits for a byte on the input port and puts it in VX.
+    /// Nothing is ever plugged in, so this carries on with VX unchanged.
+    fn op_fxfb(&mut self) {}
+
     fn op_fx07(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
 
         self.registers[vx as usize] = self.delay_timer;
     }
 
+    /// Waits for a key to be pressed and released, re-running until one is.
     fn op_fx0a(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let mut pressed = false;
-
-        for (i, key) in self.keypad.iter().enumerate() {
-            if *key != 0 {
-                self.registers[vx as usize] = i as u8;
-                pressed = true;
-            }
-        }
 
-        if !pressed {
-            self.pc -= 2;
+        match self.keypad.wait_for_release() {
+            Some(key) => self.registers[vx as usize] = key,
+            None => self.pc -= 2,
         }
     }
 
@@ -412,20 +1243,24 @@ impl Chip {
     fn op_fx1e(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
 
-        self.index += self.registers[vx as usize] as u16;
+        self.index += self.registers[vx as usize] as u32;
     }
 
     fn op_fx29(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
         let digit = self.registers[vx as usize];
 
-        self.index = (FONT_SET_START_ADDRESS + (5 * digit as u32)) as u16;
+        self.index = FONT_SET_START_ADDRESS + (5 * digit as u32);
     }
 
     fn op_fx33(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
 
+        if !self.index_reaches(3) {
+            return;
+        }
         let mut value = self.registers[vx as usize];
+        self.track(self.index as usize, 3, coverage::WRITTEN);
 
         self.memory[self.index as usize + 2] = value % 10;
         value /= 10;
@@ -434,22 +1269,266 @@ impl Chip {
         value /= 10;
 
         self.memory[self.index as usize] = value % 10;
+        self.invalidate(self.index as usize, 3);
     }
 
     fn op_fx55(&mut self) {
         let vx = ((self.opcode & 0x0F00) >> 8) as u8;
-        println!("{}", vx);
 
+        if !self.index_reaches(vx as usize + 1) {
+            return;
+        }
+        self.track(self.index as usize, vx as usize + 1, coverage::WRITTEN);
         for i in 0..=vx as usize {
             self.memory[self.index as usize + i] = self.registers[i];
         }
+        self.invalidate(self.index as usize, vx as usize + 1);
+
+        self.advance_index(vx);
     }
 
     fn op_fx65(&mut self) {
         let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
 
+        if !self.index_reaches(vx as usize + 1) {
+            return;
+        }
+        self.track(self.index as usize, vx as usize + 1, coverage::READ);
         for i in 0..=vx as usize {
             self.registers[i] = self.memory[self.index as usize + i];
         }
+
+        self.advance_index(vx);
+    }
+
+    fn advance_index(&mut self, vx: u8) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
+        }
+
+        self.index += vx as u32;
+        if !self.quirks.memory_increment_by_x {
+            self.index += 1;
+        }
+    }
+}
+
+/// Reads the ROM file at `path`, to look up or load.
+pub fn read_rom<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, RomError> {
+    let path = path.as_ref();
+    fs::read(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => RomError::NotFound(path.to_path_buf()),
+        _ => RomError::Io(err),
+    })
+}
+
+/// Finds the handler for `opcode` on `platform`, or `None` if it isn't an
+/// instruction.
+pub fn decode(opcode: u16, platform: Platform) -> Option<Decoded> {
+    let execute: fn(&mut Chip) = match platform {
+        Platform::HiresChip8 if opcode == 0x0230 => Chip::op_00e0,
+        Platform::Chip8X if opcode == 0x02A0 => Chip::op_02a0,
+        Platform::Chip8X if opcode & 0xF00F == 0x5001 => Chip::op_5xy1,
+        Platform::Chip8X if opcode & 0xF000 == 0xB000 => Chip::op_bxyn,
+        Platform::Chip8X if opcode & 0xF0FF == 0xE0F2 => Chip::op_exf2,
+        Platform::Chip8X if opcode & 0xF0FF == 0xE0F5 => Chip::op_exf5,
+        Platform::Chip8X if opcode & 0xF0FF == 0xF0F8 => Chip::op_fxf8,
+        Platform::Chip8X if opcode & 0xF0FF == 0xF0FB => Chip::op_fxfb,
+        Platform::MegaChip => decode_megachip(opcode).or_else(|| decode_common(opcode))?,
+        _ => decode_common(opcode)?,
+    };
+
+    Some(Decoded { opcode, execute })
+}
+
+/// The handler for a MEGA-CHIP `0NNN` extension.
+fn decode_megachip(opcode: u16) -> Option<fn(&mut Chip)> {
+    let execute: fn(&mut Chip) = match opcode & 0xFF00 {
+        0x0000 if opcode == 0x0010 || opcode == 0x0011 => Chip::op_001n,
+        0x0100 => Chip::op_01nn,
+        0x0200 => Chip::op_02nn,
+        0x0300 => Chip::op_03nn,
+        0x0400 => Chip::op_04nn,
+        0x0500 => Chip::op_05nn,
+        0x0600 if opcode & 0x00F0 == 0 => Chip::op_060n,
+        0x0700 if opcode == 0x0700 => Chip::op_0700,
+        0x0800 if opcode & 0x00F0 == 0 => Chip::op_080n,
+        0x0900 => Chip::op_09nn,
+        _ => return None,
+    };
+
+    Some(execute)
+}
+
+/// The handler for an instruction every platform shares.
+fn decode_common(opcode: u16) -> Option<fn(&mut Chip)> {
+    let execute: fn(&mut Chip) = match (opcode & 0xF000) >> 12 {
+        0x0000 => match opcode & 0x000F {
+            0x0000 => Chip::op_00e0,
+            0x000e => Chip::op_00ee,
+            _ => return None,
+        },
+        0x0001 => Chip::op_1nnn,
+        0x0002 => Chip::op_2nnn,
+        0x0003 => Chip::op_3xkk,
+        0x0004 => Chip::op_4xkk,
+        0x0005 => Chip::op_5xy0,
+        0x0006 => Chip::op_6xkk,
+        0x0007 => Chip::op_7xkk,
+        0x0008 => match opcode & 0x000F {
+            0x0000 => Chip::op_8xy0,
+            0x0001 => Chip::op_8xy1,
+            0x0002 => Chip::op_8xy2,
+            0x0003 => Chip::op_8xy3,
+            0x0004 => Chip::op_8xy4,
+            0x0005 => Chip::op_8xy5,
+            0x0006 => Chip::op_8xy6,
+            0x0007 => Chip::op_8xy7,
+            0x000e => Chip::op_8xye,
+            _ => return None,
+        },
+        0x0009 => Chip::op_9xy0,
+        0x000a => Chip::op_annn,
+        0x000b => Chip::op_bnnn,
+        0x000c => Chip::op_cxnn,
+        0x000d => Chip::op_dxyn,
+        0x000e | 0x000f => match opcode & 0x00FF {
+            0x00a1 => Chip::op_exa1,
+            0x009e => Chip::op_ex9e,
+            0x0007 => Chip::op_fx07,
+            0x000a => Chip::op_fx0a,
+            0x0015 => Chip::op_fx15,
+            0x0018 => Chip::op_fx18,
+            0x001e => Chip::op_fx1e,
+            0x0029 => Chip::op_fx29,
+            0x0033 => Chip::op_fx33,
+            0x0055 => Chip::op_fx55,
+            0x0065 => Chip::op_fx65,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(execute)
+}
+
+/// Decodes `opcode`, standing in a handler that panics if it isn't an
+/// instruction so the failure happens when it runs, not when it's cached.
+fn decode_or_undefined(opcode: u16, platform: Platform) -> Decoded {
+    decode(opcode, platform).unwrap_or(Decoded {
+        opcode,
+        execute: Chip::op_undefined,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chip_with(rom: &[u8]) -> Chip {
+        let mut chip = Chip::default();
+        chip.load_bytes(rom).unwrap();
+        chip
+    }
+
+    fn run(chip: &mut Chip, instructions: usize) {
+        for _ in 0..instructions {
+            chip.cycle();
+        }
+    }
+
+    #[test]
+    fn fx55_over_cached_instruction_runs_new_bytes() {
+        let mut chip = chip_with(&[
+            0xA2, 0x0A, // I = 20A
+            0x60, 0x6A, // V0 = 6A
+            0x61, 0x77, // V1 = 77
+            0xF1, 0x55, // store V0-V1 at 20A, making it 6A77
+            0x6B, 0x01, // VB = 1
+            0x6A, 0x11, // VA = 11, overwritten before it runs
+        ]);
+        run(&mut chip, 6);
+        assert_eq!(chip.registers[0xA], 0x77);
+    }
+
+    #[test]
+    fn fx33_over_low_byte_of_cached_instruction_runs_new_bytes() {
+        let mut chip = chip_with(&[
+            0xA2, 0x09, // I = 209
+            0x62, 0x7B, // V2 = 123
+            0xF2, 0x33, // BCD to 209-20B, making 208 6A01
+            0x6B, 0x00, // VB = 0
+            0x6A, 0x11, // VA = 11, overwritten before it runs
+        ]);
+        run(&mut chip, 5);
+        assert_eq!(chip.registers[0xA], 0x01);
+    }
+
+    #[test]
+    fn chip8x_port_instructions_run() {
+        let mut chip = Chip::with_platform(Platform::Chip8X);
+        chip.load_bytes(&[0x65, 0x07, 0xF5, 0xF8, 0xF5, 0xFB])
+            .unwrap();
+        run(&mut chip, 3);
+        assert_eq!((chip.pc, chip.registers[5]), (0x306, 0x07));
+    }
+
+    #[test]
+    fn write_memory_over_executed_instruction_runs_new_bytes() {
+        let mut chip = chip_with(&[0x6A, 0x11, 0x12, 0x00]);
+        run(&mut chip, 2);
+        assert_eq!(chip.registers[0xA], 0x11);
+
+        chip.write_memory(0x201, &[0x22]);
+        run(&mut chip, 1);
+        assert_eq!(chip.registers[0xA], 0x22);
+    }
+
+    #[test]
+    fn faults_halt_on_the_faulting_instruction() {
+        let cases: [(&[u8], Fault, u16); 5] = [
+            (
+                &[0x12, 0x04, 0x00, 0x00, 0xFF, 0xFF],
+                Fault::UndefinedInstruction(0xFFFF),
+                0x204,
+            ),
+            (
+                &[0xAF, 0xFF, 0xF1, 0x65],
+                Fault::IndexOutOfRange(0xFFF),
+                0x202,
+            ),
+            (
+                &[0xAF, 0xFE, 0xF0, 0x33],
+                Fault::IndexOutOfRange(0xFFE),
+                0x202,
+            ),
+            (&[0x00, 0xEE], Fault::StackUnderflow, 0x200),
+            (&[0x22, 0x00], Fault::StackOverflow, 0x200),
+        ];
+        for (rom, fault, pc) in cases {
+            let mut chip = chip_with(rom);
+            chip.run_frame_until(100, |_| false);
+            assert_eq!((chip.fault, chip.pc), (Some(fault), pc));
+
+            // Halted machines stay put.
+            chip.run_frame_until(100, |_| false);
+            assert_eq!(chip.pc, pc);
+        }
+
+        let mut chip = chip_with(&[0x1F, 0xFE]);
+        chip.write_memory(0xFFE, &[0x60, 0x01]);
+        chip.run_frame_until(10, |_| false);
+        assert_eq!(chip.fault, Some(Fault::PcOutOfRange));
+
+        chip.load_bytes(&[0x60, 0x01]).unwrap();
+        assert_eq!(chip.fault, None);
+    }
+
+    #[test]
+    fn sprites_past_the_end_of_memory_fault() {
+        let mut chip = chip_with(&[0xAF, 0xFC, 0xD0, 0x05]);
+        chip.run_frame_until(10, |_| false);
+        assert_eq!(chip.fault, Some(Fault::IndexOutOfRange(0xFFC)));
+        assert!(chip.video.iter().all(|pixel| *pixel == 0));
     }
 }
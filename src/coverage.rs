@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::chip::MEMORY_SIZE;
+
+pub const EXECUTED: u8 = 0b001;
+pub const READ: u8 = 0b010;
+pub const WRITTEN: u8 = 0b100;
+
+/// Per-byte record of how memory was used during a run. Attach one to
+/// `Chip::coverage` to enable it.
+///
+/// Saved maps are text, one line per run of bytes with the same flags:
+///
+/// ```text
+/// # executed 312 of 478 ROM bytes (65.3%)
+/// 200 2F3 x--
+/// 2F4 2FF -r-
+/// ```
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    pub flags: Vec<u8>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            flags: vec![0; MEMORY_SIZE],
+        }
+    }
+
+    pub fn mark(&mut self, address: u16, len: usize, flag: u8) {
+        for i in 0..len {
+            self.flags[(address as usize + i) % MEMORY_SIZE] |= flag;
+        }
+    }
+
+    pub fn is(&self, address: usize, flag: u8) -> bool {
+        self.flags[address % MEMORY_SIZE] & flag != 0
+    }
+
+    /// Bytes in `start..end` that were executed, and the fraction that is of
+    /// the range.
+    pub fn executed_in(&self, start: usize, end: usize) -> (usize, f64) {
+        let executed = (start..end)
+            .filter(|address| self.is(*address, EXECUTED))
+            .count();
+        let fraction = executed as f64 / (end - start).max(1) as f64;
+        (executed, fraction)
+    }
+
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        path: P,
+        rom_start: usize,
+        rom_end: usize,
+    ) -> io::Result<()> {
+        let (executed, fraction) = self.executed_in(rom_start, rom_end);
+        let mut contents = format!(
+            "# executed {} of {} ROM bytes ({:.1}%)\n",
+            executed,
+            rom_end - rom_start,
+            fraction * 100.0
+        );
+
+        let mut start = 0;
+        while start < MEMORY_SIZE {
+            let flags = self.flags[start];
+            let mut end = start;
+            while end + 1 < MEMORY_SIZE && self.flags[end + 1] == flags {
+                end += 1;
+            }
+
+            if flags != 0 {
+                contents += &format!("{:03X} {:03X} {}\n", start, end, flag_string(flags));
+            }
+            start = end + 1;
+        }
+
+        fs::write(path, contents)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Coverage, Box<dyn Error>> {
+        let mut coverage = Coverage::new();
+
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (start, end, flags) = match fields.as_slice() {
+                [start, end, flags] => (
+                    usize::from_str_radix(start, 16)?,
+                    usize::from_str_radix(end, 16)?,
+                    parse_flags(flags).ok_or_else(|| format!("bad flags '{}'", flags))?,
+                ),
+                _ => return Err(format!("bad coverage line '{}'", line).into()),
+            };
+
+            for address in start..=end.min(MEMORY_SIZE - 1) {
+                coverage.flags[address] |= flags;
+            }
+        }
+
+        Ok(coverage)
+    }
+}
+
+fn flag_string(flags: u8) -> String {
+    [(EXECUTED, 'x'), (READ, 'r'), (WRITTEN, 'w')]
+        .iter()
+        .map(|(flag, c)| if flags & flag != 0 { *c } else { '-' })
+        .collect()
+}
+
+fn parse_flags(text: &str) -> Option<u8> {
+    text.chars().try_fold(0, |flags, c| match c {
+        'x' => Some(flags | EXECUTED),
+        'r' => Some(flags | READ),
+        'w' => Some(flags | WRITTEN),
+        '-' => Some(flags),
+        _ => None,
+    })
+}
@@ -0,0 +1,544 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::chip::Chip;
+use crate::debugger::{disassemble, Debugger, Stop};
+use crate::symbols::LineMap;
+
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+const TIMERS_REFERENCE: u64 = 2;
+
+/// A ROM the client asked to launch. The main loop loads it and reports
+/// back through `DapServer::finish_launch`.
+pub struct Launch {
+    pub program: String,
+    pub line_map: Option<String>,
+}
+
+/// A Debug Adapter Protocol server on a local TCP port, for debugging ROMs
+/// from an editor. Like the GDB server it is polled between frames and
+/// drives the shared `Debugger`.
+///
+/// `launch` takes `program` (the ROM path), optional `lineMap` (a file in
+/// the `LineMap` format, enabling breakpoints by source line) and
+/// `stopOnEntry`.
+pub struct DapServer {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    buffer: Vec<u8>,
+    seq: u64,
+    line_map: LineMap,
+    /// Breakpoint addresses per source file, so a new `setBreakpoints`
+    /// for a file replaces that file's old ones.
+    source_breakpoints: HashMap<String, Vec<u16>>,
+    instruction_breakpoints: Vec<u16>,
+    pending_launch: Option<(u64, bool)>,
+    /// Set from a successful launch until `configurationDone`, holding
+    /// `stopOnEntry`. The machine stays paused meanwhile so it can't run
+    /// past breakpoints the client hasn't sent yet.
+    awaiting_configuration: Option<bool>,
+    stopped_on_entry: bool,
+    /// True while the machine runs on behalf of the client, so the next
+    /// pause gets reported as a `stopped` event.
+    running: bool,
+}
+
+impl DapServer {
+    pub fn bind(port: u16) -> io::Result<DapServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        println!("DAP server listening on 127.0.0.1:{}", port);
+
+        Ok(DapServer {
+            listener,
+            client: None,
+            buffer: Vec::new(),
+            seq: 1,
+            line_map: LineMap::default(),
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            pending_launch: None,
+            awaiting_configuration: None,
+            stopped_on_entry: false,
+            running: false,
+        })
+    }
+
+    /// Handles client requests and reports stops. Returns a ROM to launch
+    /// when the client sent a `launch` request.
+    pub fn poll(&mut self, chip: &mut Chip, debugger: &mut Debugger) -> Option<Launch> {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, address)) if stream.set_nonblocking(true).is_ok() => {
+                    println!("DAP client connected from {}", address);
+                    self.client = Some(stream);
+                    self.buffer.clear();
+                }
+                Ok(_) => return None,
+                Err(err) => {
+                    if err.kind() != ErrorKind::WouldBlock {
+                        eprintln!("DAP server: {}", err);
+                    }
+                    return None;
+                }
+            }
+        }
+
+        match self.serve(chip, debugger) {
+            Ok(launch) => launch,
+            Err(err) => {
+                println!("DAP client disconnected: {}", err);
+                self.client = None;
+                self.running = false;
+                debugger.resume(chip);
+                None
+            }
+        }
+    }
+
+    /// Answers the pending `launch` request once the main loop has tried to
+    /// load the ROM. The machine is left paused until `configurationDone`.
+    pub fn finish_launch(
+        &mut self,
+        result: Result<(), String>,
+        line_map: Option<LineMap>,
+        debugger: &mut Debugger,
+    ) {
+        let (seq, stop_on_entry) = match self.pending_launch.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        let sent = match result {
+            Ok(()) => {
+                self.line_map = line_map.unwrap_or_default();
+                self.source_breakpoints.clear();
+                self.instruction_breakpoints.clear();
+
+                debugger.pause(Stop::Pause);
+                self.awaiting_configuration = Some(stop_on_entry);
+
+                self.respond(seq, "launch", true, None, json!({}))
+                    .and_then(|_| self.event("initialized", json!({})))
+            }
+            Err(message) => self.respond(seq, "launch", false, Some(&message), json!({})),
+        };
+
+        if sent.is_err() {
+            self.client = None;
+        }
+    }
+
+    fn serve(&mut self, chip: &mut Chip, debugger: &mut Debugger) -> io::Result<Option<Launch>> {
+        let mut chunk = [0; 4096];
+        loop {
+            let client = self.client.as_mut().unwrap();
+            match client.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "closed")),
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut launch = None;
+        while let Some(message) = self.next_message() {
+            if let Some(requested) = self.handle(&message, chip, debugger)? {
+                launch = Some(requested);
+            }
+            if self.client.is_none() {
+                return Ok(None);
+            }
+        }
+
+        if self.running && debugger.paused {
+            self.running = false;
+            let reason = match debugger.last_stop {
+                _ if self.stopped_on_entry => "entry",
+                Some(Stop::Breakpoint(_)) => "breakpoint",
+                Some(Stop::Watchpoint(_)) => "data breakpoint",
+                Some(Stop::Pause) => "pause",
+                Some(Stop::Fault(_)) => "exception",
+                Some(Stop::Step) | None => "step",
+            };
+            let mut body =
+                json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+            if let Some(Stop::Fault(fault)) = debugger.last_stop {
+                body["text"] = json!(fault.to_string());
+            }
+            self.event("stopped", body)?;
+            self.stopped_on_entry = false;
+        }
+
+        Ok(launch)
+    }
+
+    /// Takes the next complete `Content-Length` framed message off the
+    /// input buffer.
+    fn next_message(&mut self) -> Option<Value> {
+        let header_end = self.buffer.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let header = String::from_utf8_lossy(&self.buffer[..header_end]).into_owned();
+        let length: usize = header.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("Content-Length")
+                .then(|| value.trim().parse().ok())?
+        })?;
+
+        let body_start = header_end + 4;
+        if self.buffer.len() < body_start + length {
+            return None;
+        }
+
+        let body: Vec<u8> = self
+            .buffer
+            .drain(..body_start + length)
+            .skip(body_start)
+            .collect();
+        // A malformed body still gets an error response rather than
+        // stalling the queue.
+        Some(serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+
+        let body = message.to_string();
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        write!(client, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    fn respond(
+        &mut self,
+        request_seq: u64,
+        command: &str,
+        success: bool,
+        message: Option<&str>,
+        body: Value,
+    ) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request_seq,
+            "command": command,
+            "success": success,
+            "body": body,
+        });
+        if let Some(message) = message {
+            response["message"] = json!(message);
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn handle(
+        &mut self,
+        request: &Value,
+        chip: &mut Chip,
+        debugger: &mut Debugger,
+    ) -> io::Result<Option<Launch>> {
+        let seq = request["seq"].as_u64().unwrap_or(0);
+        let command = request["command"].as_str().unwrap_or("").to_owned();
+        let args = &request["arguments"];
+
+        let body = match command.as_str() {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsSteppingGranularity": false,
+            }),
+            "launch" => {
+                let program = match args["program"].as_str() {
+                    Some(program) => program.to_owned(),
+                    None => {
+                        return self
+                            .respond(seq, &command, false, Some("missing 'program'"), json!({}))
+                            .map(|_| None)
+                    }
+                };
+
+                self.pending_launch = Some((seq, args["stopOnEntry"].as_bool().unwrap_or(false)));
+                return Ok(Some(Launch {
+                    program,
+                    line_map: args["lineMap"].as_str().map(str::to_owned),
+                }));
+            }
+            "configurationDone" => {
+                if let Some(stop_on_entry) = self.awaiting_configuration.take() {
+                    if stop_on_entry {
+                        self.stopped_on_entry = true;
+                    } else {
+                        debugger.resume(chip);
+                    }
+                    self.running = true;
+                }
+                json!({})
+            }
+            "setBreakpoints" => self.set_source_breakpoints(args, debugger),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args, debugger),
+            "threads" => json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] }),
+            "stackTrace" => self.stack_trace(chip),
+            "scopes" => json!({
+                "scopes": [
+                    {
+                        "name": "Registers",
+                        "variablesReference": REGISTERS_REFERENCE,
+                        "expensive": false,
+                    },
+                    {
+                        "name": "Timers",
+                        "variablesReference": TIMERS_REFERENCE,
+                        "expensive": false,
+                    },
+                ]
+            }),
+            "variables" => variables(chip, args["variablesReference"].as_u64().unwrap_or(0)),
+            "readMemory" => read_memory(chip, args),
+            "continue" => {
+                debugger.resume(chip);
+                self.running = true;
+                json!({ "allThreadsContinued": true })
+            }
+            "next" => {
+                debugger.step_over(chip);
+                self.running = true;
+                json!({})
+            }
+            "stepIn" => {
+                debugger.step(chip, 1);
+                self.running = true;
+                json!({})
+            }
+            "stepOut" => {
+                debugger.step_out(chip);
+                self.running = true;
+                json!({})
+            }
+            "pause" => {
+                debugger.pause(Stop::Pause);
+                self.running = true;
+                json!({})
+            }
+            "disconnect" => {
+                self.respond(seq, &command, true, None, json!({}))?;
+                debugger.resume(chip);
+                self.running = false;
+                self.client = None;
+                return Ok(None);
+            }
+            _ => {
+                let message = format!("unsupported request '{}'", command);
+                return self
+                    .respond(seq, &command, false, Some(&message), json!({}))
+                    .map(|_| None);
+            }
+        };
+
+        self.respond(seq, &command, true, None, body).map(|_| None)
+    }
+
+    fn set_source_breakpoints(&mut self, args: &Value, debugger: &mut Debugger) -> Value {
+        let path = args["source"]["path"].as_str().unwrap_or("").to_owned();
+
+        for address in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            debugger.breakpoints.remove(&address);
+        }
+
+        let mut addresses = Vec::new();
+        let breakpoints: Vec<Value> = args["breakpoints"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .map(|breakpoint| {
+                let line = breakpoint["line"].as_u64().unwrap_or(0) as u32;
+                match self.line_map.address(&path, line) {
+                    Some(address) => {
+                        debugger.breakpoints.insert(address);
+                        addresses.push(address);
+                        let line = self.line_map.line(address).map_or(line, |(_, line)| line);
+                        json!({
+                            "verified": true,
+                            "line": line,
+                            "instructionReference": format!("0x{:03X}", address),
+                        })
+                    }
+                    None => json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "no code at this line",
+                    }),
+                }
+            })
+            .collect();
+
+        self.source_breakpoints.insert(path, addresses);
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value, debugger: &mut Debugger) -> Value {
+        for address in self.instruction_breakpoints.drain(..) {
+            debugger.breakpoints.remove(&address);
+        }
+
+        let breakpoints: Vec<Value> = args["breakpoints"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .map(|breakpoint| {
+                let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+                let address = breakpoint["instructionReference"]
+                    .as_str()
+                    .and_then(parse_reference)
+                    .map(|address| (address as i64 + offset) as u16);
+
+                match address {
+                    Some(address) => {
+                        debugger.breakpoints.insert(address);
+                        self.instruction_breakpoints.push(address);
+                        json!({
+                            "verified": true,
+                            "instructionReference": format!("0x{:03X}", address),
+                        })
+                    }
+                    None => json!({ "verified": false }),
+                }
+            })
+            .collect();
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// The current PC, then the `CALL` behind each return address on the
+    /// CHIP-8 stack, innermost first.
+    fn stack_trace(&self, chip: &Chip) -> Value {
+        let mut addresses = vec![chip.pc];
+        addresses.extend(
+            chip.stack[..chip.sp as usize]
+                .iter()
+                .rev()
+                .map(|return_address| return_address.wrapping_sub(2)),
+        );
+
+        let frames: Vec<Value> = addresses
+            .iter()
+            .enumerate()
+            .map(|(id, address)| {
+                let opcode = read_opcode(chip, *address);
+                let name = format!(
+                    "{}: {}",
+                    chip.symbols.format_address(*address),
+                    disassemble(opcode, &chip.symbols)
+                );
+                let mut frame = json!({
+                    "id": id,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:03X}", address),
+                });
+                if let Some((file, line)) = self.line_map.line(*address) {
+                    frame["source"] = json!({ "name": file, "path": file });
+                    frame["line"] = json!(line);
+                }
+                frame
+            })
+            .collect();
+
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+}
+
+fn read_opcode(chip: &Chip, address: u16) -> u16 {
+    let address = address as usize % chip.memory().len();
+    let next = (address + 1) % chip.memory().len();
+    (chip.memory()[address] as u16) << 8 | chip.memory()[next] as u16
+}
+
+fn parse_reference(text: &str) -> Option<u16> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn variable(name: &str, value: u32, digits: usize) -> Value {
+    json!({
+        "name": name,
+        "value": format!("0x{:0width$X} ({})", value, value, width = digits),
+        "variablesReference": 0,
+    })
+}
+
+fn variables(chip: &Chip, reference: u64) -> Value {
+    let variables: Vec<Value> = match reference {
+        REGISTERS_REFERENCE => {
+            let mut registers: Vec<Value> = chip
+                .registers
+                .iter()
+                .enumerate()
+                .map(|(i, value)| variable(&format!("V{:X}", i), *value as u32, 2))
+                .collect();
+            registers.push(variable("I", chip.index, 3));
+            registers.push(variable("PC", chip.pc as u32, 3));
+            registers.push(variable("SP", chip.sp as u32, 1));
+            registers
+        }
+        TIMERS_REFERENCE => vec![
+            variable("DT", chip.delay_timer as u32, 2),
+            variable("ST", chip.sound_timer as u32, 2),
+        ],
+        _ => Vec::new(),
+    };
+
+    json!({ "variables": variables })
+}
+
+fn read_memory(chip: &Chip, args: &Value) -> Value {
+    let base = args["memoryReference"]
+        .as_str()
+        .and_then(parse_reference)
+        .unwrap_or(0) as i64;
+    let start =
+        (base + args["offset"].as_i64().unwrap_or(0)).clamp(0, chip.memory().len() as i64) as usize;
+    let count = args["count"].as_u64().unwrap_or(0) as usize;
+    let end = start.saturating_add(count).min(chip.memory().len());
+
+    json!({
+        "address": format!("0x{:03X}", start),
+        "data": base64(&chip.memory()[start..end]),
+        "unreadableBytes": count - (end - start),
+    })
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -1,64 +1,620 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::cheats::{Cheat, Cheats, Filter, Location, Search};
 use crate::chip;
+use crate::coverage::{self, Coverage};
+use crate::symbols::SymbolTable;
+
+/// Search results listed by `results` before the rest are summarised.
+const MAX_RESULTS: usize = 32;
 
-pub struct CpuState {
-    pub asm: String,
+/// Prints the instruction about to run, under its label if it has one.
+pub fn trace(chip: &chip::Chip) {
+    if let Some(label) = chip.symbols.label(chip.pc) {
+        println!("{}:", label);
+    }
+    println!(
+        "0{:X}: {}",
+        chip.pc,
+        disassemble(chip.opcode, &chip.symbols)
+    );
 }
 
-impl CpuState {
-    pub fn new() -> CpuState {
-        CpuState {
-            asm: "".to_string(),
+/// Formats a single opcode as assembly, naming jump, call and index
+/// targets after their labels. Opcodes that aren't instructions are shown
+/// as data words.
+pub fn disassemble(opcode: u16, symbols: &SymbolTable) -> String {
+    let address = symbols.format_address(opcode & 0x0FFF);
+    let digit1 = (opcode & 0xF000) >> 12;
+    let digit2 = (opcode & 0x0F00) >> 8;
+    let digit3 = (opcode & 0x00F0) >> 4;
+    let digit4 = opcode & 0x000F;
+
+    match (digit1, digit2, digit3, digit4) {
+        (0, _, _, 0xE) => "RET".to_owned(),
+        (0, _, _, _) => "CLR".to_owned(),
+        (1, _, _, _) => format!("JMP   {}", address),
+        (2, _, _, _) => format!("CALL  {}", address),
+        (3, _, _, _) => format!("SE    V{:X}, {:X}{:X}", digit2, digit3, digit4),
+        (4, _, _, _) => format!("SNE   V{:X}, {:X}{:X}", digit2, digit3, digit4),
+        (5, _, _, _) => format!("SE    V{:X}, V{:X}", digit2, digit3),
+        (6, _, _, _) => format!("LD    V{:X}, {:X}{:X}", digit2, digit3, digit4),
+        (7, _, _, _) => format!("ADD   V{:X}, {:X}{:X}", digit2, digit3, digit4),
+        (8, _, _, 0) => format!("LD    V{:X}, V{:X}", digit2, digit3),
+        (8, _, _, 1) => format!("OR    V{:X}, V{:X}", digit2, digit3),
+        (8, _, _, 2) => format!("AND   V{:X}, V{:X}", digit2, digit3),
+        (8, _, _, 3) => format!("XOR   V{:X}, V{:X}", digit2, digit3),
+        (8, _, _, 4) => format!("ADD   V{:X}, V{:X}", digit2, digit3),
+        (8, _, _, 5) => format!("SUB   V{:X}, V{:X}", digit2, digit3),
+        (8, _, _, 6) => format!("SHR   V{:X}, V{:X}", digit2, digit3),
+        (8, _, _, 7) => format!("SUBN  V{:X}, V{:X}", digit2, digit3),
+        (8, _, _, 0xE) => format!("SHL  V{:X}, V{:X}", digit2, digit3),
+        (9, _, _, _) => format!("SNE  V{:X}, V{:X}", digit2, digit3),
+        (0xA, _, _, _) => format!("LD    I, {}", address),
+        (0xB, _, _, _) => format!("JP    V0, {}", address),
+        (0xC, _, _, _) => format!("RND   V{:X}, {:X}{:X}", digit2, digit3, digit4),
+        (0xD, _, _, _) => {
+            format!("DRW   V{:X}, V{:X}, {:X}", digit2, digit3, digit4)
         }
+        (0xE, _, 9, 0xE) => format!("SKP   V{:X}", digit2),
+        (0xE, _, 0xA, 1) => format!("SKNP  V{:X}", digit2),
+        (0xF, _, 0, 7) => format!("LD    V{:X}, DT", digit2),
+        (0xF, _, 0, 0xA) => format!("LD    V{:X}, K", digit2),
+        (0xF, _, 1, 5) => format!("LD    DT, V{:X}", digit2),
+        (0xF, _, 1, 8) => format!("LD    ST, V{:X}", digit2),
+        (0xF, _, 1, 0xE) => format!("ADD   I, V{:X}", digit2),
+        (0xF, _, 2, 9) => format!("LD    F, V{:X}", digit2),
+        (0xF, _, 3, 3) => format!("LD    B, V{:X}", digit2),
+        (0xF, _, 5, 5) => format!("LD    [I], V{:X}", digit2),
+        (0xF, _, 6, 5) => format!("LD    V{:X}, [I]", digit2),
+        (_, _, _, _) => format!("DW    {:04X}", opcode),
     }
+}
+
+/// Lists the loaded ROM. With a coverage map, bytes that never ran are
+/// shown as data instead of being decoded as instructions, and each region
+/// is headed by what the run used it for.
+pub fn disassemble_rom(chip: &chip::Chip, coverage: Option<&Coverage>) -> String {
+    let start = chip.load_address as usize;
+    let end = start + chip.rom().len();
+    let is_code = |address: usize| coverage.is_none_or(|c| c.is(address, coverage::EXECUTED));
 
-    pub fn show_cpu_state(
+    let mut listing = String::new();
+    let mut region = "";
+    let mut address = start;
+
+    while address < end {
+        let kind = match coverage {
+            None => "",
+            Some(_) if is_code(address) => "; code",
+            Some(c) if c.is(address, coverage::READ | coverage::WRITTEN) => "; data",
+            Some(_) => "; unused",
+        };
+        if kind != region {
+            listing += &format!("\n{}\n", kind);
+            region = kind;
+        }
+
+        if let Some(label) = chip.symbols.label(address as u16) {
+            listing += &format!("{}:\n", label);
+        }
+
+        if is_code(address) && address + 1 < end {
+            let opcode = (chip.memory[address] as u16) << 8 | chip.memory[address + 1] as u16;
+            listing += &format!(
+                "{:03X}: {:04X}  {}\n",
+                address,
+                opcode,
+                disassemble(opcode, &chip.symbols)
+            );
+            address += 2;
+        } else {
+            listing += &format!(
+                "{:03X}: {:02X}    DB    {:02X}\n",
+                address, chip.memory[address], chip.memory[address]
+            );
+            address += 1;
+        }
+    }
+
+    listing.trim_start().to_owned()
+}
+
+/// Why execution last stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(u16),
+    /// A write changed the watched byte at this address.
+    Watchpoint(u16),
+    Step,
+    Pause,
+    /// The program faulted and the machine halted.
+    Fault(chip::Fault),
+}
+
+/// Breakpoints, watchpoints, cheats and run state shared by the debugger
+/// frontends.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    /// Addresses of bytes that pause execution when written to.
+    pub watchpoints: BTreeSet<u16>,
+    pub paused: bool,
+    pub last_stop: Option<Stop>,
+    /// Cheats for the running ROM, applied after every frame.
+    pub cheats: Cheats,
+    /// When set, pause as soon as the stack is back at this depth. Used to
+    /// step over calls and out of subroutines.
+    until_depth: Option<u8>,
+    /// Set when resuming so the breakpoint at the current PC doesn't
+    /// immediately fire again.
+    resume_at: Option<u16>,
+    /// A screenshot asked for with `screenshot`, taken by the frontend since
+    /// it knows the palette and display scale. Holds the scale asked for,
+    /// if any.
+    pub screenshot: Option<Option<u32>>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Runs a frame unless paused, pausing at the first breakpoint hit or
+    /// watched byte changed. `hook` is called before each instruction that
+    /// runs.
+    pub fn run_frame<H: FnMut(&mut chip::Chip)>(
         &mut self,
-        chip: &chip::Chip,
-        digit1: u16,
-        digit2: u16,
-        digit3: u16,
-        digit4: u16,
+        chip: &mut chip::Chip,
+        instructions: u32,
+        mut hook: H,
     ) {
-        match (digit1, digit2, digit3, digit4) {
-            (0, _, _, 0xE) => self.asm = "RET".to_owned(),
-            (0, _, _, _) => self.asm = "CLR".to_owned(),
-            (1, _, _, _) => self.asm = format!("JMP   {:X}{:X}{:X}", digit2, digit3, digit4),
-            (2, _, _, _) => self.asm = format!("CALL  {:X}{:X}{:X}", digit2, digit3, digit4),
-            (3, _, _, _) => self.asm = format!("SE    V{:X}, {:X}{:X}", digit2, digit3, digit4),
-            (4, _, _, _) => self.asm = format!("SNE   V{:X}, {:X}{:X}", digit2, digit3, digit4),
-            (5, _, _, _) => self.asm = format!("SE    V{:X}, V{:X}", digit2, digit3),
-            (6, _, _, _) => self.asm = format!("LD    V{:X}, {:X}{:X}", digit2, digit3, digit4),
-            (7, _, _, _) => self.asm = format!("ADD   V{:X}, {:X}{:X}", digit2, digit3, digit4),
-            (8, _, _, 0) => self.asm = format!("LD    V{:X}, V{:X}", digit2, digit3),
-            (8, _, _, 1) => self.asm = format!("OR    V{:X}, V{:X}", digit2, digit3),
-            (8, _, _, 2) => self.asm = format!("AND   V{:X}, V{:X}", digit2, digit3),
-            (8, _, _, 3) => self.asm = format!("XOR   V{:X}, V{:X}", digit2, digit3),
-            (8, _, _, 4) => self.asm = format!("ADD   V{:X}, V{:X}", digit2, digit3),
-            (8, _, _, 5) => self.asm = format!("SUB   V{:X}, V{:X}", digit2, digit3),
-            (8, _, _, 6) => self.asm = format!("SHR   V{:X}, V{:X}", digit2, digit3),
-            (8, _, _, 7) => self.asm = format!("SUBN  V{:X}, V{:X}", digit2, digit3),
-            (8, _, _, 0xE) => self.asm = format!("SHL  V{:X}, V{:X}", digit2, digit3),
-            (9, _, _, _) => self.asm = format!("SNE  V{:X}, V{:X}", digit2, digit3),
-            (0xA, _, _, _) => self.asm = format!("LD    I, {:X}{:X}{:X}", digit2, digit3, digit4),
-            (0xB, _, _, _) => self.asm = format!("JP    V0, {:X}{:X}{:X}", digit2, digit3, digit4),
-            (0xC, _, _, _) => self.asm = format!("RND   V{:X}, {:X}{:X}", digit2, digit3, digit4),
-            (0xD, _, _, _) => {
-                self.asm = format!("DRW   V{:X}, V{:X}, {:X}", digit2, digit3, digit4)
-            }
-            (0xE, _, 9, 0xE) => self.asm = format!("SKP   V{:X}", digit2),
-            (0xE, _, 0xA, 1) => self.asm = format!("SKNP  V{:X}", digit2),
-            (0xF, _, 0, 7) => self.asm = format!("LD    V{:X}, DT", digit2),
-            (0xF, _, 0, 0xA) => self.asm = format!("LD    V{:X}, K", digit2),
-            (0xF, _, 1, 5) => self.asm = format!("LD    DT, V{:X}", digit2),
-            (0xF, _, 1, 8) => self.asm = format!("LD    ST, V{:X}", digit2),
-            (0xF, _, 1, 0xE) => self.asm = format!("ADD   I, V{:X}", digit2),
-            (0xF, _, 2, 9) => self.asm = format!("LD    F, V{:X}", digit2),
-            (0xF, _, 3, 3) => self.asm = format!("LD    B, V{:X}", digit2),
-            (0xF, _, 5, 5) => self.asm = format!("LD    [I], V{:X}", digit2),
-            (0xF, _, 6, 5) => self.asm = format!("LD    V{:X}, [I]", digit2),
-            (_, _, _, _) => unreachable!("Ran unimplemented instruction {:X}", chip.opcode),
+        if self.paused {
+            return;
+        }
+
+        let breakpoints = &self.breakpoints;
+        let watched: Vec<(u16, u8)> = self
+            .watchpoints
+            .iter()
+            .map(|address| (*address, chip.memory[*address as usize % chip.memory.len()]))
+            .collect();
+        let mut resume_at = self.resume_at.take();
+        let until_depth = self.until_depth;
+        let mut stop = None;
+
+        chip.run_frame_until(instructions, |chip| {
+            if let Some((address, _)) = watched.iter().find(|(address, value)| {
+                chip.memory[*address as usize % chip.memory.len()] != *value
+            }) {
+                stop = Some(Stop::Watchpoint(*address));
+            } else if resume_at.take() != Some(chip.pc) && breakpoints.contains(&chip.pc) {
+                stop = Some(Stop::Breakpoint(chip.pc));
+            } else if until_depth == Some(chip.sp) {
+                stop = Some(Stop::Step);
+            } else {
+                hook(chip);
+            }
+            stop.is_some()
+        });
+        self.cheats.apply(chip);
+
+        if let Some(stop) = stop.or(chip.fault.map(Stop::Fault)) {
+            match stop {
+                Stop::Step => {}
+                Stop::Fault(fault) => println!(
+                    "Halted at {}: {}",
+                    chip.symbols.format_address(chip.pc),
+                    fault
+                ),
+                Stop::Watchpoint(address) => println!(
+                    "Watchpoint {} changed, PC at {}",
+                    chip.symbols.format_address(address),
+                    chip.symbols.format_address(chip.pc)
+                ),
+                _ => println!("Breakpoint at {}", chip.symbols.format_address(chip.pc)),
+            }
+            self.pause(stop);
+        }
+    }
+
+    pub fn pause(&mut self, reason: Stop) {
+        self.paused = true;
+        self.until_depth = None;
+        self.last_stop = Some(reason);
+    }
+
+    pub fn resume(&mut self, chip: &chip::Chip) {
+        self.paused = false;
+        self.last_stop = None;
+        self.until_depth = None;
+        self.resume_at = Some(chip.pc);
+    }
+
+    /// Steps one instruction, running a called subroutine to its return.
+    pub fn step_over(&mut self, chip: &mut chip::Chip) {
+        let depth = chip.sp;
+        self.step(chip, 1);
+
+        if chip.sp > depth {
+            self.resume(chip);
+            self.until_depth = Some(depth);
+        }
+    }
+
+    /// Runs until the current subroutine returns.
+    pub fn step_out(&mut self, chip: &chip::Chip) {
+        match chip.sp.checked_sub(1) {
+            Some(depth) => {
+                self.resume(chip);
+                self.until_depth = Some(depth);
+            }
+            None => self.pause(Stop::Step),
+        }
+    }
+
+    pub fn step(&mut self, chip: &mut chip::Chip, count: u16) {
+        for _ in 0..count {
+            chip.cycle();
+        }
+        self.pause(chip.fault.map_or(Stop::Step, Stop::Fault));
+    }
+}
+
+/// A command typed into the debugger console. Addresses may be given as
+/// hex or as a label from the loaded symbol file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// `peek ADDR [LEN]`: dump LEN bytes (default 16) starting at ADDR.
+    Peek { address: u16, len: u16 },
+    /// `poke ADDR BYTE...`: write bytes starting at ADDR.
+    Poke { address: u16, bytes: Vec<u8> },
+    /// `break ADDR`: pause before executing ADDR.
+    Break { address: u16 },
+    /// `delete ADDR`: remove a breakpoint.
+    Delete { address: u16 },
+    /// `breakpoints`: list breakpoints.
+    Breakpoints,
+    /// `watch ADDR`: pause after the byte at ADDR is written.
+    Watch { address: u16 },
+    /// `unwatch ADDR`: remove a watchpoint.
+    Unwatch { address: u16 },
+    /// `pause`: stop running.
+    Pause,
+    /// `continue`: run until the next breakpoint.
+    Continue,
+    /// `step [N]`: execute N instructions (default 1) and stay paused.
+    Step { count: u16 },
+    /// `search [BYTE]`: start a cheat search over memory and registers,
+    /// optionally keeping only locations holding BYTE.
+    Search { value: Option<u8> },
+    /// `narrow changed|unchanged|increased|decreased|BYTE`: narrow the
+    /// search against the values seen last time.
+    Narrow { filter: Filter },
+    /// `results`: list the search candidates.
+    Results,
+    /// `freeze LOC BYTE [NAME]`: hold an address or `VX` at BYTE every frame
+    /// and save it as a cheat for this ROM.
+    Freeze { cheat: Cheat },
+    /// `unfreeze NAME|LOC`: remove a cheat.
+    Unfreeze { name: String },
+    /// `cheats`: list the cheats.
+    Cheats,
+    /// `screenshot [SCALE]`: save the display as PNG at native resolution
+    /// and at SCALE, or at the display scale without one.
+    Screenshot { scale: Option<u32> },
+}
+
+impl Command {
+    pub fn parse(line: &str, symbols: &SymbolTable) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or("empty command")?;
+        let args: Vec<&str> = words.collect();
+
+        let address = |text: &str| {
+            symbols
+                .resolve(text)
+                .ok_or_else(|| format!("'{}' is neither a label nor an address", text))
+        };
+
+        let byte = |text: &str| {
+            parse_hex(text)
+                .ok()
+                .and_then(|value| u8::try_from(value).ok())
+                .ok_or_else(|| format!("'{}' is not a hex byte", text))
+        };
+        let location = |text: &str| {
+            Location::parse(text, symbols)
+                .ok_or_else(|| format!("'{}' is neither a register nor an address", text))
+        };
+
+        match (name, args.as_slice()) {
+            ("peek", [at]) => Ok(Command::Peek {
+                address: address(at)?,
+                len: 16,
+            }),
+            ("peek", [at, len]) => Ok(Command::Peek {
+                address: address(at)?,
+                len: parse_hex(len)?,
+            }),
+            ("poke", [at, bytes @ ..]) if !bytes.is_empty() => Ok(Command::Poke {
+                address: address(at)?,
+                bytes: bytes
+                    .iter()
+                    .map(|text| byte(text))
+                    .collect::<Result<_, _>>()?,
+            }),
+            ("break" | "b", [at]) => Ok(Command::Break {
+                address: address(at)?,
+            }),
+            ("delete" | "d", [at]) => Ok(Command::Delete {
+                address: address(at)?,
+            }),
+            ("breakpoints", []) => Ok(Command::Breakpoints),
+            ("watch", [at]) => Ok(Command::Watch {
+                address: address(at)?,
+            }),
+            ("unwatch", [at]) => Ok(Command::Unwatch {
+                address: address(at)?,
+            }),
+            ("pause", []) => Ok(Command::Pause),
+            ("continue" | "c", []) => Ok(Command::Continue),
+            ("step" | "s", []) => Ok(Command::Step { count: 1 }),
+            ("step" | "s", [count]) => Ok(Command::Step {
+                count: count
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a count", count))?,
+            }),
+            ("search", []) => Ok(Command::Search { value: None }),
+            ("search", [value]) => Ok(Command::Search {
+                value: Some(byte(value)?),
+            }),
+            ("narrow", [filter]) => Ok(Command::Narrow {
+                filter: match *filter {
+                    "changed" => Filter::Changed,
+                    "unchanged" => Filter::Unchanged,
+                    "increased" => Filter::Increased,
+                    "decreased" => Filter::Decreased,
+                    value => Filter::Equal(byte(value)?),
+                },
+            }),
+            ("results", []) => Ok(Command::Results),
+            ("freeze", [at, value, name @ ..]) if name.len() <= 1 => {
+                let location = location(at)?;
+                Ok(Command::Freeze {
+                    cheat: Cheat {
+                        name: name
+                            .first()
+                            .map_or(location.to_string(), |name| name.to_string()),
+                        location,
+                        value: byte(value)?,
+                    },
+                })
+            }
+            ("unfreeze", [name]) => Ok(Command::Unfreeze {
+                name: name.to_string(),
+            }),
+            ("cheats", []) => Ok(Command::Cheats),
+            ("screenshot", []) => Ok(Command::Screenshot { scale: None }),
+            ("screenshot", [scale]) => Ok(Command::Screenshot {
+                scale: Some(
+                    scale
+                        .parse()
+                        .ok()
+                        .filter(|scale| *scale > 0)
+                        .ok_or_else(|| format!("'{}' is not a scale", scale))?,
+                ),
+            }),
+            ("peek", _) => Err("usage: peek ADDR [LEN]".to_owned()),
+            ("poke", _) => Err("usage: poke ADDR BYTE...".to_owned()),
+            ("break" | "b" | "delete" | "d" | "watch" | "unwatch", _) => {
+                Err(format!("usage: {} ADDR", name))
+            }
+            ("step" | "s", _) => Err("usage: step [N]".to_owned()),
+            ("search", _) => Err("usage: search [BYTE]".to_owned()),
+            ("narrow", _) => {
+                Err("usage: narrow changed|unchanged|increased|decreased|BYTE".to_owned())
+            }
+            ("freeze", _) => Err("usage: freeze LOC BYTE [NAME]".to_owned()),
+            ("unfreeze", _) => Err("usage: unfreeze NAME|LOC".to_owned()),
+            ("screenshot", _) => Err("usage: screenshot [SCALE]".to_owned()),
+            _ => Err(format!("unknown command '{}'", name)),
+        }
+    }
+
+    /// Runs the command against `chip`, returning what to print.
+    pub fn execute(&self, chip: &mut chip::Chip, debugger: &mut Debugger) -> String {
+        let memory_len = chip.memory.len();
+        let symbols = chip.symbols.clone();
+
+        match self {
+            Command::Peek { address, len } => {
+                let start = *address as usize % memory_len;
+                let end = (start + *len as usize).min(memory_len);
+
+                chip.memory[start..end]
+                    .chunks(16)
+                    .enumerate()
+                    .map(|(row, bytes)| {
+                        let hex: Vec<String> =
+                            bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                        format!("{:03X}: {}", start + row * 16, hex.join(" "))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            Command::Poke { address, bytes } => {
+                chip.write_memory(*address, bytes);
+                format!("wrote {} byte(s) at {:03X}", bytes.len(), address)
+            }
+            Command::Break { address } => {
+                debugger.breakpoints.insert(*address);
+                format!("breakpoint at {}", symbols.format_address(*address))
+            }
+            Command::Delete { address } => {
+                if debugger.breakpoints.remove(address) {
+                    format!("deleted breakpoint at {}", symbols.format_address(*address))
+                } else {
+                    format!("no breakpoint at {}", symbols.format_address(*address))
+                }
+            }
+            Command::Breakpoints => debugger
+                .breakpoints
+                .iter()
+                .map(|address| format!("{:03X} {}", address, symbols.label(*address).unwrap_or("")))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Command::Watch { address } => {
+                debugger.watchpoints.insert(*address);
+                format!("watchpoint at {}", symbols.format_address(*address))
+            }
+            Command::Unwatch { address } => {
+                if debugger.watchpoints.remove(address) {
+                    format!("deleted watchpoint at {}", symbols.format_address(*address))
+                } else {
+                    format!("no watchpoint at {}", symbols.format_address(*address))
+                }
+            }
+            Command::Pause => {
+                debugger.pause(Stop::Pause);
+                format!("paused at {}", symbols.format_address(chip.pc))
+            }
+            Command::Continue => {
+                debugger.resume(chip);
+                "running".to_owned()
+            }
+            Command::Step { count } => {
+                debugger.step(chip, *count);
+                format!("paused at {}", symbols.format_address(chip.pc))
+            }
+            Command::Search { value } => {
+                let mut search = Search::new(chip);
+                if let Some(value) = value {
+                    search.narrow(chip, Filter::Equal(*value));
+                }
+                let count = search.candidates.len();
+                debugger.cheats.search = Some(search);
+                format!("{} candidate(s)", count)
+            }
+            Command::Narrow { filter } => match &mut debugger.cheats.search {
+                Some(search) => {
+                    search.narrow(chip, *filter);
+                    format!("{} candidate(s)", search.candidates.len())
+                }
+                None => "no search running, start one with 'search'".to_owned(),
+            },
+            Command::Results => match &debugger.cheats.search {
+                Some(search) => {
+                    let mut lines: Vec<String> = search
+                        .candidates
+                        .iter()
+                        .take(MAX_RESULTS)
+                        .map(|(location, value)| format!("{} = {:02X}", location, value))
+                        .collect();
+                    if search.candidates.len() > MAX_RESULTS {
+                        lines.push(format!(
+                            "... and {} more",
+                            search.candidates.len() - MAX_RESULTS
+                        ));
+                    }
+                    lines.join("\n")
+                }
+                None => "no search running, start one with 'search'".to_owned(),
+            },
+            Command::Freeze { cheat } => {
+                let message = format!(
+                    "froze {} at {:02X} as '{}'",
+                    cheat.location, cheat.value, cheat.name
+                );
+                cheat.location.write(chip, cheat.value);
+                debugger.cheats.freeze(cheat.clone());
+                match debugger.cheats.save() {
+                    Ok(()) => message,
+                    Err(err) => format!("{}, but could not save cheats: {}", message, err),
+                }
+            }
+            Command::Unfreeze { name } => {
+                if !debugger.cheats.unfreeze(name, &symbols) {
+                    return format!("no cheat '{}'", name);
+                }
+                match debugger.cheats.save() {
+                    Ok(()) => format!("removed cheat '{}'", name),
+                    Err(err) => format!("removed cheat '{}', but could not save: {}", name, err),
+                }
+            }
+            Command::Cheats => debugger
+                .cheats
+                .frozen
+                .iter()
+                .map(|cheat| format!("{} = {:02X}  {}", cheat.location, cheat.value, cheat.name))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Command::Screenshot { scale } => {
+                debugger.screenshot = Some(*scale);
+                "taking screenshot".to_owned()
+            }
         }
+    }
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not a hex number", text))
+}
+
+/// Reads debugger commands from stdin on a background thread so the main
+/// loop can pick them up between frames without blocking.
+pub struct Console {
+    lines: Receiver<String>,
+}
+
+impl Console {
+    pub fn spawn() -> Console {
+        let (sender, lines) = mpsc::channel();
+
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Console { lines }
+    }
+
+    /// Executes every command typed since the last call.
+    pub fn poll(&self, chip: &mut chip::Chip, debugger: &mut Debugger) {
+        for line in self.lines.try_iter() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match Command::parse(&line, &chip.symbols) {
+                Ok(command) => println!("{}", command.execute(chip, debugger)),
+                Err(err) => println!("{}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        println!("0{:X}: {}", chip.pc, self.asm);
+    #[test]
+    fn poke_takes_only_bytes() {
+        let symbols = SymbolTable::default();
+        assert_eq!(
+            Command::parse("poke 300 12 ff", &symbols),
+            Ok(Command::Poke {
+                address: 0x300,
+                bytes: vec![0x12, 0xFF],
+            })
+        );
+        assert!(Command::parse("poke 300 1ff", &symbols).is_err());
     }
 }
@@ -0,0 +1,456 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::iter;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::cheats::Location;
+use crate::chip::{Chip, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use crate::romdb::sha1_hex;
+use crate::symbols::SymbolTable;
+
+pub const DEFAULT_RULES_PATH: &str = "environments.toml";
+
+/// Which keys each action holds, how reward is scored and when an episode
+/// ends, for one ROM. Rules are kept per ROM hash in a TOML file:
+///
+/// ```toml
+/// [f1cfcffe1937ed6dd6eeed1a7f85dfc777bda700]
+/// actions = ["", "4", "6", "5", "45", "56"]
+/// reward = [{ location = "V5", scale = 1.0 }]
+/// done = [{ location = "V3", equals = 0 }]
+/// ```
+///
+/// Each action is the hex keys held together, `""` for none; without
+/// `actions`, action 0 holds nothing and action `n` holds key `n - 1`.
+/// Reward is how much each location went up over a step, times its scale.
+/// An episode ends once any `done` location equals, is below or is above
+/// the given value.
+#[derive(Debug, Clone)]
+pub struct Rules {
+    /// Each action's keys, a bit each.
+    pub actions: Vec<u16>,
+    pub rewards: Vec<(Location, f32)>,
+    pub done: Vec<(Location, Condition)>,
+}
+
+/// A test on a location's value that ends an episode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Equals(u8),
+    Below(u8),
+    Above(u8),
+}
+
+impl Condition {
+    fn holds(self, value: u8) -> bool {
+        match self {
+            Condition::Equals(target) => value == target,
+            Condition::Below(target) => value < target,
+            Condition::Above(target) => value > target,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RulesEntry {
+    actions: Option<Vec<String>>,
+    #[serde(default)]
+    reward: Vec<RewardEntry>,
+    #[serde(default)]
+    done: Vec<DoneEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RewardEntry {
+    location: String,
+    #[serde(default = "unit_scale")]
+    scale: f32,
+}
+
+fn unit_scale() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Deserialize)]
+struct DoneEntry {
+    location: String,
+    equals: Option<u8>,
+    below: Option<u8>,
+    above: Option<u8>,
+}
+
+impl Rules {
+    /// Reads the rules for `rom` from the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P, rom: &[u8]) -> Result<Rules, Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        Rules::parse(&contents, rom).map_err(|err| format!("{}: {}", path.display(), err).into())
+    }
+
+    /// Finds the rules for `rom` in the TOML `contents`.
+    pub fn parse(contents: &str, rom: &[u8]) -> Result<Rules, Box<dyn Error>> {
+        let mut file: BTreeMap<String, RulesEntry> = toml::from_str(contents)?;
+        let hash = sha1_hex(rom);
+        let entry = file
+            .remove(&hash)
+            .ok_or_else(|| format!("no rules for ROM {}", hash))?;
+
+        let symbols = SymbolTable::default();
+        let location = |text: &str| {
+            Location::parse(text, &symbols).ok_or_else(|| format!("bad location '{}'", text))
+        };
+
+        let actions = match entry.actions {
+            Some(actions) => actions
+                .iter()
+                .map(|keys| parse_keys(keys))
+                .collect::<Result<Vec<u16>, String>>()?,
+            None => iter::once(0).chain((0..16).map(|key| 1 << key)).collect(),
+        };
+        if actions.is_empty() {
+            return Err("an environment needs at least one action".into());
+        }
+
+        let rewards = entry
+            .reward
+            .iter()
+            .map(|reward| Ok((location(&reward.location)?, reward.scale)))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let mut done = Vec::new();
+        for entry in &entry.done {
+            let conditions = [
+                entry.equals.map(Condition::Equals),
+                entry.below.map(Condition::Below),
+                entry.above.map(Condition::Above),
+            ];
+            let mut conditions = conditions.into_iter().flatten().peekable();
+            if conditions.peek().is_none() {
+                return Err(format!(
+                    "done rule for '{}' needs equals, below or above",
+                    entry.location
+                )
+                .into());
+            }
+            let location = location(&entry.location)?;
+            done.extend(conditions.map(|condition| (location, condition)));
+        }
+
+        Ok(Rules {
+            actions,
+            rewards,
+            done,
+        })
+    }
+}
+
+/// Parses hex key digits such as `"1C"` as a bit per key.
+fn parse_keys(keys: &str) -> Result<u16, String> {
+    keys.chars().try_fold(0, |mask, digit| {
+        digit
+            .to_digit(16)
+            .map(|key| mask | 1 << key)
+            .ok_or_else(|| format!("bad key '{}' in action '{}'", digit, keys))
+    })
+}
+
+/// How an `Environment` runs the machine for each step.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Frames run per step, with the action held throughout.
+    pub frame_skip: u32,
+    /// Chance each frame of holding the previous frame's action instead of
+    /// the one asked for, as the Arcade Learning Environment does to keep
+    /// agents from memorising input sequences. From 0 to 1;
+    /// `Environment::new` refuses anything else.
+    pub sticky_action_probability: f64,
+    pub instructions_per_frame: u32,
+    /// Seeds the sticky action draws and the machine's `CXNN`, so runs can
+    /// be repeated.
+    pub seed: u64,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            frame_skip: 4,
+            sticky_action_probability: 0.0,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            seed: 0,
+        }
+    }
+}
+
+/// A Gym-style reinforcement-learning environment over a machine with a ROM
+/// loaded. Observations are the display, a byte per pixel, nonzero where
+/// lit.
+///
+/// Cloning is cheap, besides the machine itself: the starting machine and
+/// the rules are shared, so parallel environments can be forked from one
+/// another. Clones draw the same sticky actions and random numbers unless
+/// reseeded.
+#[derive(Debug, Clone)]
+pub struct Environment {
+    chip: Chip,
+    /// The machine as it was given, which `reset` goes back to.
+    start: Arc<Chip>,
+    rules: Arc<Rules>,
+    options: Options,
+    rng: StdRng,
+    previous_action: usize,
+    /// Each reward location's value at the end of the last step.
+    scores: Vec<u8>,
+    done: bool,
+}
+
+impl Environment {
+    pub fn new(chip: Chip, rules: Rules, options: Options) -> Result<Environment, Box<dyn Error>> {
+        let probability = options.sticky_action_probability;
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(format!(
+                "sticky action probability must be from 0 to 1, not {}",
+                probability
+            )
+            .into());
+        }
+
+        let mut environment = Environment {
+            start: Arc::new(chip.clone()),
+            chip,
+            rules: Arc::new(rules),
+            options,
+            rng: StdRng::seed_from_u64(options.seed),
+            previous_action: 0,
+            scores: Vec::new(),
+            done: false,
+        };
+        environment.reset();
+        Ok(environment)
+    }
+
+    /// Puts the machine back as it started, with fresh random numbers for
+    /// `CXNN`, and returns the first observation. The `done` rules aren't
+    /// checked until a frame has run, as a program has yet to set up, say,
+    /// its lives counter.
+    pub fn reset(&mut self) -> Vec<u8> {
+        self.chip = Chip::clone(&self.start);
+        self.chip.reseed(self.rng.gen());
+        self.previous_action = 0;
+        self.scores = self.read_scores();
+        self.done = false;
+        self.observation()
+    }
+
+    /// Holds `action`'s keys for `Options::frame_skip` frames, or until the
+    /// episode ends, and returns the observation, the reward over those
+    /// frames, and whether the episode has ended. Besides the `done` rules,
+    /// an episode ends when the program faults, with `chip().fault` saying
+    /// why. Once it has ended, stepping does nothing until `reset`.
+    ///
+    /// Panics if `action` isn't below `action_count`.
+    pub fn step(&mut self, action: usize) -> (Vec<u8>, f32, bool) {
+        assert!(
+            action < self.action_count(),
+            "action {} out of range for {} actions",
+            action,
+            self.action_count()
+        );
+
+        let mut reward = 0.0;
+        for _ in 0..self.options.frame_skip {
+            if self.done {
+                break;
+            }
+
+            let sticky = self.rng.gen_bool(self.options.sticky_action_probability);
+            if !sticky {
+                self.previous_action = action;
+            }
+            let keys = self.rules.actions[self.previous_action];
+            self.chip.keypad.hold(keys);
+            self.chip
+                .run_frame_until(self.options.instructions_per_frame, |_| false);
+
+            reward += self.collect_reward();
+            self.done = self.is_done();
+        }
+
+        (self.observation(), reward, self.done)
+    }
+
+    pub fn action_count(&self) -> usize {
+        self.rules.actions.len()
+    }
+
+    pub fn observation(&self) -> Vec<u8> {
+        self.chip.video.clone()
+    }
+
+    /// The display's width and height, which a program can change.
+    pub fn observation_size(&self) -> (usize, usize) {
+        (self.chip.video_width, self.chip.video_height)
+    }
+
+    pub fn chip(&self) -> &Chip {
+        &self.chip
+    }
+
+    /// Reseeds the sticky action draws, and the random numbers from the
+    /// next `reset` on, as for a clone meant to explore differently.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    fn read_scores(&self) -> Vec<u8> {
+        let rewards = &self.rules.rewards;
+        rewards
+            .iter()
+            .map(|(location, _)| location.read(&self.chip))
+            .collect()
+    }
+
+    /// The scaled rise in each reward location since the last call. A rise
+    /// is taken as the shorter way round, so a counter wrapping from 255 to
+    /// 0 counts as one up.
+    fn collect_reward(&mut self) -> f32 {
+        let scores = self.read_scores();
+        let reward = self
+            .rules
+            .rewards
+            .iter()
+            .zip(scores.iter().zip(&self.scores))
+            .map(|((_, scale), (now, before))| now.wrapping_sub(*before) as i8 as f32 * scale)
+            .sum();
+        self.scores = scores;
+        reward
+    }
+
+    fn is_done(&self) -> bool {
+        let done = &self.rules.done;
+        self.chip.fault.is_some()
+            || done
+                .iter()
+                .any(|(location, condition)| condition.holds(location.read(&self.chip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::Fault;
+
+    /// Scores a point a frame in V5 and loses its one life in V3 the
+    /// first frame key 6 is held.
+    const ROM: &[u8] = &[
+        0x63, 0x01, // 200: V3 = 1
+        0x65, 0x00, // 202: V5 = 0
+        0x64, 0x06, // 204: V4 = 6
+        0x66, 0x01, // 206: V6 = 1
+        0xF0, 0x07, // 208: V0 = DT
+        0x30, 0x00, // 20A: skip if V0 == 0
+        0x12, 0x08, // 20C: jump 208, waiting for the next frame
+        0xF6, 0x15, // 20E: DT = V6
+        0x75, 0x01, // 210: V5 += 1
+        0xE4, 0x9E, // 212: skip if key V4 is down
+        0x12, 0x08, // 214: jump 208
+        0x63, 0x00, // 216: V3 = 0
+        0x12, 0x08, // 218: jump 208
+    ];
+
+    fn environment(options: Options) -> Result<Environment, Box<dyn Error>> {
+        environment_for(ROM, options)
+    }
+
+    fn environment_for(rom: &[u8], options: Options) -> Result<Environment, Box<dyn Error>> {
+        let rules = format!(
+            "[{}]\n\
+             actions = [\"\", \"6\"]\n\
+             reward = [{{ location = \"V5\", scale = 0.5 }}]\n\
+             done = [{{ location = \"V3\", equals = 0 }}]\n",
+            sha1_hex(rom)
+        );
+        let rules = Rules::parse(&rules, rom)?;
+        let mut chip = Chip::new();
+        chip.load_bytes(rom)?;
+        Environment::new(chip, rules, options)
+    }
+
+    #[test]
+    fn steps_score_and_end_episodes() {
+        let mut environment = environment(Options::default()).unwrap();
+        assert_eq!(environment.action_count(), 2);
+        let observation = environment.reset();
+        assert_eq!(observation, environment.chip().video);
+        assert!(observation.iter().all(|pixel| *pixel == 0));
+
+        // A point a frame, at half a point each, over four frames a step.
+        let (_, reward, done) = environment.step(0);
+        assert_eq!((reward, done), (2.0, false));
+        assert_eq!(environment.chip().registers[5], 4);
+
+        // Holding key 6 ends the episode after its first frame.
+        let (_, reward, done) = environment.step(1);
+        assert_eq!((reward, done), (0.5, true));
+        assert_eq!(environment.chip().registers[5], 5);
+        assert_eq!(environment.step(0).1, 0.0);
+        assert_eq!(environment.chip().registers[5], 5);
+
+        environment.reset();
+        assert_eq!(environment.chip().registers[5], 0);
+        assert_eq!(environment.step(0), (environment.observation(), 2.0, false));
+    }
+
+    #[test]
+    fn frame_skip_sets_frames_per_step() {
+        let options = Options {
+            frame_skip: 3,
+            ..Options::default()
+        };
+        let mut environment = environment(options).unwrap();
+        assert_eq!(environment.step(0).1, 1.5);
+        assert_eq!(environment.step(0).1, 1.5);
+        assert_eq!(environment.chip().registers[5], 6);
+    }
+
+    #[test]
+    fn sticky_actions_hold_the_previous_action() {
+        let options = Options {
+            sticky_action_probability: 1.0,
+            ..Options::default()
+        };
+        let mut environment = environment(options).unwrap();
+        assert!(!environment.step(1).2);
+    }
+
+    #[test]
+    fn refuses_sticky_probabilities_outside_zero_to_one() {
+        for probability in [-0.1, 1.5, f64::NAN] {
+            let options = Options {
+                sticky_action_probability: probability,
+                ..Options::default()
+            };
+            assert!(environment(options).is_err(), "{} accepted", probability);
+        }
+    }
+
+    #[test]
+    fn faults_end_episodes() {
+        // Scores a point, then jumps into data.
+        let rom = [0x63, 0x01, 0x75, 0x01, 0x12, 0x06, 0xFF, 0xFF];
+        let mut environment = environment_for(&rom, Options::default()).unwrap();
+        let (_, reward, done) = environment.step(0);
+        assert_eq!((reward, done), (0.5, true));
+        assert_eq!(
+            environment.chip().fault,
+            Some(Fault::UndefinedInstruction(0xFFFF))
+        );
+
+        environment.reset();
+        assert_eq!(environment.chip().fault, None);
+    }
+}
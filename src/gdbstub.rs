@@ -0,0 +1,508 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::chip::{Chip, Fault, MEMORY_SIZE};
+use crate::debugger::{Debugger, Stop};
+
+const PACKET_SIZE: usize = 0x1000;
+
+/// Register numbers as seen by the client: V0-VF, then I, PC, SP, DT, ST.
+const REGISTER_I: usize = 16;
+const REGISTER_PC: usize = 17;
+const REGISTER_SP: usize = 18;
+const REGISTER_DT: usize = 19;
+const REGISTER_ST: usize = 20;
+const REGISTER_COUNT: usize = 21;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/// A GDB remote serial protocol server on a local TCP port.
+///
+/// The server is polled from the main loop between frames, so the
+/// interpreter keeps running normally until a client attaches. Attaching
+/// pauses the machine; `c`, `s`, `Z0`/`Z1` breakpoints and `Z2` write
+/// watchpoints map onto the shared `Debugger`.
+pub struct GdbServer {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    buffer: Vec<u8>,
+    /// True after `c` until the machine stops and the client is told why.
+    running: bool,
+}
+
+impl GdbServer {
+    pub fn bind(port: u16) -> io::Result<GdbServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        println!("GDB server listening on 127.0.0.1:{}", port);
+
+        Ok(GdbServer {
+            listener,
+            client: None,
+            buffer: Vec::new(),
+            running: false,
+        })
+    }
+
+    /// Accepts a client, handles any packets it sent and reports stops.
+    pub fn poll(&mut self, chip: &mut Chip, debugger: &mut Debugger) {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, address)) => {
+                    if stream.set_nonblocking(true).is_ok() {
+                        println!("GDB client connected from {}", address);
+                        self.client = Some(stream);
+                        self.buffer.clear();
+                        self.running = false;
+                        debugger.pause(Stop::Pause);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    eprintln!("GDB server: {}", err);
+                    return;
+                }
+            }
+        }
+
+        if let Err(err) = self.serve(chip, debugger) {
+            println!("GDB client disconnected: {}", err);
+            self.client = None;
+            self.running = false;
+            debugger.resume(chip);
+        }
+    }
+
+    fn serve(&mut self, chip: &mut Chip, debugger: &mut Debugger) -> io::Result<()> {
+        let mut chunk = [0; 1024];
+        loop {
+            let client = self.client.as_mut().unwrap();
+            match client.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "closed")),
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        while let Some(packet) = self.next_packet()? {
+            match packet {
+                Incoming::Interrupt => {
+                    debugger.pause(Stop::Pause);
+                }
+                Incoming::Packet(data) => {
+                    if let Some(reply) = self.handle(&data, chip, debugger) {
+                        self.send(&reply)?;
+                    }
+                }
+            }
+
+            if self.client.is_none() {
+                return Ok(());
+            }
+        }
+
+        if self.running && debugger.paused {
+            self.running = false;
+            let reply = stop_reply(debugger.last_stop);
+            self.send(&reply)?;
+        }
+
+        Ok(())
+    }
+
+    /// Pops the next complete packet or interrupt off the input buffer,
+    /// acknowledging packets as they're taken.
+    fn next_packet(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            match self.buffer.first() {
+                None => return Ok(None),
+                Some(0x03) => {
+                    self.buffer.remove(0);
+                    return Ok(Some(Incoming::Interrupt));
+                }
+                Some(b'$') => break,
+                // Acks, nacks and noise between packets.
+                Some(_) => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+
+        let end = match self.buffer.iter().position(|byte| *byte == b'#') {
+            Some(end) if end + 2 < self.buffer.len() => end,
+            _ => return Ok(None),
+        };
+
+        let data: Vec<u8> = self.buffer[1..end].to_vec();
+        let checksum = std::str::from_utf8(&self.buffer[end + 1..end + 3])
+            .ok()
+            .and_then(|text| u8::from_str_radix(text, 16).ok());
+        self.buffer.drain(..end + 3);
+
+        let client = self.client.as_mut().unwrap();
+        if checksum != Some(checksum_of(&data)) {
+            client.write_all(b"-")?;
+            return self.next_packet();
+        }
+        client.write_all(b"+")?;
+
+        Ok(Some(Incoming::Packet(
+            String::from_utf8_lossy(&data).into_owned(),
+        )))
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let client = self.client.as_mut().unwrap();
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        client.write_all(packet.as_bytes())
+    }
+
+    /// Returns the reply to a packet, or `None` when the reply is deferred
+    /// until the machine stops.
+    fn handle(&mut self, packet: &str, chip: &mut Chip, debugger: &mut Debugger) -> Option<String> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => stop_reply(debugger.last_stop),
+            Some(b'g') => (0..REGISTER_COUNT)
+                .map(|register| read_register(chip, register))
+                .collect(),
+            Some(b'G') => write_all_registers(chip, &packet[1..]),
+            Some(b'p') => usize::from_str_radix(&packet[1..], 16)
+                .ok()
+                .filter(|register| *register < REGISTER_COUNT)
+                .map(|register| read_register(chip, register))
+                .unwrap_or_else(|| "E01".to_owned()),
+            Some(b'P') => write_one_register(chip, &packet[1..]),
+            Some(b'm') => read_memory(chip, &packet[1..]),
+            Some(b'M') => write_memory(chip, &packet[1..]),
+            Some(b'c') => {
+                debugger.resume(chip);
+                self.running = true;
+                return None;
+            }
+            Some(b's') => {
+                debugger.step(chip, 1);
+                stop_reply(debugger.last_stop)
+            }
+            Some(b'Z') | Some(b'z') => set_point(debugger, packet),
+            Some(b'H') => "OK".to_owned(),
+            Some(b'D') => {
+                debugger.resume(chip);
+                "OK".to_owned()
+            }
+            Some(b'k') => {
+                debugger.resume(chip);
+                self.client = None;
+                return None;
+            }
+            _ if packet.starts_with("qSupported") => {
+                format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+            }
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                read_target_xml(&packet["qXfer:features:read:target.xml:".len()..])
+            }
+            _ if packet == "qAttached" => "1".to_owned(),
+            _ if packet == "qC" => "QC1".to_owned(),
+            _ if packet == "qfThreadInfo" => "m1".to_owned(),
+            _ if packet == "qsThreadInfo" => "l".to_owned(),
+            _ => String::new(),
+        };
+
+        Some(reply)
+    }
+}
+
+enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn stop_reply(stop: Option<Stop>) -> String {
+    match stop {
+        Some(Stop::Watchpoint(address)) => format!("T05watch:{:x};", address),
+        Some(Stop::Pause) => "S02".to_owned(),
+        Some(Stop::Fault(Fault::UndefinedInstruction(_))) => "S04".to_owned(),
+        Some(Stop::Fault(_)) => "S0b".to_owned(),
+        _ => "S05".to_owned(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn register_size(register: usize) -> usize {
+    match register {
+        REGISTER_I | REGISTER_PC => 2,
+        _ => 1,
+    }
+}
+
+/// Registers are sent as little-endian hex.
+fn read_register(chip: &Chip, register: usize) -> String {
+    match register {
+        0..=15 => hex(&[chip.registers[register]]),
+        REGISTER_I => hex(&(chip.index as u16).to_le_bytes()),
+        REGISTER_PC => hex(&chip.pc.to_le_bytes()),
+        REGISTER_SP => hex(&[chip.sp]),
+        REGISTER_DT => hex(&[chip.delay_timer]),
+        REGISTER_ST => hex(&[chip.sound_timer]),
+        _ => String::new(),
+    }
+}
+
+fn wide(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+/// Whether `bytes` can go in `register`: I must point into memory and PC
+/// at a whole instruction in it, or the next cycle would read past the end.
+fn register_fits(chip: &Chip, register: usize, bytes: &[u8]) -> bool {
+    match register {
+        REGISTER_I => (wide(bytes) as usize) < chip.memory().len(),
+        REGISTER_PC => (wide(bytes) as usize) < MEMORY_SIZE - 1,
+        _ => true,
+    }
+}
+
+fn write_register(chip: &mut Chip, register: usize, bytes: &[u8]) {
+    match register {
+        0..=15 => chip.registers[register] = bytes[0],
+        REGISTER_I => chip.index = wide(bytes) as u32,
+        REGISTER_PC => chip.pc = wide(bytes),
+        REGISTER_SP => chip.sp = bytes[0].min(chip.stack.len() as u8),
+        REGISTER_DT => chip.delay_timer = bytes[0],
+        REGISTER_ST => chip.sound_timer = bytes[0],
+        _ => {}
+    }
+}
+
+fn write_all_registers(chip: &mut Chip, data: &str) -> String {
+    let bytes = match unhex(data) {
+        Some(bytes) => bytes,
+        None => return "E01".to_owned(),
+    };
+
+    let needed: usize = (0..REGISTER_COUNT).map(register_size).sum();
+    if bytes.len() < needed {
+        return "E01".to_owned();
+    }
+
+    let mut values = Vec::new();
+    let mut offset = 0;
+    for register in 0..REGISTER_COUNT {
+        let size = register_size(register);
+        values.push((register, &bytes[offset..offset + size]));
+        offset += size;
+    }
+
+    // Check everything before changing anything.
+    if !values
+        .iter()
+        .all(|(register, bytes)| register_fits(chip, *register, bytes))
+    {
+        return "E01".to_owned();
+    }
+    for (register, bytes) in values {
+        write_register(chip, register, bytes);
+    }
+
+    "OK".to_owned()
+}
+
+fn write_one_register(chip: &mut Chip, data: &str) -> String {
+    let parsed = data.split_once('=').and_then(|(register, value)| {
+        let register = usize::from_str_radix(register, 16).ok()?;
+        let bytes = unhex(value)?;
+        (register < REGISTER_COUNT
+            && bytes.len() >= register_size(register)
+            && register_fits(chip, register, &bytes))
+        .then_some((register, bytes))
+    });
+
+    match parsed {
+        Some((register, bytes)) => {
+            write_register(chip, register, &bytes);
+            "OK".to_owned()
+        }
+        None => "E01".to_owned(),
+    }
+}
+
+/// Parses `addr,len` into a range that lies inside CHIP-8 memory.
+fn memory_range(chip: &Chip, text: &str) -> Option<(usize, usize)> {
+    let (address, len) = text.split_once(',')?;
+    let address = usize::from_str_radix(address, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+
+    address
+        .checked_add(len)
+        .filter(|end| *end <= chip.memory().len())
+        .map(|_| (address, len))
+}
+
+fn read_memory(chip: &Chip, args: &str) -> String {
+    match memory_range(chip, args) {
+        Some((address, len)) => hex(&chip.memory()[address..address + len]),
+        None => "E01".to_owned(),
+    }
+}
+
+fn write_memory(chip: &mut Chip, args: &str) -> String {
+    // `Chip::write_memory` takes 16-bit addresses, short of MEGA-CHIP's
+    // whole memory.
+    let parsed = args.split_once(':').and_then(|(range, data)| {
+        let (address, len) = memory_range(chip, range)?;
+        let address = u16::try_from(address).ok()?;
+        let bytes = unhex(data)?;
+        (bytes.len() == len).then_some((address, bytes))
+    });
+
+    match parsed {
+        Some((address, bytes)) => {
+            chip.write_memory(address, &bytes);
+            "OK".to_owned()
+        }
+        None => "E01".to_owned(),
+    }
+}
+
+/// Handles `Z`/`z` packets: types 0 and 1 are breakpoints, type 2 is a
+/// write watchpoint. Read and access watchpoints aren't supported.
+fn set_point(debugger: &mut Debugger, packet: &str) -> String {
+    let insert = packet.starts_with('Z');
+    let fields: Vec<&str> = packet[1..].split(',').collect();
+
+    let (kind, address, len) = match fields.as_slice() {
+        [kind, address, len, ..] => match (
+            u16::from_str_radix(address, 16),
+            u16::from_str_radix(len, 16),
+        ) {
+            (Ok(address), Ok(len)) => (*kind, address, len),
+            _ => return "E01".to_owned(),
+        },
+        _ => return "E01".to_owned(),
+    };
+
+    match kind {
+        "0" | "1" => {
+            if insert {
+                debugger.breakpoints.insert(address);
+            } else {
+                debugger.breakpoints.remove(&address);
+            }
+        }
+        "2" => {
+            for address in address..address.saturating_add(len.max(1)) {
+                if insert {
+                    debugger.watchpoints.insert(address);
+                } else {
+                    debugger.watchpoints.remove(&address);
+                }
+            }
+        }
+        _ => return String::new(),
+    }
+
+    "OK".to_owned()
+}
+
+/// Serves `offset,length` slices of the target description.
+fn read_target_xml(args: &str) -> String {
+    let range = args.split_once(',').and_then(|(offset, len)| {
+        Some((
+            usize::from_str_radix(offset, 16).ok()?,
+            usize::from_str_radix(len, 16).ok()?,
+        ))
+    });
+
+    match range {
+        Some((offset, _)) if offset > TARGET_XML.len() => "E00".to_owned(),
+        Some((offset, len)) => {
+            let end = offset.saturating_add(len).min(TARGET_XML.len());
+            let prefix = if end == TARGET_XML.len() { 'l' } else { 'm' };
+            format!("{}{}", prefix, &TARGET_XML[offset..end])
+        }
+        None => "E01".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::Platform;
+
+    #[test]
+    fn refuses_pc_and_i_outside_memory() {
+        let mut chip = Chip::new();
+        assert_eq!(write_one_register(&mut chip, "11=ff0f"), "E01");
+        assert_eq!(write_one_register(&mut chip, "10=0010"), "E01");
+        assert_eq!(write_one_register(&mut chip, "11=fe0f"), "OK");
+        assert_eq!(chip.pc, 0xFFE);
+
+        // A bad PC leaves the other registers of a `G` packet alone.
+        let mut registers = "01".repeat(16);
+        registers += "0000ff0f000000";
+        assert_eq!(write_all_registers(&mut chip, &registers), "E01");
+        assert_eq!(chip.registers, [0; 16]);
+    }
+
+    #[test]
+    fn refuses_writes_above_sixteen_bit_addresses() {
+        let mut chip = Chip::with_platform(Platform::MegaChip);
+        assert_eq!(write_memory(&mut chip, "10000,1:aa"), "E01");
+        assert_eq!(chip.memory()[0], 0);
+        assert_eq!(write_memory(&mut chip, "ffff,1:aa"), "OK");
+        assert_eq!(chip.memory()[0xFFFF], 0xAA);
+    }
+
+    #[test]
+    fn target_xml_ranges_clamp_and_end() {
+        assert!(read_target_xml(&format!("0,{:x}", usize::MAX)).starts_with('l'));
+        let end = TARGET_XML.len();
+        assert_eq!(read_target_xml(&format!("{:x},10", end)), "l");
+        assert_eq!(read_target_xml(&format!("{:x},10", end + 1)), "E00");
+    }
+}
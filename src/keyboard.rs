@@ -1,23 +1,206 @@
-use sdl2::keyboard::Keycode;
-
-pub fn key2btn(key: Keycode) -> Option<usize> {
-    match key {
-        Keycode::Num1 => Some(0x1),
-        Keycode::Num2 => Some(0x2),
-        Keycode::Num3 => Some(0x3),
-        Keycode::Num4 => Some(0xC),
-        Keycode::Q => Some(0x4),
-        Keycode::W => Some(0x5),
-        Keycode::E => Some(0x6),
-        Keycode::R => Some(0xD),
-        Keycode::A => Some(0x7),
-        Keycode::S => Some(0x8),
-        Keycode::D => Some(0x9),
-        Keycode::F => Some(0xE),
-        Keycode::Z => Some(0xA),
-        Keycode::X => Some(0x0),
-        Keycode::C => Some(0xB),
-        Keycode::V => Some(0xF),
-        _ => None,
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use sdl2::keyboard::{Keycode, Scancode};
+
+pub const DEFAULT_CONFIG_PATH: &str = "keys.toml";
+
+/// A host key bound to a CHIP-8 button, either by the symbol it produces
+/// (layout dependent) or by its physical position (layout independent).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Binding {
+    Keycode(Keycode),
+    Scancode(Scancode),
+}
+
+impl Binding {
+    /// Parses `key:<name>` or `scan:<name>` using SDL key names, e.g.
+    /// `key:Q`, `scan:Q` or `key:Keypad 7`. A bare name is treated as a keycode.
+    pub fn parse(text: &str) -> Result<Binding, String> {
+        let (kind, name) = match text.split_once(':') {
+            Some((kind, name)) => (kind.trim(), name.trim()),
+            None => ("key", text.trim()),
+        };
+
+        match kind {
+            "key" => Keycode::from_name(name)
+                .map(Binding::Keycode)
+                .ok_or_else(|| format!("unknown keycode '{}'", name)),
+            "scan" => Scancode::from_name(name)
+                .map(Binding::Scancode)
+                .ok_or_else(|| format!("unknown scancode '{}'", name)),
+            _ => Err(format!("unknown binding kind '{}' in '{}'", kind, text)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    Qwerty,
+    Azerty,
+    Numpad,
+}
+
+impl Preset {
+    pub fn from_name(name: &str) -> Option<Preset> {
+        match name.to_ascii_lowercase().as_str() {
+            "qwerty" => Some(Preset::Qwerty),
+            "azerty" => Some(Preset::Azerty),
+            "numpad" => Some(Preset::Numpad),
+            _ => None,
+        }
+    }
+
+    /// Host keys for the CHIP-8 keypad, listed in keypad order:
+    ///
+    /// ```text
+    /// 1 2 3 C
+    /// 4 5 6 D
+    /// 7 8 9 E
+    /// A 0 B F
+    /// ```
+    ///
+    /// AZERTY is bound by position, since its number row (`& é " ' (`)
+    /// holds symbols SDL has no keycode for.
+    #[rustfmt::skip]
+    fn keys(self) -> [Binding; 16] {
+        match self {
+            Preset::Qwerty => [
+                Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4,
+                Keycode::Q, Keycode::W, Keycode::E, Keycode::R,
+                Keycode::A, Keycode::S, Keycode::D, Keycode::F,
+                Keycode::Z, Keycode::X, Keycode::C, Keycode::V,
+            ].map(Binding::Keycode),
+            Preset::Azerty => [
+                Scancode::Num1, Scancode::Num2, Scancode::Num3, Scancode::Num4,
+                Scancode::Q, Scancode::W, Scancode::E, Scancode::R,
+                Scancode::A, Scancode::S, Scancode::D, Scancode::F,
+                Scancode::Z, Scancode::X, Scancode::C, Scancode::V,
+            ].map(Binding::Scancode),
+            Preset::Numpad => [
+                Keycode::Kp7, Keycode::Kp8, Keycode::Kp9, Keycode::KpDivide,
+                Keycode::Kp4, Keycode::Kp5, Keycode::Kp6, Keycode::KpMultiply,
+                Keycode::Kp1, Keycode::Kp2, Keycode::Kp3, Keycode::KpMinus,
+                Keycode::KpEnter, Keycode::Kp0, Keycode::KpPeriod, Keycode::KpPlus,
+            ].map(Binding::Keycode),
+        }
+    }
+}
+
+const KEYPAD_LAYOUT: [usize; 16] = [
+    0x1, 0x2, 0x3, 0xC, //
+    0x4, 0x5, 0x6, 0xD, //
+    0x7, 0x8, 0x9, 0xE, //
+    0xA, 0x0, 0xB, 0xF, //
+];
+
+/// On-disk layout of the key config file:
+///
+/// ```toml
+/// preset = "azerty"
+///
+/// [bindings]
+/// "1" = ["scan:1", "key:Keypad 7"]
+/// "F" = ["key:V"]
+/// ```
+///
+/// Buttons listed under `bindings` replace the preset's keys for that button.
+/// Buttons `10`-`1F` are keys 0-F of the CHIP-8X's second keypad, which no
+/// preset binds.
+#[derive(Debug, Deserialize)]
+struct KeyConfig {
+    preset: Option<String>,
+    #[serde(default)]
+    bindings: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyMap {
+    bindings: HashMap<Binding, usize>,
+}
+
+impl KeyMap {
+    pub fn preset(preset: Preset) -> KeyMap {
+        let bindings = preset
+            .keys()
+            .iter()
+            .zip(KEYPAD_LAYOUT.iter())
+            .map(|(key, btn)| (*key, *btn))
+            .collect();
+
+        KeyMap { bindings }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<KeyMap, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        KeyMap::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<KeyMap, Box<dyn Error>> {
+        let config: KeyConfig = toml::from_str(contents)?;
+
+        let preset = match config.preset {
+            Some(name) => {
+                Preset::from_name(&name).ok_or_else(|| format!("unknown preset '{}'", name))?
+            }
+            None => Preset::Qwerty,
+        };
+        let mut keymap = KeyMap::preset(preset);
+
+        for (button, keys) in config.bindings {
+            let btn = usize::from_str_radix(button.trim_start_matches("0x"), 16)
+                .ok()
+                .filter(|btn| *btn < 32)
+                .ok_or_else(|| format!("invalid CHIP-8 button '{}'", button))?;
+
+            keymap.bindings.retain(|_, bound| *bound != btn);
+            for key in keys {
+                keymap.bindings.insert(Binding::parse(&key)?, btn);
+            }
+        }
+
+        Ok(keymap)
+    }
+
+    /// Loads the config at `path` if there is one, otherwise the QWERTY preset.
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Result<KeyMap, Box<dyn Error>> {
+        if path.as_ref().exists() {
+            KeyMap::load(path)
+        } else {
+            Ok(KeyMap::preset(Preset::Qwerty))
+        }
+    }
+
+    /// Binds the arrow keys, space and return to the buttons a ROM database
+    /// entry suggests for `up`/`down`/`left`/`right`/`a`/`b`, leaving
+    /// existing bindings in place.
+    pub fn add_hints(&mut self, hints: &HashMap<String, u8>) {
+        let hint_keys = [
+            ("up", Keycode::Up),
+            ("down", Keycode::Down),
+            ("left", Keycode::Left),
+            ("right", Keycode::Right),
+            ("a", Keycode::Space),
+            ("b", Keycode::Return),
+        ];
+
+        for (hint, key) in hint_keys {
+            if let Some(btn) = hints.get(hint).filter(|btn| **btn < 16) {
+                self.bindings
+                    .entry(Binding::Keycode(key))
+                    .or_insert(*btn as usize);
+            }
+        }
+    }
+
+    /// Looks up a key event, preferring a physical scancode binding.
+    pub fn key2btn(&self, keycode: Option<Keycode>, scancode: Option<Scancode>) -> Option<usize> {
+        scancode
+            .and_then(|scancode| self.bindings.get(&Binding::Scancode(scancode)))
+            .or_else(|| keycode.and_then(|keycode| self.bindings.get(&Binding::Keycode(keycode))))
+            .copied()
     }
 }
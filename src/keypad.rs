@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// A change to one of the 16 keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyEvent {
+    Press(u8),
+    Release(u8),
+}
+
+/// The hex keypad, driven by press and release events.
+///
+/// Events can be queued with how far through the coming frame they should
+/// land, and the machine applies them between instructions as the frame
+/// reaches that point. A tap shorter than a frame then still reaches the
+/// program, and input isn't held back to the next frame boundary.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Keypad {
+    down: [bool; 16],
+    /// Events not applied yet, oldest first, with their place in the frame
+    /// from 0 to 1.
+    pending: VecDeque<(f32, KeyEvent)>,
+    /// Keys that went down and came up since the frame began, a bit each.
+    pressed: u16,
+    released: u16,
+    /// Whether `FX0A` is waiting, and the first key let go since it began.
+    waiting: bool,
+    released_while_waiting: Option<u8>,
+}
+
+impl Keypad {
+    pub fn is_down(&self, key: u8) -> bool {
+        self.down[key as usize & 0xF]
+    }
+
+    /// Whether `key` went down since the frame began.
+    pub fn was_pressed(&self, key: u8) -> bool {
+        self.pressed & 1 << (key & 0xF) != 0
+    }
+
+    /// Whether `key` came up since the frame began.
+    pub fn was_released(&self, key: u8) -> bool {
+        self.released & 1 << (key & 0xF) != 0
+    }
+
+    /// The keys held down, in key order.
+    pub fn held(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..16).filter(|key| self.is_down(*key))
+    }
+
+    /// Applies `event` now, ahead of anything queued.
+    pub fn apply(&mut self, event: KeyEvent) {
+        match event {
+            KeyEvent::Press(key) => {
+                let key = key & 0xF;
+                self.down[key as usize] = true;
+                self.pressed |= 1 << key;
+            }
+            KeyEvent::Release(key) => {
+                let key = key & 0xF;
+                self.down[key as usize] = false;
+                self.released |= 1 << key;
+                if self.waiting {
+                    self.released_while_waiting.get_or_insert(key);
+                }
+            }
+        }
+    }
+
+    /// Presses the keys whose bits are set in `keys` and releases the
+    /// others, now, as if each had been pressed or let go.
+    pub fn hold(&mut self, keys: u16) {
+        for key in 0..16 {
+            let down = keys & 1 << key != 0;
+            if self.is_down(key) != down {
+                self.apply(if down {
+                    KeyEvent::Press(key)
+                } else {
+                    KeyEvent::Release(key)
+                });
+            }
+        }
+    }
+
+    /// Queues `event` to be applied once the frame is `at` (0 to 1) of the
+    /// way through.
+    pub fn queue(&mut self, at: f32, event: KeyEvent) {
+        self.pending.push_back((at.clamp(0.0, 1.0), event));
+    }
+
+    /// Applies the queued events due by `progress`, in the order they came.
+    pub fn apply_until(&mut self, progress: f32) {
+        while let Some((_, event)) = self.pending.front().filter(|(at, _)| *at <= progress) {
+            let event = *event;
+            self.pending.pop_front();
+            self.apply(event);
+        }
+    }
+
+    /// Forgets the previous frame's edges.
+    pub fn begin_frame(&mut self) {
+        self.pressed = 0;
+        self.released = 0;
+    }
+
+    /// Called each time `FX0A` runs. Like the VIP interpreter, it waits for
+    /// a key to be let go, not just held, and returns that key once one is.
+    pub fn wait_for_release(&mut self) -> Option<u8> {
+        if !self.waiting {
+            self.waiting = true;
+            self.released_while_waiting = None;
+        }
+
+        let key = self.released_while_waiting.take();
+        if key.is_some() {
+            self.waiting = false;
+        }
+        key
+    }
+}
@@ -0,0 +1,127 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::romdb::RomDatabase;
+
+pub const DEFAULT_ROM_DIR: &str = "roms";
+pub const RECENT_ROMS_PATH: &str = "recent_roms.txt";
+const MAX_RECENT: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub title: String,
+    pub recent: bool,
+}
+
+/// State of the in-window ROM browser: recently played ROMs followed by
+/// every file found under the ROM directory.
+#[derive(Debug, Default)]
+pub struct Launcher {
+    pub entries: Vec<Entry>,
+    pub selected: usize,
+    pub open: bool,
+    recent: Vec<PathBuf>,
+}
+
+impl Launcher {
+    pub fn new<P: AsRef<Path>>(rom_dir: P, database: &RomDatabase) -> Launcher {
+        let mut launcher = Launcher {
+            recent: load_recent(),
+            ..Launcher::default()
+        };
+        launcher.scan(rom_dir.as_ref(), database);
+        launcher
+    }
+
+    pub fn scan(&mut self, rom_dir: &Path, database: &RomDatabase) {
+        self.entries.clear();
+
+        for path in self.recent.iter().filter(|path| path.is_file()) {
+            self.entries.push(Entry {
+                path: path.clone(),
+                title: entry_title(path, database),
+                recent: true,
+            });
+        }
+
+        let mut roms = Vec::new();
+        collect_roms(rom_dir, &mut roms);
+        roms.sort();
+
+        for path in roms {
+            self.entries.push(Entry {
+                title: entry_title(&path, database),
+                path,
+                recent: false,
+            });
+        }
+
+        self.selected = self.selected.min(self.entries.len().saturating_sub(1));
+    }
+
+    pub fn select_previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.entries.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn selected(&self) -> Option<&Entry> {
+        self.entries.get(self.selected)
+    }
+
+    /// Moves `path` to the top of the recent list and saves the list.
+    pub fn add_recent(&mut self, path: &Path) -> io::Result<()> {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+
+        self.recent.retain(|recent| *recent != path);
+        self.recent.insert(0, path);
+        self.recent.truncate(MAX_RECENT);
+
+        let contents: Vec<String> = self
+            .recent
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        fs::write(RECENT_ROMS_PATH, contents.join("\n"))
+    }
+}
+
+fn load_recent() -> Vec<PathBuf> {
+    fs::read_to_string(RECENT_ROMS_PATH)
+        .map(|contents| contents.lines().map(PathBuf::from).collect())
+        .unwrap_or_default()
+}
+
+fn collect_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_roms(&path, roms);
+        } else {
+            roms.push(path);
+        }
+    }
+}
+
+/// Uses the ROM database title when the ROM is known, the file name otherwise.
+fn entry_title(path: &Path, database: &RomDatabase) -> String {
+    fs::read(path)
+        .ok()
+        .and_then(|rom| database.title(&rom).map(str::to_owned))
+        .unwrap_or_else(|| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        })
+}
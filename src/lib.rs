@@ -0,0 +1,17 @@
+//! The CHIP-8 machine and the tools built around it, without a frontend, so
+//! other crates such as the Python bindings can drive it.
+
+pub mod cheats;
+pub mod chip;
+pub mod coverage;
+pub mod debugger;
+pub mod environment;
+pub mod keypad;
+pub mod megachip;
+pub mod profiler;
+pub mod recompiler;
+pub mod romdb;
+pub mod savestate;
+pub mod symbols;
+pub mod timing;
+pub mod vip;
@@ -1,30 +1,806 @@
-mod chip;
-mod debugger;
+mod automation;
+mod dap;
+mod gdbstub;
 mod keyboard;
+mod launcher;
+mod memview;
+mod overlay;
+mod recorder;
+mod roms;
+mod screenshot;
+mod script;
 mod sdl_driver;
+mod text;
 
+use rust_chip8::{
+    cheats, chip, coverage, debugger, keypad, megachip, profiler, recompiler, romdb, symbols,
+    timing, vip,
+};
+
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
 use std::error::Error;
-use std::time::Duration;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chip::Chip;
+use romdb::{RomDatabase, RomSettings};
+use script::Script;
+use sdl_driver::UiEvent;
+use symbols::SymbolTable;
+
+const FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / 60);
+/// Size of a CHIP-8 pixel in the window, before shrinking to fit.
+const DISPLAY_SCALE: u32 = 15;
+
+struct Args {
+    rom: Option<String>,
+    rom_dir: String,
+    load_address: Option<u16>,
+    platform: Option<chip::Platform>,
+    symbols: Option<String>,
+    profile: Option<String>,
+    coverage: Option<String>,
+    disassemble: bool,
+    gdb_port: Option<u16>,
+    dap_port: Option<u16>,
+    script: Option<String>,
+    headless: bool,
+    frames: Option<u64>,
+    trace: bool,
+    benchmark: Option<u64>,
+    recompile: bool,
+    vip_timing: bool,
+    vip: Option<String>,
+    vip_monitor: Option<String>,
+    record: Option<String>,
+    record_audio: Option<String>,
+    record_scale: u32,
+    screenshot: bool,
+    automation_port: Option<u16>,
+}
+
+/// Usage: `rust-chip8 [OPTIONS] [ROM]`, where ROM is a path, `-` for stdin
+/// or `builtin:<name>` for a ROM embedded in the binary. Without a ROM the
+/// emulator opens the ROM browser.
+///
+/// - `--rom-dir DIR`: directory listed by the ROM browser.
+/// - `--load-address ADDR`: load and start the ROM at ADDR instead of the
+///   platform's usual address.
+/// - `--platform ID`: run as `originalChip8`, `modernChip8`, `chip48`,
+///   `hiresChip8`, `chip8x`, `eti660` or `megachip8` whatever the ROM
+///   database says.
+/// - `--symbols FILE`: label file; defaults to a `.sym` file next to the ROM.
+/// - `--profile STEM`: profile the last ROM run, writing `STEM.txt` and
+///   `STEM.folded` on exit.
+/// - `--coverage FILE`: record executed, read and written bytes and save
+///   the map on exit.
+/// - `--disassemble`: print a listing of ROM and exit, telling code from
+///   data with the `--coverage` map if it already exists.
+/// - `--gdb PORT`: serve the GDB remote protocol on localhost.
+/// - `--dap PORT`: serve the Debug Adapter Protocol on localhost.
+/// - `--automation PORT`: serve the JSON-RPC control API on localhost, see
+///   `AutomationServer`.
+/// - `--script FILE`: run a Rhai script against the ROM, see `Script`.
+/// - `--headless`: run the ROM without a window or frame limiting, until
+///   `--frames` have run or the `--script` quits. One of the two is needed.
+/// - `--frames N`: stop after N frames.
+/// - `--recompile`: run `--headless` through the basic-block recompiler.
+/// - `--vip-timing`: run as many instructions per frame as a COSMAC VIP
+///   would instead of a fixed number.
+/// - `--vip FILE`: emulate a whole COSMAC VIP running the CHIP-8
+///   interpreter image in FILE, in place of the built-in instruction set.
+/// - `--vip-monitor FILE`: boot the `--vip` machine through its monitor ROM.
+/// - `--record FILE`: record the display to FILE, an animated `.gif` or a
+///   raw `.y4m` video, until the emulator exits. F11 starts and stops a
+///   GIF and WAV recording named after the ROM at the display scale
+///   instead.
+/// - `--record-audio FILE`: record the sound alongside as a WAV file.
+/// - `--record-scale N`: record the display N times its size, 1 by default.
+/// - `--screenshot`: save the last frame as PNG screenshots named after the
+///   ROM, at native resolution and at the display scale, when the emulator
+///   exits. F12 and the console's `screenshot` take them while it runs.
+/// - `--trace`: print every instruction as it runs.
+/// - `--benchmark N`: time N frames as the old interpreter ran them, with
+///   and without the decoded instruction cache and recompiled, and exit.
+impl Default for Args {
+    fn default() -> Args {
+        Args {
+            rom: None,
+            rom_dir: launcher::DEFAULT_ROM_DIR.to_owned(),
+            load_address: None,
+            platform: None,
+            symbols: None,
+            profile: None,
+            coverage: None,
+            disassemble: false,
+            gdb_port: None,
+            dap_port: None,
+            script: None,
+            headless: false,
+            frames: None,
+            trace: false,
+            benchmark: None,
+            recompile: false,
+            vip_timing: false,
+            vip: None,
+            vip_monitor: None,
+            record: None,
+            record_audio: None,
+            record_scale: 1,
+            screenshot: false,
+            automation_port: None,
+        }
+    }
+}
+
+fn parse_args() -> Result<Args, Box<dyn Error>> {
+    let mut args = Args::default();
+
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--rom-dir" => {
+                args.rom_dir = argv.next().ok_or("--rom-dir needs a value")?;
+            }
+            "--load-address" => {
+                let value = argv.next().ok_or("--load-address needs a value")?;
+                let address = u16::from_str_radix(value.trim_start_matches("0x"), 16)?;
+                args.load_address = Some(address);
+            }
+            "--platform" => {
+                let value = argv.next().ok_or("--platform needs a value")?;
+                let platform = chip::Platform::from_id(&value)
+                    .ok_or_else(|| format!("unknown platform '{}'", value))?;
+                args.platform = Some(platform);
+            }
+            "--profile" => {
+                args.profile = Some(argv.next().ok_or("--profile needs a value")?);
+            }
+            "--coverage" => {
+                args.coverage = Some(argv.next().ok_or("--coverage needs a value")?);
+            }
+            "--disassemble" => args.disassemble = true,
+            "--gdb" => {
+                let value = argv.next().ok_or("--gdb needs a port")?;
+                args.gdb_port = Some(value.parse()?);
+            }
+            "--automation" => {
+                let value = argv.next().ok_or("--automation needs a port")?;
+                args.automation_port = Some(value.parse()?);
+            }
+            "--dap" => {
+                let value = argv.next().ok_or("--dap needs a port")?;
+                args.dap_port = Some(value.parse()?);
+            }
+            "--script" => {
+                args.script = Some(argv.next().ok_or("--script needs a value")?);
+            }
+            "--headless" => args.headless = true,
+            "--frames" => {
+                let value = argv.next().ok_or("--frames needs a value")?;
+                args.frames = Some(value.parse()?);
+            }
+            "--trace" => args.trace = true,
+            "--recompile" => args.recompile = true,
+            "--vip-timing" => args.vip_timing = true,
+            "--vip" => {
+                args.vip = Some(argv.next().ok_or("--vip needs an interpreter image")?);
+            }
+            "--vip-monitor" => {
+                args.vip_monitor = Some(argv.next().ok_or("--vip-monitor needs a value")?);
+            }
+            "--record" => {
+                args.record = Some(argv.next().ok_or("--record needs a value")?);
+            }
+            "--record-audio" => {
+                args.record_audio = Some(argv.next().ok_or("--record-audio needs a value")?);
+            }
+            "--screenshot" => args.screenshot = true,
+            "--record-scale" => {
+                let value = argv.next().ok_or("--record-scale needs a value")?;
+                args.record_scale = value.parse()?;
+                if args.record_scale == 0 {
+                    return Err("--record-scale must be at least 1".into());
+                }
+            }
+            "--benchmark" => {
+                let value = argv.next().ok_or("--benchmark needs a frame count")?;
+                args.benchmark = Some(value.parse()?);
+            }
+            "--symbols" => {
+                args.symbols = Some(argv.next().ok_or("--symbols needs a value")?);
+            }
+            _ => args.rom = Some(arg),
+        }
+    }
+
+    Ok(args)
+}
+
+/// Reads the ROM named on the command line: a path, `-` for stdin or
+/// `builtin:<name>`.
+fn read_rom(rom: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if rom == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().lock().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    } else if let Some(name) = rom.strip_prefix("builtin:") {
+        let bytes = roms::embedded(name).ok_or(format!("no built-in ROM named '{}'", name))?;
+        Ok(bytes.to_vec())
+    } else {
+        Ok(chip::read_rom(rom)?)
+    }
+}
+
+/// Builds a fresh machine running `rom`, configured from the ROM database.
+fn start(
+    rom: &str,
+    args: &Args,
+    database: &RomDatabase,
+) -> Result<(Chip, RomSettings), Box<dyn Error>> {
+    // Look the ROM up before loading it, as its platform decides how much
+    // fits and where it goes.
+    let rom_bytes = read_rom(rom)?;
+    let mut settings = database.lookup(&rom_bytes);
+    if let Some(platform) = args.platform {
+        settings.platform = platform;
+        settings.quirks = platform.quirks();
+    }
+    if args.vip.is_some() {
+        settings.platform = chip::Platform::OriginalChip8;
+        settings.quirks = settings.platform.quirks();
+    }
+    if let Some(title) = &settings.title {
+        println!("{} ({})", title, settings.platform.id());
+    }
+
+    let mut chip = Chip::with_platform(settings.platform);
+    if let Some(address) = args.load_address {
+        chip.load_address = address;
+    }
+    chip.load_bytes(&rom_bytes)?;
+    chip.quirks = settings.quirks;
+
+    if let Some(interpreter) = &args.vip {
+        let interpreter =
+            fs::read(interpreter).map_err(|err| format!("{}: {}", interpreter, err))?;
+        let monitor = match &args.vip_monitor {
+            Some(path) => Some(fs::read(path).map_err(|err| format!("{}: {}", path, err))?),
+            None => None,
+        };
+        vip::Vip::install(&mut chip, &interpreter, monitor)?;
+    }
+
+    let symbol_path = match &args.symbols {
+        Some(path) => Some(Path::new(path).to_path_buf()),
+        None => Some(Path::new(rom).with_extension("sym")).filter(|path| path.is_file()),
+    };
+    if let Some(path) = symbol_path {
+        let symbols =
+            SymbolTable::load(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        chip.symbols = Arc::new(symbols);
+    }
+
+    chip.trace = args.trace;
+    if args.vip_timing {
+        chip.timing = timing::Timing::Vip;
+    }
+    if args.profile.is_some() {
+        chip.profiler = Some(Box::new(profiler::Profiler::new(chip.pc)));
+    }
+    if args.coverage.is_some() {
+        chip.coverage = Some(Box::new(coverage::Coverage::new()));
+    }
+
+    Ok((chip, settings))
+}
+
+/// Loads the saved cheats for the running ROM, carrying on without them if
+/// the cheats file can't be read.
+fn load_cheats(chip: &Chip) -> cheats::Cheats {
+    let path = cheats::DEFAULT_CHEATS_PATH;
+    cheats::Cheats::load(path, chip.rom()).unwrap_or_else(|err| {
+        eprintln!("Could not load cheats: {}", err);
+        cheats::Cheats::new(path, chip.rom())
+    })
+}
+
+/// Runs a frame, then the script's frame hooks unless the debugger was
+/// paused.
+fn run_frame(
+    chip: &mut Chip,
+    debugger: &mut debugger::Debugger,
+    instructions: u32,
+    script: &mut Option<Script>,
+) -> Result<(), Box<dyn Error>> {
+    let paused = debugger.paused;
+
+    match script {
+        Some(script) => {
+            debugger.run_frame(chip, instructions, |chip| script.on_instruction(chip));
+            if !paused {
+                script.end_frame(chip)?;
+            }
+        }
+        None => debugger.run_frame(chip, instructions, |_| {}),
+    }
+
+    Ok(())
+}
+
+/// Runs `--headless` until the script quits, `--frames` frames have run or
+/// the program faults, returning the script's exit status.
+fn run_headless(
+    args: &Args,
+    database: &RomDatabase,
+    mut script: Option<Script>,
+) -> Result<Option<i32>, Box<dyn Error>> {
+    let rom = args.rom.as_deref().ok_or("--headless needs a ROM")?;
+    if script.is_none() && args.frames.is_none() {
+        return Err("--headless needs --frames or --script to know when to stop".into());
+    }
+    let (mut chip, settings) = start(rom, args, database)?;
+    let mut debugger = debugger::Debugger::new();
+    debugger.cheats = load_cheats(&chip);
+    if let Some(script) = &mut script {
+        script.start(&mut chip)?;
+    }
+    let mut recompiler = match (args.recompile, &script) {
+        (true, Some(_)) => return Err("--recompile can't run scripts".into()),
+        (true, None) => Some(recompiler::Recompiler::new()),
+        (false, _) => None,
+    };
+
+    let mut recorder = match &args.record {
+        Some(path) => Some(start_recording(
+            Path::new(path),
+            args.record_audio.as_deref().map(Path::new),
+            &chip,
+            args.record_scale,
+        )?),
+        None => None,
+    };
+    let palette = settings.palette.unwrap_or(screenshot::DEFAULT_COLORS);
+
+    let mut frames = 0;
+    let mut exit_code = None;
+    while exit_code.is_none() && args.frames.is_none_or(|limit| frames < limit) {
+        match &mut recompiler {
+            Some(recompiler) => {
+                recompiler.run_frame(&mut chip, settings.instructions_per_frame);
+                debugger.cheats.apply(&mut chip);
+            }
+            None => run_frame(
+                &mut chip,
+                &mut debugger,
+                settings.instructions_per_frame,
+                &mut script,
+            )?,
+        }
+        if let Some(recorder) = &mut recorder {
+            let samples = chip.audio_frame();
+            recorder.record(&screenshot::Image::capture(&chip, palette), &samples)?;
+        }
+        exit_code = script.as_ref().and_then(Script::exit_code);
+        frames += 1;
+        if chip.fault.is_some() {
+            break;
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+    if args.screenshot {
+        let scale = sdl_driver::window_scale(&chip, DISPLAY_SCALE);
+        save_screenshots(&screenshot::Image::capture(&chip, palette), rom, scale);
+    }
+    save_results(args, &chip)?;
+    if let Some(fault) = chip.fault {
+        return Err(format!("halted at {:03X}: {}", chip.pc, fault).into());
+    }
+    Ok(exit_code)
+}
+
+/// Times `frames` frames of the ROM the way the interpreter used to run
+/// them, with the decoded instruction cache off, with it on, and through the
+/// recompiler.
+///
+/// The old interpreter decoded every instruction and formatted a trace line
+/// for it into a fresh `String`. "legacy" repeats that work, short of
+/// printing the line, so it is the baseline the others are measured against.
+fn benchmark(args: &Args, database: &RomDatabase, frames: u64) -> Result<(), Box<dyn Error>> {
+    let rom = args.rom.as_deref().ok_or("--benchmark needs a ROM")?;
+    let mut baseline = None;
+
+    for backend in ["legacy", "decode always", "cached", "recompiled"] {
+        let (mut chip, settings) = start(rom, args, database)?;
+        chip.cache_decoding = !matches!(backend, "legacy" | "decode always");
+        let mut recompiler = recompiler::Recompiler::new();
+
+        let started = Instant::now();
+        for _ in 0..frames {
+            match backend {
+                "legacy" => {
+                    chip.run_frame_until(settings.instructions_per_frame, |chip| {
+                        let opcode = chip.fetch(chip.pc as usize);
+                        let mut line = String::new();
+                        if let Some(label) = chip.symbols.label(chip.pc) {
+                            line = format!("{}:\n", label);
+                        }
+                        line += &format!(
+                            "0{:X}: {}",
+                            chip.pc,
+                            debugger::disassemble(opcode, &chip.symbols)
+                        );
+                        std::hint::black_box(line);
+                        false
+                    });
+                }
+                "recompiled" => recompiler.run_frame(&mut chip, settings.instructions_per_frame),
+                _ => {
+                    chip.run_frame_until(settings.instructions_per_frame, |_| false);
+                }
+            }
+        }
+        let seconds = started.elapsed().as_secs_f64().max(f64::EPSILON);
+        let baseline = *baseline.get_or_insert(seconds);
+
+        println!(
+            "{:<14} {:>10.1} ms {:>12.0} frames/s {:>6.2}x",
+            backend,
+            seconds * 1000.0,
+            frames as f64 / seconds,
+            baseline / seconds
+        );
+    }
+
+    Ok(())
+}
+
+/// Starts recording `chip`'s display `scale` times its size to `path`, and
+/// the sound to `audio` if given. MEGA-CHIP machines are recorded at the
+/// size of mega mode throughout.
+fn start_recording(
+    path: &Path,
+    audio: Option<&Path>,
+    chip: &Chip,
+    scale: u32,
+) -> Result<recorder::Recorder, Box<dyn Error>> {
+    let (width, height) = match chip.mega {
+        Some(_) => (megachip::SCREEN_WIDTH, megachip::SCREEN_HEIGHT),
+        None => (chip.video_width, chip.video_height),
+    };
+    let scale = scale as usize;
+
+    recorder::Recorder::start(path, audio, width * scale, height * scale)
+}
+
+fn finish_recording(recorder: recorder::Recorder) {
+    match recorder.finish() {
+        Ok(()) => println!("Recording saved"),
+        Err(err) => eprintln!("Could not finish recording: {}", err),
+    }
+}
+
+/// Saves `image` as PNG at native resolution and blown up `scale` times,
+/// named after `rom`, and reports where.
+fn save_screenshots(image: &screenshot::Image, rom: &str, scale: u32) {
+    let stem = screenshot::auto_stem(rom);
+    let native = format!("{}.png", stem);
+    let scaled = format!("{}-x{}.png", stem, scale);
+    let result = image
+        .save_png(&native)
+        .and_then(|()| image.scaled(scale as usize).save_png(&scaled));
+
+    match result {
+        Ok(()) => println!("Screenshots saved to {} and {}", native, scaled),
+        Err(err) => eprintln!("Could not save screenshot: {}", err),
+    }
+}
+
+/// Writes the coverage map and profile asked for on the command line.
+fn save_results(args: &Args, chip: &Chip) -> Result<(), Box<dyn Error>> {
+    if let (Some(path), Some(coverage)) = (&args.coverage, &chip.coverage) {
+        let rom_start = chip.load_address as usize;
+        coverage.save(path, rom_start, rom_start + chip.rom().len())?;
+        println!("Coverage written to {}", path);
+    }
+
+    if let (Some(stem), Some(profiler)) = (&args.profile, &chip.profiler) {
+        profiler.save(stem, &chip.symbols)?;
+        println!("Profile written to {}.txt and {}.folded", stem, stem);
+    }
+
+    Ok(())
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut sdl_driver = sdl_driver::SdlDriver::new()?;
+    let args = parse_args()?;
 
-    let mut chip = chip::Chip::new();
-    chip.load_rom("roms/games/cave.ch8");
+    let database = RomDatabase::load()?;
+
+    if args.disassemble {
+        let rom = args.rom.as_deref().ok_or("--disassemble needs a ROM")?;
+        let (chip, _) = start(rom, &args, &database)?;
+        let coverage = match &args.coverage {
+            Some(path) if Path::new(path).is_file() => Some(coverage::Coverage::load(path)?),
+            _ => None,
+        };
+        println!("{}", debugger::disassemble_rom(&chip, coverage.as_ref()));
+        return Ok(());
+    }
+
+    let mut script = match &args.script {
+        Some(path) => Some(Script::load(path).map_err(|err| format!("{}: {}", path, err))?),
+        None => None,
+    };
+
+    if let Some(frames) = args.benchmark {
+        return benchmark(&args, &database, frames);
+    }
+
+    if args.headless {
+        if let Some(code) = run_headless(&args, &database, script)? {
+            std::process::exit(code);
+        }
+        return Ok(());
+    }
+
+    let base_keymap = keyboard::KeyMap::load_or_default(keyboard::DEFAULT_CONFIG_PATH)?;
+    let mut sdl_driver = sdl_driver::SdlDriver::new(base_keymap.clone())?;
+    let mut launcher = launcher::Launcher::new(&args.rom_dir, &database);
+    let console = debugger::Console::spawn();
+    let mut debugger = debugger::Debugger::new();
+    let mut gdb_server = match args.gdb_port {
+        Some(port) => Some(gdbstub::GdbServer::bind(port)?),
+        None => None,
+    };
+    let mut dap_server = match args.dap_port {
+        Some(port) => Some(dap::DapServer::bind(port)?),
+        None => None,
+    };
+    let mut dap_launch: Option<Option<String>> = None;
+    let mut automation = match args.automation_port {
+        Some(port) => Some(automation::AutomationServer::bind(port)?),
+        None => None,
+    };
+    let mut automation_load = false;
+
+    let mut chip = Chip::new();
+    let mut settings = RomSettings::default();
+    let mut loaded = false;
+    let mut rom_name = String::new();
+    let mut recorder = None;
+    let mut record_on_load = args.record.as_deref();
+    let mut exit_code = None;
+    let mut pending = args.rom.clone();
+    launcher.open = pending.is_none();
 
     loop {
-        let quit = sdl_driver.process_input(&mut chip.keypad);
+        let frame_start = Instant::now();
+
+        if let Some(rom) = pending.take() {
+            let result = match start(&rom, &args, &database) {
+                Ok((new_chip, new_settings)) => {
+                    chip = new_chip;
+                    settings = new_settings;
+                    loaded = true;
+                    rom_name = rom.clone();
+                    if let Some(path) = record_on_load.take() {
+                        recorder = Some(start_recording(
+                            Path::new(path),
+                            args.record_audio.as_deref().map(Path::new),
+                            &chip,
+                            args.record_scale,
+                        )?);
+                    }
+                    debugger.paused = false;
+                    debugger.cheats = load_cheats(&chip);
+                    launcher.open = false;
+                    if let Some(script) = &mut script {
+                        script.start(&mut chip)?;
+                    }
+
+                    let mut keymap = base_keymap.clone();
+                    keymap.add_hints(&settings.keys);
+                    sdl_driver.keymap = keymap;
+                    sdl_driver.palette = match settings.palette {
+                        Some([background, foreground]) => {
+                            [Color::from(background), Color::from(foreground)]
+                        }
+                        None => sdl_driver::DEFAULT_PALETTE,
+                    };
+
+                    if Path::new(&rom).is_file() {
+                        if let Err(err) = launcher.add_recent(Path::new(&rom)) {
+                            eprintln!("Could not save recent ROMs: {}", err);
+                        }
+                    }
+                    Ok(())
+                }
+                Err(err) if loaded || launcher.open || dap_launch.is_some() || automation_load => {
+                    eprintln!("{}: {}", rom, err);
+                    Err(err.to_string())
+                }
+                Err(err) => return Err(err),
+            };
 
-        chip.cycle();
+            if let Some(automation) = &mut automation {
+                if automation_load {
+                    automation.finish_load(result.clone(), &chip, &mut debugger);
+                }
+            }
+            automation_load = false;
 
-        sdl_driver.render(&mut chip, 15);
+            if let (Some(dap_server), Some(line_map)) = (&mut dap_server, dap_launch.take()) {
+                let line_map = line_map
+                    .map(symbols::LineMap::load)
+                    .transpose()
+                    .map_err(|err| err.to_string());
+                match result.and(line_map) {
+                    Ok(line_map) => dap_server.finish_launch(Ok(()), line_map, &mut debugger),
+                    Err(err) => dap_server.finish_launch(Err(err), None, &mut debugger),
+                }
+            }
+        }
+
+        let mut quit = false;
+        for event in sdl_driver.process_input(&mut chip.keypad, &mut chip.extra_keypad) {
+            match event {
+                UiEvent::Quit => quit = true,
+                UiEvent::DropFile(path) => pending = Some(path.display().to_string()),
+                UiEvent::KeyDown(Keycode::F1) if loaded => {
+                    launcher.open = !launcher.open;
+                    if launcher.open {
+                        launcher.scan(Path::new(&args.rom_dir), &database);
+                    }
+                }
+                UiEvent::KeyDown(Keycode::F2) => {
+                    sdl_driver.debug_overlay = !sdl_driver.debug_overlay
+                }
+                UiEvent::KeyDown(Keycode::F3) => {
+                    sdl_driver.memory_viewer.open = !sdl_driver.memory_viewer.open
+                }
+                UiEvent::KeyDown(Keycode::F11) if loaded => match recorder.take() {
+                    Some(recorder) => finish_recording(recorder),
+                    None => {
+                        let stem = screenshot::auto_stem(&rom_name);
+                        let video = PathBuf::from(format!("{}.gif", stem));
+                        let audio = PathBuf::from(format!("{}.wav", stem));
+                        let scale = sdl_driver.display_scale(&chip, DISPLAY_SCALE);
+                        match start_recording(&video, Some(&audio), &chip, scale) {
+                            Ok(started) => {
+                                println!("Recording to {}", video.display());
+                                recorder = Some(started);
+                            }
+                            Err(err) => eprintln!("Could not start recording: {}", err),
+                        }
+                    }
+                },
+                UiEvent::KeyDown(Keycode::F12) if loaded => debugger.screenshot = Some(None),
+                UiEvent::KeyDown(key) if sdl_driver.memory_viewer.open && !launcher.open => {
+                    sdl_driver.memory_viewer.handle_key(key, &mut chip)
+                }
+                UiEvent::KeyDown(key) if launcher.open => match key {
+                    Keycode::Up => launcher.select_previous(),
+                    Keycode::Down => launcher.select_next(),
+                    Keycode::Return => {
+                        pending = launcher
+                            .selected()
+                            .map(|entry| entry.path.display().to_string())
+                    }
+                    _ => {}
+                },
+                UiEvent::KeyDown(_) => {}
+            }
+        }
+
+        console.poll(&mut chip, &mut debugger);
+        if let Some(gdb_server) = &mut gdb_server {
+            gdb_server.poll(&mut chip, &mut debugger);
+        }
+        if let Some(dap_server) = &mut dap_server {
+            if let Some(launch) = dap_server.poll(&mut chip, &mut debugger) {
+                pending = Some(launch.program);
+                dap_launch = Some(launch.line_map);
+            }
+        }
+        if let Some(automation) = &mut automation {
+            let rom = match automation.poll(&mut chip, &mut debugger) {
+                Some(automation::Control::Load(rom)) => Some(rom),
+                Some(automation::Control::Reset) if loaded => Some(rom_name.clone()),
+                Some(automation::Control::Reset) => {
+                    let error = Err("no ROM is loaded".to_owned());
+                    automation.finish_load(error, &chip, &mut debugger);
+                    None
+                }
+                None => None,
+            };
+            if rom.is_some() {
+                pending = rom;
+                automation_load = true;
+            }
+        }
 
-        std::thread::sleep(Duration::new(0, 10_000_000 as u32));
+        if launcher.open {
+            sdl_driver.render_launcher(&launcher);
+        } else {
+            let running = !debugger.paused;
+            run_frame(
+                &mut chip,
+                &mut debugger,
+                settings.instructions_per_frame,
+                &mut script,
+            )?;
+            if let (Some(automation), true) = (&mut automation, running) {
+                automation.end_frame(&chip, &mut debugger);
+            }
+            if let Some(code) = script.as_ref().and_then(Script::exit_code) {
+                exit_code = Some(code);
+                quit = true;
+            }
+
+            sdl_driver.render(&mut chip, DISPLAY_SCALE);
+            let samples = chip.audio_frame();
+            sdl_driver.play_sound(&samples);
+            if let Some(recording) = &mut recorder {
+                if let Err(err) = recording.record(&sdl_driver.screenshot(&chip), &samples) {
+                    eprintln!("Recording stopped: {}", err);
+                    recorder = None;
+                }
+            }
+        }
+
+        if let Some(scale) = debugger.screenshot.take() {
+            let scale = scale.unwrap_or_else(|| sdl_driver.display_scale(&chip, DISPLAY_SCALE));
+            save_screenshots(&sdl_driver.screenshot(&chip), &rom_name, scale);
+        }
+
+        std::thread::sleep(FRAME_DURATION.saturating_sub(frame_start.elapsed()));
 
         if quit {
             break;
         }
     }
 
+    if let Some(recorder) = recorder {
+        finish_recording(recorder);
+    }
+    if args.screenshot && loaded {
+        let scale = sdl_driver::window_scale(&chip, DISPLAY_SCALE);
+        save_screenshots(&sdl_driver.screenshot(&chip), &rom_name, scale);
+    }
+    save_results(&args, &chip)?;
+    if let Some(code) = exit_code {
+        std::process::exit(code);
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_megachip_roms_bigger_than_classic_memory() {
+        let path = std::env::temp_dir().join(format!("megachip-{}.mc8", std::process::id()));
+        fs::write(&path, vec![0x00; 8000]).unwrap();
+        let args = Args {
+            platform: Some(chip::Platform::MegaChip),
+            ..Args::default()
+        };
+
+        let started = start(path.to_str().unwrap(), &args, &RomDatabase::default());
+        fs::remove_file(&path).unwrap();
+        let (chip, settings) = started.unwrap();
+        assert_eq!(settings.platform, chip::Platform::MegaChip);
+        assert_eq!(chip.rom().len(), 8000);
+        assert!(chip.mega.is_some());
+    }
+}
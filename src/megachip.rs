@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+
+/// Memory of a MEGA-CHIP machine, all of it reachable through `01NN NNNN`.
+pub const MEMORY_SIZE: usize = 0x100_0000;
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 192;
+
+const BLACK: u32 = 0xFF00_0000;
+/// The unsigned 8-bit level of silence.
+pub const SILENCE: u8 = 0x80;
+/// Bytes before the sound data: a 16-bit sample rate, a 24-bit length and
+/// a reserved byte.
+const SAMPLE_HEADER_LEN: usize = 6;
+
+/// How sprite pixels combine with the pixels under them, set by `080N`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Blend {
+    /// Mixed by the palette colour's own alpha.
+    Normal,
+    Quarter,
+    Half,
+    Add,
+    Multiply,
+}
+
+impl Blend {
+    pub fn from_mode(mode: u8) -> Blend {
+        match mode {
+            1 => Blend::Quarter,
+            2 => Blend::Half,
+            3 => Blend::Add,
+            4 => Blend::Multiply,
+            _ => Blend::Normal,
+        }
+    }
+}
+
+/// A digitised sound playing out of memory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Sample {
+    start: usize,
+    len: usize,
+    rate: u32,
+    looping: bool,
+    /// Samples played so far, in the sound's own rate.
+    position: f64,
+}
+
+/// What a MEGA-CHIP machine has on top of CHIP-8. Outside mega mode the
+/// machine draws like CHIP-8; in it, `Chip::video` holds the palette index
+/// of each pixel for collisions and the colours are kept here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MegaChip {
+    /// Whether `0011` has switched on 256x192 colour mode.
+    pub enabled: bool,
+    /// ARGB colour of each palette index. Index 0 is never drawn.
+    #[serde(with = "palette")]
+    palette: [u32; 256],
+    pub sprite_width: usize,
+    pub sprite_height: usize,
+    /// Palette index that sets VF when a sprite covers it.
+    pub collision_color: u8,
+    pub blend: Blend,
+    /// Opacity of the whole screen, set by `05NN`.
+    pub alpha: u8,
+    /// ARGB pixels sprites are drawn into, shown by the next `00E0`.
+    buffer: Vec<u32>,
+    /// ARGB pixels on screen.
+    pub frame: Vec<u32>,
+    sample: Option<Sample>,
+}
+
+impl Default for MegaChip {
+    fn default() -> Self {
+        MegaChip::new()
+    }
+}
+
+impl MegaChip {
+    pub fn new() -> MegaChip {
+        MegaChip {
+            enabled: false,
+            palette: [BLACK; 256],
+            sprite_width: 256,
+            sprite_height: 256,
+            collision_color: 0xFF,
+            blend: Blend::Normal,
+            alpha: 0xFF,
+            buffer: vec![BLACK; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame: vec![BLACK; SCREEN_WIDTH * SCREEN_HEIGHT],
+            sample: None,
+        }
+    }
+
+    /// Whether the screens are the size drawing expects, as a loaded save
+    /// state might not have them.
+    pub(crate) fn is_consistent(&self) -> bool {
+        let pixels = SCREEN_WIDTH * SCREEN_HEIGHT;
+        self.buffer.len() == pixels
+            && self.frame.len() == pixels
+            && self.sprite_width <= SCREEN_WIDTH
+            && self.sprite_height <= SCREEN_WIDTH
+    }
+
+    /// Blanks both the screen and the buffer being drawn.
+    pub fn reset_screen(&mut self) {
+        self.buffer.fill(BLACK);
+        self.frame.fill(BLACK);
+    }
+
+    /// Reads `count` ARGB colours from `address` into palette indices 1 on.
+    pub fn load_palette(&mut self, memory: &[u8], address: usize, count: usize) {
+        for (i, color) in self.palette[1..=count].iter_mut().enumerate() {
+            let at = address + 4 * i;
+            if let Some(bytes) = memory.get(at..at + 4) {
+                *color = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+        }
+    }
+
+    /// Draws a `sprite_width` by `sprite_height` sprite of palette indices
+    /// at (x, y), leaving pixels of index 0 alone, and returns whether it
+    /// covered a pixel of the collision colour.
+    pub fn draw(&mut self, video: &mut [u8], sprite: &[u8], x: u8, y: u8, wrap: bool) -> bool {
+        let mut collision = false;
+
+        for row in 0..self.sprite_height {
+            let mut y = y as usize + row;
+            if y >= SCREEN_HEIGHT {
+                if !wrap {
+                    break;
+                }
+                y %= SCREEN_HEIGHT;
+            }
+
+            for column in 0..self.sprite_width {
+                let mut x = x as usize + column;
+                if x >= SCREEN_WIDTH {
+                    if !wrap {
+                        break;
+                    }
+                    x %= SCREEN_WIDTH;
+                }
+
+                let index = sprite
+                    .get(row * self.sprite_width + column)
+                    .copied()
+                    .unwrap_or(0);
+                if index == 0 {
+                    continue;
+                }
+
+                let pixel = y * SCREEN_WIDTH + x;
+                collision |= video[pixel] == self.collision_color;
+                video[pixel] = index;
+                self.buffer[pixel] =
+                    blend(self.buffer[pixel], self.palette[index as usize], self.blend);
+            }
+        }
+
+        collision
+    }
+
+    /// Shows what has been drawn since the last call, faded by `alpha`, and
+    /// starts a blank buffer.
+    pub fn present(&mut self) {
+        let alpha = (self.alpha as u32) << 24;
+        for (shown, drawn) in self.frame.iter_mut().zip(&self.buffer) {
+            *shown = blend(BLACK, drawn & 0x00FF_FFFF | alpha, Blend::Normal);
+        }
+        self.buffer.fill(BLACK);
+    }
+
+    /// Starts the sound whose header is at `address`, replacing any playing.
+    pub fn play(&mut self, memory: &[u8], address: usize, looping: bool) {
+        let header = match memory.get(address..address + SAMPLE_HEADER_LEN) {
+            Some(header) => header,
+            None => return,
+        };
+        let rate = u16::from_be_bytes([header[0], header[1]]) as u32;
+        let len = u32::from_be_bytes([0, header[2], header[3], header[4]]) as usize;
+
+        self.sample = (rate > 0 && len > 0).then_some(Sample {
+            start: address + SAMPLE_HEADER_LEN,
+            len,
+            rate,
+            looping,
+            position: 0.0,
+        });
+    }
+
+    pub fn stop(&mut self) {
+        self.sample = None;
+    }
+
+    /// The next `count` unsigned 8-bit samples at `rate` Hz of the sound
+    /// playing, silence once it has finished.
+    pub fn mix(&mut self, memory: &[u8], rate: u32, count: usize) -> Vec<u8> {
+        let mut output = vec![SILENCE; count];
+        let sample = match &mut self.sample {
+            Some(sample) => sample,
+            None => return output,
+        };
+        let step = sample.rate as f64 / rate as f64;
+
+        for value in &mut output {
+            if sample.position as usize >= sample.len {
+                if !sample.looping {
+                    self.sample = None;
+                    break;
+                }
+                sample.position = 0.0;
+            }
+
+            let at = sample.start + sample.position as usize;
+            *value = memory.get(at).copied().unwrap_or(SILENCE);
+            sample.position += step;
+        }
+
+        output
+    }
+}
+
+/// Combines an ARGB sprite colour with the pixel under it.
+fn blend(under: u32, over: u32, mode: Blend) -> u32 {
+    let alpha = match mode {
+        Blend::Quarter => 0x40,
+        Blend::Half => 0x80,
+        _ => over >> 24,
+    };
+    let channel = |shift: u32| {
+        let under = (under >> shift) & 0xFF;
+        let over = (over >> shift) & 0xFF;
+        match mode {
+            Blend::Add => (under + over).min(0xFF),
+            Blend::Multiply => under * over / 0xFF,
+            _ => (over * alpha + under * (0xFF - alpha)) / 0xFF,
+        }
+    };
+
+    BLACK | channel(16) << 16 | channel(8) << 8 | channel(0)
+}
+
+/// Saves the palette as a sequence, since serde only derives arrays of up
+/// to 32 elements.
+mod palette {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        palette: &[u32; 256],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        palette.as_slice().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u32; 256], D::Error> {
+        let colors = Vec::<u32>::deserialize(deserializer)?;
+        colors
+            .try_into()
+            .map_err(|_| D::Error::custom("a palette has 256 colours"))
+    }
+}
@@ -0,0 +1,138 @@
+use sdl2::keyboard::Keycode;
+
+use crate::chip::{Chip, MEMORY_SIZE};
+
+pub const BYTES_PER_ROW: usize = 8;
+pub const VISIBLE_ROWS: usize = 12;
+/// Frames a byte stays highlighted after it changes.
+pub const FLASH_FRAMES: u8 = 20;
+
+/// State of the hex/ASCII memory viewer and editor.
+///
+/// Arrow keys and Page Up/Down move the cursor, hex digits overwrite the
+/// byte under it a nibble at a time, and `G` followed by an address and
+/// Enter jumps to that address.
+pub struct MemoryViewer {
+    pub open: bool,
+    pub cursor: u16,
+    pub top: u16,
+    pub jump_input: Option<String>,
+    pending_nibble: Option<u8>,
+    previous: Vec<u8>,
+    flash: Vec<u8>,
+}
+
+impl MemoryViewer {
+    pub fn new() -> MemoryViewer {
+        MemoryViewer {
+            open: false,
+            cursor: 0x200,
+            top: 0x200,
+            jump_input: None,
+            pending_nibble: None,
+            previous: vec![0; MEMORY_SIZE],
+            flash: vec![0; MEMORY_SIZE],
+        }
+    }
+
+    /// Records which bytes changed since the previous frame.
+    pub fn update(&mut self, chip: &Chip) {
+        for (i, byte) in chip.memory()[..MEMORY_SIZE].iter().enumerate() {
+            if self.previous[i] != *byte {
+                self.flash[i] = FLASH_FRAMES;
+            } else {
+                self.flash[i] = self.flash[i].saturating_sub(1);
+            }
+        }
+
+        self.previous.copy_from_slice(&chip.memory()[..MEMORY_SIZE]);
+    }
+
+    pub fn is_flashing(&self, address: usize) -> bool {
+        self.flash[address] > 0
+    }
+
+    pub fn handle_key(&mut self, key: Keycode, chip: &mut Chip) {
+        if let Some(input) = &mut self.jump_input {
+            match key {
+                Keycode::Return | Keycode::KpEnter => {
+                    if let Ok(address) = u16::from_str_radix(input, 16) {
+                        self.jump(address);
+                    }
+                    self.jump_input = None;
+                }
+                Keycode::Backspace => {
+                    input.pop();
+                }
+                _ => {
+                    if let Some(digit) = hex_digit(key).filter(|_| input.len() < 3) {
+                        input.push(char::from_digit(digit as u32, 16).unwrap());
+                    }
+                }
+            }
+            return;
+        }
+
+        match key {
+            Keycode::G => self.jump_input = Some(String::new()),
+            Keycode::Left => self.jump(self.cursor.wrapping_sub(1)),
+            Keycode::Right => self.jump(self.cursor.wrapping_add(1)),
+            Keycode::Up => self.jump(self.cursor.wrapping_sub(BYTES_PER_ROW as u16)),
+            Keycode::Down => self.jump(self.cursor.wrapping_add(BYTES_PER_ROW as u16)),
+            Keycode::PageUp => self.jump(
+                self.cursor
+                    .wrapping_sub((BYTES_PER_ROW * VISIBLE_ROWS) as u16),
+            ),
+            Keycode::PageDown => self.jump(
+                self.cursor
+                    .wrapping_add((BYTES_PER_ROW * VISIBLE_ROWS) as u16),
+            ),
+            _ => {
+                if let Some(digit) = hex_digit(key) {
+                    self.type_nibble(digit, chip);
+                }
+            }
+        }
+    }
+
+    /// Moves the cursor to `address`, scrolling it into view.
+    pub fn jump(&mut self, address: u16) {
+        self.cursor = address % MEMORY_SIZE as u16;
+        self.pending_nibble = None;
+
+        let row = self.cursor - self.cursor % BYTES_PER_ROW as u16;
+        let page = (BYTES_PER_ROW * VISIBLE_ROWS) as u16;
+        if row < self.top || row >= self.top + page {
+            self.top = row.saturating_sub(page / 2);
+            self.top -= self.top % BYTES_PER_ROW as u16;
+            self.top = self.top.min(MEMORY_SIZE as u16 - page);
+        }
+    }
+
+    fn type_nibble(&mut self, digit: u8, chip: &mut Chip) {
+        let address = self.cursor;
+
+        match self.pending_nibble.take() {
+            None => {
+                let low = chip.memory()[address as usize] & 0x0F;
+                chip.write_memory(address, &[(digit << 4) | low]);
+                self.pending_nibble = Some(digit);
+            }
+            Some(high) => {
+                chip.write_memory(address, &[(high << 4) | digit]);
+                self.jump(self.cursor.wrapping_add(1));
+            }
+        }
+    }
+}
+
+fn hex_digit(key: Keycode) -> Option<u8> {
+    let name = key.name();
+    let name = name.strip_prefix("Keypad ").unwrap_or(&name);
+
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => c.to_digit(16).map(|digit| digit as u8),
+        _ => None,
+    }
+}
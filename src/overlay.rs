@@ -0,0 +1,169 @@
+use sdl2::pixels::Color;
+use sdl2::render::WindowCanvas;
+
+use crate::chip::Chip;
+use crate::debugger::disassemble;
+use crate::memview::{MemoryViewer, BYTES_PER_ROW, VISIBLE_ROWS};
+use crate::text::{draw_text, GLYPH_HEIGHT, GLYPH_WIDTH};
+
+const TEXT_SCALE: u32 = 2;
+const DISASSEMBLY_CONTEXT: i32 = 6;
+
+/// Draws the live debug panel (registers, stack, timers, keys and the code
+/// around PC) with its top left corner at `(x, y)`.
+pub fn draw_debug_panel(canvas: &mut WindowCanvas, x: i32, y: i32, chip: &Chip, color: Color) {
+    let highlight = Color::RGB(255, 200, 0);
+    let line_height = (GLYPH_HEIGHT * TEXT_SCALE + 2) as i32;
+    let mut line = 0;
+    let mut print = |canvas: &mut WindowCanvas, color: Color, text: &str| {
+        draw_text(canvas, x, y + line * line_height, TEXT_SCALE, color, text);
+        line += 1;
+    };
+
+    for (row, values) in chip.registers.chunks(4).enumerate() {
+        let text: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("V{:X}={:02X}", row * 4 + i, value))
+            .collect();
+        print(canvas, color, &text.join(" "));
+    }
+
+    print(
+        canvas,
+        color,
+        &format!("I={:03X} PC={:03X} SP={:X}", chip.index, chip.pc, chip.sp),
+    );
+    print(
+        canvas,
+        color,
+        &format!("DT={:02X} ST={:02X}", chip.delay_timer, chip.sound_timer),
+    );
+
+    let pressed: Vec<String> = chip.keypad.held().map(|key| format!("{:X}", key)).collect();
+    print(canvas, color, &format!("KEYS {}", pressed.join(" ")));
+
+    let stack: Vec<String> = chip.stack[..chip.sp as usize]
+        .iter()
+        .map(|address| format!("{:03X}", address))
+        .collect();
+    print(canvas, color, &format!("STACK {}", stack.join(" ")));
+
+    print(canvas, color, "");
+
+    for offset in -DISASSEMBLY_CONTEXT..=DISASSEMBLY_CONTEXT {
+        let address = chip.pc as i32 + offset * 2;
+        if address < 0 || address as usize + 1 >= chip.memory().len() {
+            continue;
+        }
+
+        let address = address as usize;
+        let opcode = (chip.memory()[address] as u16) << 8 | chip.memory()[address + 1] as u16;
+        let (marker, line_color) = if offset == 0 {
+            (">", highlight)
+        } else {
+            (" ", color)
+        };
+
+        print(
+            canvas,
+            line_color,
+            &format!(
+                "{}{:03X} {:04X} {}",
+                marker,
+                address,
+                opcode,
+                disassemble(opcode, &chip.symbols)
+            ),
+        );
+    }
+}
+
+/// Draws the hex/ASCII memory view with PC, I and the stack's return
+/// addresses highlighted, and recently changed bytes flashing.
+pub fn draw_memory_panel(
+    canvas: &mut WindowCanvas,
+    x: i32,
+    y: i32,
+    chip: &Chip,
+    viewer: &MemoryViewer,
+    color: Color,
+) {
+    let line_height = (GLYPH_HEIGHT * TEXT_SCALE + 2) as i32;
+    let char_width = (GLYPH_WIDTH * TEXT_SCALE) as i32;
+    let ascii_column = 4 + 3 * BYTES_PER_ROW as i32 + 1;
+
+    let return_addresses = &chip.stack[..chip.sp as usize];
+    let byte_color = |address: usize| {
+        let address16 = address as u16;
+        if address16 == viewer.cursor {
+            Color::RGB(0, 255, 0)
+        } else if viewer.is_flashing(address) {
+            Color::RGB(255, 60, 60)
+        } else if address16 == chip.pc || address16 == chip.pc + 1 {
+            Color::RGB(255, 200, 0)
+        } else if address16 as u32 == chip.index {
+            Color::RGB(0, 200, 255)
+        } else if return_addresses.contains(&address16) {
+            Color::RGB(255, 0, 255)
+        } else {
+            color
+        }
+    };
+
+    for row in 0..VISIBLE_ROWS {
+        let start = viewer.top as usize + row * BYTES_PER_ROW;
+        if start >= chip.memory().len() {
+            break;
+        }
+
+        let line_y = y + row as i32 * line_height;
+        draw_text(
+            canvas,
+            x,
+            line_y,
+            TEXT_SCALE,
+            color,
+            &format!("{:03X}", start),
+        );
+
+        for (column, byte) in chip.memory()[start..start + BYTES_PER_ROW]
+            .iter()
+            .enumerate()
+        {
+            let byte_color = byte_color(start + column);
+
+            let hex_x = x + (4 + 3 * column as i32) * char_width;
+            draw_text(
+                canvas,
+                hex_x,
+                line_y,
+                TEXT_SCALE,
+                byte_color,
+                &format!("{:02X}", byte),
+            );
+
+            let ascii = if byte.is_ascii_graphic() {
+                *byte as char
+            } else {
+                '.'
+            };
+            let ascii_x = x + (ascii_column + column as i32) * char_width;
+            draw_text(
+                canvas,
+                ascii_x,
+                line_y,
+                TEXT_SCALE,
+                byte_color,
+                &ascii.to_string(),
+            );
+        }
+    }
+
+    let status = match &viewer.jump_input {
+        Some(input) => format!("GO TO: {}_", input),
+        None => format!("CURSOR {:03X}  G: GO TO", viewer.cursor),
+    };
+    let status_y = y + VISIBLE_ROWS as i32 * line_height;
+    draw_text(canvas, x, status_y, TEXT_SCALE, color, &status);
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::chip::MEMORY_SIZE;
+use crate::symbols::SymbolTable;
+
+const REPORT_TOP_ADDRESSES: usize = 20;
+
+/// Counts executed instructions per address, per opcode class and per call
+/// stack. Attach one to `Chip::profiler` to enable it.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    pub total: u64,
+    pub address_counts: Vec<u64>,
+    pub class_counts: HashMap<&'static str, u64>,
+    /// Entry addresses of the subroutines currently on the CHIP-8 stack,
+    /// outermost first. The first entry is the program's start address.
+    call_stack: Vec<u16>,
+    stack_counts: HashMap<Vec<u16>, u64>,
+}
+
+impl Profiler {
+    pub fn new(entry: u16) -> Profiler {
+        Profiler {
+            total: 0,
+            address_counts: vec![0; MEMORY_SIZE],
+            class_counts: HashMap::new(),
+            call_stack: vec![entry],
+            stack_counts: HashMap::new(),
+        }
+    }
+
+    /// Records the instruction about to execute at `pc`.
+    pub fn record(&mut self, pc: u16, opcode: u16) {
+        self.total += 1;
+        self.address_counts[pc as usize % MEMORY_SIZE] += 1;
+        *self.class_counts.entry(opcode_class(opcode)).or_insert(0) += 1;
+
+        match self.stack_counts.get_mut(&self.call_stack) {
+            Some(count) => *count += 1,
+            None => {
+                self.stack_counts.insert(self.call_stack.clone(), 1);
+            }
+        }
+
+        if opcode & 0xF000 == 0x2000 {
+            self.call_stack.push(opcode & 0x0FFF);
+        } else if opcode == 0x00EE && self.call_stack.len() > 1 {
+            self.call_stack.pop();
+        }
+    }
+
+    /// Cycles per subroutine entry address as `(self, inclusive)`.
+    pub fn subroutines(&self) -> HashMap<u16, (u64, u64)> {
+        let mut subroutines: HashMap<u16, (u64, u64)> = HashMap::new();
+
+        for (stack, count) in &self.stack_counts {
+            if let Some(innermost) = stack.last() {
+                subroutines.entry(*innermost).or_default().0 += count;
+            }
+
+            let mut seen = Vec::with_capacity(stack.len());
+            for address in stack {
+                // Recursive calls only count once towards the inclusive total.
+                if !seen.contains(address) {
+                    seen.push(*address);
+                    subroutines.entry(*address).or_default().1 += count;
+                }
+            }
+        }
+
+        subroutines
+    }
+
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let percent = |count: u64| 100.0 * count as f64 / self.total.max(1) as f64;
+        let mut report = format!("Instructions executed: {}\n", self.total);
+
+        report += "\nHottest addresses:\n";
+        let mut addresses: Vec<(usize, u64)> = self
+            .address_counts
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (address, count) in addresses.iter().take(REPORT_TOP_ADDRESSES) {
+            report += &format!(
+                "  {:>10} {:>6.2}%  {}\n",
+                count,
+                percent(*count),
+                symbols.format_address(*address as u16)
+            );
+        }
+
+        report += "\nOpcode classes:\n";
+        let mut classes: Vec<_> = self.class_counts.iter().collect();
+        classes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (class, count) in classes {
+            report += &format!("  {:>10} {:>6.2}%  {}\n", count, percent(*count), class);
+        }
+
+        report += "\nSubroutines (self, inclusive):\n";
+        let mut subroutines: Vec<_> = self.subroutines().into_iter().collect();
+        subroutines.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.0.cmp(&b.0)));
+        for (address, (self_count, inclusive)) in subroutines {
+            report += &format!(
+                "  {:>10} {:>6.2}%  {:>10} {:>6.2}%  {}\n",
+                self_count,
+                percent(self_count),
+                inclusive,
+                percent(inclusive),
+                symbols.format_address(address)
+            );
+        }
+
+        report
+    }
+
+    /// Call stacks in the folded format read by flamegraph tools: one line
+    /// per stack, frames separated by `;`, followed by a sample count.
+    pub fn folded_stacks(&self, symbols: &SymbolTable) -> String {
+        let mut lines: Vec<String> = self
+            .stack_counts
+            .iter()
+            .map(|(stack, count)| {
+                let frames: Vec<String> = stack
+                    .iter()
+                    .map(|address| symbols.format_address(*address))
+                    .collect();
+                format!("{} {}", frames.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+
+        lines.join("\n") + "\n"
+    }
+
+    /// Writes the report to `<stem>.txt` and the folded stacks to
+    /// `<stem>.folded`.
+    pub fn save(&self, stem: &str, symbols: &SymbolTable) -> io::Result<()> {
+        fs::write(Path::new(&format!("{}.txt", stem)), self.report(symbols))?;
+        fs::write(
+            Path::new(&format!("{}.folded", stem)),
+            self.folded_stacks(symbols),
+        )
+    }
+}
+
+/// Groups an opcode with the others that share its instruction, e.g. every
+/// `6XNN` load is counted as `6XNN`.
+pub fn opcode_class(opcode: u16) -> &'static str {
+    match (opcode & 0xF000, opcode & 0x00FF, opcode & 0x000F) {
+        (0x0000, 0xE0, _) => "00E0",
+        (0x0000, 0xEE, _) => "00EE",
+        (0x0000, _, _) => "0NNN",
+        (0x1000, _, _) => "1NNN",
+        (0x2000, _, _) => "2NNN",
+        (0x3000, _, _) => "3XNN",
+        (0x4000, _, _) => "4XNN",
+        (0x5000, _, _) => "5XY0",
+        (0x6000, _, _) => "6XNN",
+        (0x7000, _, _) => "7XNN",
+        (0x8000, _, 0x0) => "8XY0",
+        (0x8000, _, 0x1) => "8XY1",
+        (0x8000, _, 0x2) => "8XY2",
+        (0x8000, _, 0x3) => "8XY3",
+        (0x8000, _, 0x4) => "8XY4",
+        (0x8000, _, 0x5) => "8XY5",
+        (0x8000, _, 0x6) => "8XY6",
+        (0x8000, _, 0x7) => "8XY7",
+        (0x8000, _, 0xE) => "8XYE",
+        (0x9000, _, _) => "9XY0",
+        (0xA000, _, _) => "ANNN",
+        (0xB000, _, _) => "BNNN",
+        (0xC000, _, _) => "CXNN",
+        (0xD000, _, _) => "DXYN",
+        (0xE000, 0x9E, _) => "EX9E",
+        (0xE000, 0xA1, _) => "EXA1",
+        (0xF000, 0x07, _) => "FX07",
+        (0xF000, 0x0A, _) => "FX0A",
+        (0xF000, 0x15, _) => "FX15",
+        (0xF000, 0x18, _) => "FX18",
+        (0xF000, 0x1E, _) => "FX1E",
+        (0xF000, 0x29, _) => "FX29",
+        (0xF000, 0x33, _) => "FX33",
+        (0xF000, 0x55, _) => "FX55",
+        (0xF000, 0x65, _) => "FX65",
+        _ => "unknown",
+    }
+}
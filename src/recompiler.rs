@@ -0,0 +1,417 @@
+use crate::chip::{self, Chip, Decoded, Platform, MEMORY_SIZE};
+use crate::timing::Timing;
+
+/// Longest run of straight-line instructions compiled into one block.
+const MAX_BLOCK_LEN: usize = 64;
+
+type Op = Box<dyn Fn(&mut Chip)>;
+
+/// A run of instructions that can't change PC, compiled to closures with
+/// their operands already decoded, followed by the instruction that ends
+/// the block.
+struct Block {
+    /// The bytes the block was compiled from, to notice when they change.
+    source: Vec<u8>,
+    body: Vec<(u16, Op)>,
+    /// The branch, skip, draw, key wait or memory write ending the block,
+    /// run through the interpreter's handler. `None` when the block was cut
+    /// at `MAX_BLOCK_LEN` or the end of memory.
+    exit: Option<Decoded>,
+}
+
+impl Block {
+    fn compile(chip: &Chip, start: u16) -> Block {
+        let mut body = Vec::new();
+        let mut exit = None;
+        let mut address = start as usize;
+
+        while body.len() < MAX_BLOCK_LEN && address + 1 < MEMORY_SIZE {
+            let opcode = chip.fetch(address);
+            address += 2;
+
+            if ends_block(opcode) {
+                exit = chip::decode(opcode, chip.platform);
+                break;
+            }
+            match compile_op(opcode, chip.platform) {
+                Some(op) => body.push((opcode, op)),
+                None => break,
+            }
+        }
+
+        let len = 2 * body.len() + if exit.is_some() { 2 } else { 0 };
+        Block {
+            source: chip.memory[start as usize..start as usize + len].to_vec(),
+            body,
+            exit,
+        }
+    }
+
+    fn is_stale(&self, chip: &Chip) -> bool {
+        let start = chip.pc as usize;
+        chip.memory[start..start + self.source.len()] != self.source[..]
+    }
+
+    /// Runs at most `budget` instructions of the block, starting `done`
+    /// instructions into a frame of `instructions`, and returns how many
+    /// ran. The machine ends up exactly as if `Chip::cycle` had run them:
+    /// only the exit can read the keys, so the key events due by then are
+    /// applied just before it.
+    fn run(&self, chip: &mut Chip, budget: u32, done: u32, instructions: u32) -> u32 {
+        let count = self.body.len().min(budget as usize);
+        for (_, op) in &self.body[..count] {
+            op(chip);
+        }
+        if count > 0 {
+            chip.opcode = self.body[count - 1].0;
+            chip.pc += 2 * count as u16;
+        }
+
+        match self.exit {
+            Some(exit) if count < budget as usize => {
+                chip.apply_keys((done + count as u32) as f32 / instructions as f32);
+                chip.opcode = exit.opcode;
+                chip.pc += 2;
+                (exit.execute)(chip);
+                count as u32 + 1
+            }
+            _ => count as u32,
+        }
+    }
+}
+
+/// An alternative to `Chip::run_frame_until` for headless runs that
+/// executes basic blocks of precompiled closures. Blocks whose memory has
+/// changed since they were compiled are dropped and the instruction is
+/// interpreted instead, and a machine with tracing, profiling or coverage
+/// on is always interpreted.
+pub struct Recompiler {
+    /// The compiled block starting at each address.
+    blocks: Vec<Option<Block>>,
+}
+
+impl Default for Recompiler {
+    fn default() -> Self {
+        Recompiler::new()
+    }
+}
+
+impl Recompiler {
+    pub fn new() -> Recompiler {
+        Recompiler {
+            blocks: (0..MEMORY_SIZE).map(|_| None).collect(),
+        }
+    }
+
+    /// Runs one frame with the same results as `Chip::run_frame_until`
+    /// without a stop condition, queued key events included. VIP timing and
+    /// emulated VIPs are left to the interpreter.
+    pub fn run_frame(&mut self, chip: &mut Chip, instructions: u32) {
+        if chip.timing == Timing::Vip || chip.vip.is_some() {
+            chip.run_frame_until(instructions, |_| false);
+            return;
+        }
+
+        chip.keypad.begin_frame();
+        chip.extra_keypad.begin_frame();
+
+        let instrumented = chip.trace || chip.profiler.is_some() || chip.coverage.is_some();
+        let mut remaining = instructions;
+
+        while remaining > 0 && chip.fault.is_none() {
+            let done = instructions - remaining;
+            chip.apply_keys(done as f32 / instructions as f32);
+            let ran = if instrumented {
+                None
+            } else {
+                self.run_block(chip, remaining, done, instructions)
+            };
+
+            match ran {
+                Some(count) => remaining -= count,
+                None => {
+                    chip.cycle();
+                    remaining -= 1;
+                }
+            }
+
+            if chip.quirks.vblank && chip.opcode & 0xF000 == 0xD000 {
+                break;
+            }
+        }
+
+        chip.apply_keys(1.0);
+        chip.tick_timers();
+    }
+
+    /// Runs the block starting at PC, compiling it if needed, and returns
+    /// how many instructions ran. `None` means the next instruction should
+    /// be interpreted.
+    fn run_block(
+        &mut self,
+        chip: &mut Chip,
+        budget: u32,
+        done: u32,
+        instructions: u32,
+    ) -> Option<u32> {
+        let pc = chip.pc as usize;
+        if pc + 1 >= MEMORY_SIZE {
+            return None;
+        }
+
+        match &self.blocks[pc] {
+            Some(block) if block.is_stale(chip) => {
+                self.blocks[pc] = None;
+                return None;
+            }
+            Some(_) => {}
+            None => {
+                let block = Block::compile(chip, chip.pc);
+                if block.body.is_empty() && block.exit.is_none() {
+                    return None;
+                }
+                self.blocks[pc] = Some(block);
+            }
+        }
+
+        self.blocks[pc]
+            .as_ref()
+            .map(|block| block.run(chip, budget, done, instructions))
+    }
+}
+
+/// Whether `opcode` may change PC, reads PC or the keys, draws, or reads or
+/// writes memory through I, and so must be the last instruction of a block.
+/// Those are also the ones that can fault.
+fn ends_block(opcode: u16) -> bool {
+    match opcode & 0xF000 {
+        0x0000 => opcode == 0x00EE || opcode & 0xFF00 == 0x0100,
+        0x1000 | 0x2000 | 0x3000 | 0x4000 | 0x5000 | 0x9000 | 0xB000 | 0xD000 | 0xE000 => true,
+        0xF000 => matches!(opcode & 0x00FF, 0x0A | 0x33 | 0x55 | 0x65),
+        _ => false,
+    }
+}
+
+/// Compiles a straight-line instruction. The most common loads and adds
+/// are bound directly; the rest call the interpreter's handler. `None`
+/// for opcodes that aren't instructions, which are left to the
+/// interpreter to report.
+fn compile_op(opcode: u16, platform: Platform) -> Option<Op> {
+    let x = ((opcode & 0x0F00) >> 8) as usize;
+    let y = ((opcode & 0x00F0) >> 4) as usize;
+    let byte = (opcode & 0x00FF) as u8;
+    let address = opcode & 0x0FFF;
+
+    let op: Op = match (opcode & 0xF000, opcode & 0x000F) {
+        (0x6000, _) => Box::new(move |chip| chip.registers[x] = byte),
+        (0x7000, _) => {
+            Box::new(move |chip| chip.registers[x] = chip.registers[x].wrapping_add(byte))
+        }
+        (0x8000, 0x0) => Box::new(move |chip| chip.registers[x] = chip.registers[y]),
+        (0xA000, _) => Box::new(move |chip| chip.index = address as u32),
+        _ => {
+            let decoded = chip::decode(opcode, platform)?;
+            Box::new(move |chip| {
+                chip.opcode = decoded.opcode;
+                (decoded.execute)(chip)
+            })
+        }
+    };
+
+    Some(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::Fault;
+    use crate::keypad::KeyEvent;
+
+    const FRAMES: usize = 300;
+    const PLATFORMS: [Platform; 3] = [
+        Platform::OriginalChip8,
+        Platform::ModernChip8,
+        Platform::Chip48,
+    ];
+
+    /// Everything a program or the frontend can see of the machine. Two
+    /// runs are identical when this matches after every frame. The decode
+    /// and block caches are left out.
+    #[allow(clippy::type_complexity)]
+    fn state(
+        chip: &Chip,
+    ) -> (
+        Vec<u8>,
+        [u8; 16],
+        u32,
+        u16,
+        u8,
+        [u16; 16],
+        (u8, u8),
+        Vec<u8>,
+        Option<Fault>,
+    ) {
+        (
+            chip.memory.clone(),
+            chip.registers,
+            chip.index,
+            chip.pc,
+            chip.sp,
+            chip.stack,
+            (chip.delay_timer, chip.sound_timer),
+            chip.video.clone(),
+            chip.fault,
+        )
+    }
+
+    fn assert_identical(rom: &[u8]) {
+        assert_identical_with_keys(rom, |_| Vec::new());
+    }
+
+    /// Like `assert_identical`, queueing `keys(frame)` on both machines
+    /// before each frame.
+    fn assert_identical_with_keys(rom: &[u8], keys: fn(usize) -> Vec<(f32, KeyEvent)>) {
+        for platform in PLATFORMS {
+            let mut interpreted = Chip::with_platform(platform);
+            interpreted.load_bytes(rom).unwrap();
+            interpreted.reseed(8);
+            let mut recompiled = interpreted.clone();
+            let mut recompiler = Recompiler::new();
+
+            for frame in 0..FRAMES {
+                for (at, event) in keys(frame) {
+                    interpreted.keypad.queue(at, event);
+                    recompiled.keypad.queue(at, event);
+                }
+                interpreted.run_frame_until(10, |_| false);
+                recompiler.run_frame(&mut recompiled, 10);
+                assert!(
+                    state(&interpreted) == state(&recompiled),
+                    "{:?} differs after frame {}",
+                    platform,
+                    frame
+                );
+                assert_eq!(interpreted.opcode, recompiled.opcode);
+                assert_eq!(
+                    (interpreted.video_width, interpreted.video_height),
+                    (recompiled.video_width, recompiled.video_height)
+                );
+            }
+        }
+    }
+
+    /// Assembles `(address, opcode)` pairs into a ROM loaded at 0x200.
+    fn assemble(program: &[(usize, u16)]) -> Vec<u8> {
+        let mut rom = Vec::new();
+        for (address, opcode) in program {
+            let at = address - 0x200;
+            rom.resize(rom.len().max(at + 2), 0);
+            rom[at..at + 2].copy_from_slice(&opcode.to_be_bytes());
+        }
+        rom
+    }
+
+    #[test]
+    fn test_opcode_rom_matches_interpreter() {
+        assert_identical(include_bytes!("../test_opcode.ch8"));
+    }
+
+    #[test]
+    fn self_modifying_rom_matches_interpreter() {
+        let rom = assemble(&[
+            (0x200, 0x603C), // V0 = 3C
+            (0x202, 0xF015), // DT = V0
+            (0x204, 0x6000), // V0 = 0
+            (0x206, 0x2244), // call 244, which adds 1 to V0
+            (0x208, 0x3005), // skip if V0 = 5
+            (0x20A, 0x1206), // back to the call
+            (0x20C, 0x6002), // V0 = 2
+            (0x20E, 0x6202), // V2 = 2, for the CHIP-48 BXNN
+            (0x210, 0xB212), // jump to 214
+            (0x212, 0x1212), // trap
+            (0x214, 0x607A), // V0 = 7A
+            (0x216, 0xF107), // V1 = DT
+            (0x218, 0xA250), // I = 250
+            (0x21A, 0xF155), // patch 250 to 7A<DT>
+            (0x21C, 0x2250), // call the patched code
+            (0x21E, 0x4A00), // skip if VA != 0
+            (0x220, 0x6B01), // VB = 1
+            (0x222, 0x9AB0), // skip if VA != VB
+            (0x224, 0x6C01), // VC = 1
+            (0x226, 0xCD0F), // VD = random & 0F
+            (0x228, 0xFD29), // I = glyph VD
+            (0x22A, 0xDCD5), // draw it
+            (0x22C, 0x3F00), // skip if nothing was erased
+            (0x22E, 0x7E01), // VE += 1
+            (0x230, 0xA300), // I = 300
+            (0x232, 0xFA33), // BCD of VA
+            (0x234, 0xF265), // read it back into V0-V2
+            (0x236, 0x8124), // V1 += V2
+            (0x238, 0x5120), // skip if V1 = V2
+            (0x23A, 0x7301), // V3 += 1
+            (0x23C, 0xF007), // V0 = DT
+            (0x23E, 0x3000), // skip if DT ran out
+            (0x240, 0x1204), // loop
+            (0x242, 0x1200), // restart, reloading DT
+            (0x244, 0x7001), // V0 += 1
+            (0x246, 0x00EE), // return
+            (0x250, 0x7A00), // VA += patched byte
+            (0x252, 0x00EE), // return
+        ]);
+        assert_identical(&rom);
+    }
+
+    #[test]
+    fn mid_frame_key_events_match_interpreter() {
+        let rom = assemble(&[
+            (0x200, 0x6105), // V1 = 5
+            (0x202, 0x7201), // V2 += 1
+            (0x204, 0x7301), // V3 += 1
+            (0x206, 0xE19E), // skip if key 5 is down
+            (0x208, 0x7401), // V4 += 1
+            (0x20A, 0x7501), // V5 += 1
+            (0x20C, 0xE1A1), // skip if key 5 is up
+            (0x20E, 0x7601), // V6 += 1
+            (0x210, 0x3420), // skip if V4 = 20
+            (0x212, 0x1202), // loop
+            (0x214, 0x6400), // V4 = 0
+            (0x216, 0xF70A), // V7 = next key let go
+            (0x218, 0x8874), // V8 += V7
+            (0x21A, 0x1202), // loop
+        ]);
+        // Taps of key 5 starting and ending at a different point of each
+        // frame, with a tap of key 9 now and then for FX0A.
+        assert_identical_with_keys(&rom, |frame| {
+            let at = (frame % 7) as f32 / 7.0;
+            let mut keys = vec![(at, KeyEvent::Press(5)), (at + 0.25, KeyEvent::Release(5))];
+            if frame % 5 == 0 {
+                keys.push((0.6, KeyEvent::Press(9)));
+                keys.push((0.85, KeyEvent::Release(9)));
+            }
+            keys
+        });
+    }
+
+    #[test]
+    fn faulting_roms_match_interpreter() {
+        // FX65 mid-block, past the end of memory.
+        assert_identical(&assemble(&[
+            (0x200, 0x6001), // V0 = 1
+            (0x202, 0xAFFF), // I = FFF
+            (0x204, 0xF165), // read two bytes from FFF
+            (0x206, 0x6101), // V1 = 1, never reached
+        ]));
+        // A jump into data.
+        assert_identical(&assemble(&[
+            (0x200, 0x6001),
+            (0x202, 0x1206),
+            (0x206, 0xFFFF),
+        ]));
+        // Off the end of memory.
+        assert_identical(&assemble(&[
+            (0x200, 0x1FFC),
+            (0xFFC, 0x6001),
+            (0xFFE, 0x6002),
+        ]));
+    }
+}
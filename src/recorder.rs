@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::chip::AUDIO_RATE;
+use crate::screenshot::Image;
+
+/// Frames recorded per second, the rate the timers run at.
+const FRAME_RATE: u64 = 60;
+/// Shortest delay GIF viewers honour, in hundredths of a second. Most show
+/// shorter frames for a tenth of a second instead.
+const MIN_GIF_DELAY: u64 = 2;
+/// NeuQuant sampling speed for frames with more colours than a GIF palette
+/// holds, from 1 (best) to 30 (fastest).
+const QUANTIZE_SPEED: i32 = 10;
+
+/// Records the display every frame to an animated GIF or raw Y4M video, and
+/// optionally the sound to a WAV file alongside.
+pub struct Recorder {
+    video: Video,
+    audio: Option<Wav>,
+    width: usize,
+    height: usize,
+}
+
+enum Video {
+    Gif(Gif),
+    Y4m(BufWriter<File>),
+}
+
+impl Recorder {
+    /// Starts recording `width` by `height` frames to `path`, a `.gif` or
+    /// `.y4m` file, and the sound to `audio` if given. Frames of another
+    /// size, as when a program switches resolution, are stretched to fit.
+    pub fn start<P: AsRef<Path>>(
+        path: P,
+        audio: Option<&Path>,
+        width: usize,
+        height: usize,
+    ) -> Result<Recorder, Box<dyn Error>> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+
+        let video = match extension.as_deref() {
+            Some("gif") => Video::Gif(Gif::create(path, width, height)?),
+            Some("y4m") => {
+                let mut file = BufWriter::new(File::create(path)?);
+                writeln!(
+                    file,
+                    "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444",
+                    width, height, FRAME_RATE
+                )?;
+                Video::Y4m(file)
+            }
+            _ => return Err(format!("{}: recordings must be .gif or .y4m", path.display()).into()),
+        };
+        let audio = audio.map(Wav::create).transpose()?;
+
+        Ok(Recorder {
+            video,
+            audio,
+            width,
+            height,
+        })
+    }
+
+    /// Adds a frame of the display and the frame's `Chip::audio_frame`
+    /// samples.
+    pub fn record(&mut self, image: &Image, samples: &[u8]) -> Result<(), Box<dyn Error>> {
+        let resized;
+        let image = if (image.width, image.height) == (self.width, self.height) {
+            image
+        } else {
+            resized = image.resized(self.width, self.height);
+            &resized
+        };
+
+        match &mut self.video {
+            Video::Gif(gif) => gif.add(image)?,
+            Video::Y4m(file) => write_y4m_frame(file, image)?,
+        }
+        if let Some(audio) = &mut self.audio {
+            audio.write(samples)?;
+        }
+        Ok(())
+    }
+
+    /// Writes out what is still buffered and completes the files.
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self.video {
+            Video::Gif(gif) => gif.finish()?,
+            Video::Y4m(mut file) => file.flush()?,
+        }
+        if let Some(audio) = self.audio {
+            audio.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// An animated GIF written a frame behind, so that a run of identical
+/// frames becomes one frame shown for longer.
+struct Gif {
+    encoder: gif::Encoder<BufWriter<File>>,
+    width: u16,
+    height: u16,
+    /// The latest distinct frame, not written yet.
+    held: Option<Vec<u8>>,
+    /// Frames recorded before `held` began, and in all.
+    held_from: u64,
+    frames: u64,
+}
+
+impl Gif {
+    fn create(path: &Path, width: usize, height: usize) -> Result<Gif, Box<dyn Error>> {
+        let (width, height) = (u16::try_from(width)?, u16::try_from(height)?);
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = gif::Encoder::new(file, width, height, &[])?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+
+        Ok(Gif {
+            encoder,
+            width,
+            height,
+            held: None,
+            held_from: 0,
+            frames: 0,
+        })
+    }
+
+    fn add(&mut self, image: &Image) -> Result<(), Box<dyn Error>> {
+        if self.held.as_ref() != Some(&image.pixels) {
+            // A frame too short to show is dropped for the one after it.
+            if self.delay() >= MIN_GIF_DELAY {
+                self.write_held()?;
+                self.held_from = self.frames;
+            }
+            self.held = Some(image.pixels.clone());
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// How long `held` has been showing, in hundredths of a second.
+    fn delay(&self) -> u64 {
+        let centiseconds = |frames: u64| frames * 100 / FRAME_RATE;
+        centiseconds(self.frames) - centiseconds(self.held_from)
+    }
+
+    fn write_held(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(pixels) = &self.held else {
+            return Ok(());
+        };
+
+        let mut frame = match palettize(pixels) {
+            Some((indices, palette)) => {
+                gif::Frame::from_palette_pixels(self.width, self.height, indices, palette, None)
+            }
+            None => gif::Frame::from_rgb_speed(self.width, self.height, pixels, QUANTIZE_SPEED),
+        };
+        frame.delay = self.delay().min(u16::MAX as u64) as u16;
+        self.encoder.write_frame(&frame)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.write_held()?;
+        self.encoder.into_inner()?.flush()?;
+        Ok(())
+    }
+}
+
+/// The palette index of each pixel and the palette, or `None` when the
+/// pixels have more colours than a GIF palette holds.
+fn palettize(pixels: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut palette = Vec::new();
+    let mut indices: HashMap<&[u8], u8> = HashMap::new();
+
+    let pixels = pixels
+        .chunks(3)
+        .map(|rgb| match indices.get(rgb) {
+            Some(index) => Some(*index),
+            None => {
+                let index = u8::try_from(indices.len()).ok()?;
+                indices.insert(rgb, index);
+                palette.extend_from_slice(rgb);
+                Some(index)
+            }
+        })
+        .collect::<Option<Vec<u8>>>()?;
+
+    Some((pixels, palette))
+}
+
+/// Writes `image` as a Y4M frame of full-resolution BT.601 luma and chroma
+/// planes.
+fn write_y4m_frame(file: &mut BufWriter<File>, image: &Image) -> Result<(), Box<dyn Error>> {
+    let pixels: Vec<(i32, i32, i32)> = image
+        .pixels
+        .chunks(3)
+        .map(|rgb| (rgb[0] as i32, rgb[1] as i32, rgb[2] as i32))
+        .collect();
+    let plane = |(r, g, b): (i32, i32, i32), offset: i32| {
+        pixels
+            .iter()
+            .map(|(red, green, blue)| {
+                (((r * red + g * green + b * blue + 128) >> 8) + offset) as u8
+            })
+            .collect::<Vec<u8>>()
+    };
+
+    file.write_all(b"FRAME\n")?;
+    file.write_all(&plane((66, 129, 25), 16))?;
+    file.write_all(&plane((-38, -74, 112), 128))?;
+    file.write_all(&plane((112, -94, -18), 128))?;
+    Ok(())
+}
+
+/// A mono, unsigned 8-bit WAV file at `AUDIO_RATE`. The header is written
+/// with empty lengths and filled in by `finish`.
+struct Wav {
+    file: BufWriter<File>,
+    len: u32,
+}
+
+impl Wav {
+    fn create(path: &Path) -> Result<Wav, Box<dyn Error>> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        // PCM, one channel, one byte a sample.
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&AUDIO_RATE.to_le_bytes())?;
+        file.write_all(&AUDIO_RATE.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&8u16.to_le_bytes())?;
+        file.write_all(b"data\0\0\0\0")?;
+
+        Ok(Wav { file, len: 0 })
+    }
+
+    fn write(&mut self, samples: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.file.write_all(samples)?;
+        self.len += samples.len() as u32;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.len.to_le_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::chip::{Platform, Quirks, DEFAULT_INSTRUCTIONS_PER_FRAME};
+
+/// Program list shipped with the emulator, in the community CHIP-8 database
+/// `programs.json` format.
+const BUNDLED_PROGRAMS: &str = include_str!("../db/programs.json");
+
+/// User additions in the same format. Entries here win over bundled ones.
+pub const USER_DATABASE_PATH: &str = "romdb.json";
+
+#[derive(Debug, Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    roms: HashMap<String, RomEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkOverrides>,
+    tickrate: Option<u32>,
+    colors: Option<Colors>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuirkOverrides {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl QuirkOverrides {
+    fn apply(&self, quirks: &mut Quirks) {
+        let fields = [
+            (self.shift, &mut quirks.shift),
+            (
+                self.memory_increment_by_x,
+                &mut quirks.memory_increment_by_x,
+            ),
+            (
+                self.memory_leave_i_unchanged,
+                &mut quirks.memory_leave_i_unchanged,
+            ),
+            (self.wrap, &mut quirks.wrap),
+            (self.jump, &mut quirks.jump),
+            (self.vblank, &mut quirks.vblank),
+            (self.logic, &mut quirks.logic),
+        ];
+
+        for (value, quirk) in fields {
+            if let Some(value) = value {
+                *quirk = value;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+/// Everything the emulator needs to know to run a particular ROM.
+#[derive(Debug, Clone)]
+pub struct RomSettings {
+    pub title: Option<String>,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub instructions_per_frame: u32,
+    /// Background and foreground colour, in that order.
+    pub palette: Option<[(u8, u8, u8); 2]>,
+    /// Database key hints, e.g. `"up" => 0x5`.
+    pub keys: HashMap<String, u8>,
+}
+
+impl Default for RomSettings {
+    fn default() -> Self {
+        RomSettings {
+            title: None,
+            platform: Platform::ModernChip8,
+            quirks: Quirks::default(),
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            palette: None,
+            keys: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RomDatabase {
+    entries: HashMap<String, (String, RomEntry)>,
+}
+
+impl RomDatabase {
+    /// Loads the bundled database plus the user database if one exists.
+    pub fn load() -> Result<RomDatabase, Box<dyn Error>> {
+        let mut db = RomDatabase::default();
+        db.extend_from_str(BUNDLED_PROGRAMS)?;
+
+        if Path::new(USER_DATABASE_PATH).exists() {
+            db.extend_from_file(USER_DATABASE_PATH)?;
+        }
+
+        Ok(db)
+    }
+
+    pub fn extend_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        self.extend_from_str(&contents)
+    }
+
+    pub fn extend_from_str(&mut self, contents: &str) -> Result<(), Box<dyn Error>> {
+        let programs: Vec<Program> = serde_json::from_str(contents)?;
+
+        for program in programs {
+            for (hash, rom) in program.roms {
+                self.entries
+                    .insert(hash.to_ascii_lowercase(), (program.title.clone(), rom));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn title(&self, rom: &[u8]) -> Option<&str> {
+        self.entries
+            .get(&sha1_hex(rom))
+            .map(|(title, _)| title.as_str())
+    }
+
+    /// Picks settings for `rom`, falling back to the defaults for unknown ROMs.
+    pub fn lookup(&self, rom: &[u8]) -> RomSettings {
+        let mut settings = RomSettings::default();
+
+        let (title, entry) = match self.entries.get(&sha1_hex(rom)) {
+            Some(found) => found,
+            None => return settings,
+        };
+
+        settings.title = Some(title.clone());
+
+        if let Some((id, platform)) = entry
+            .platforms
+            .iter()
+            .find_map(|id| Platform::from_id(id).map(|platform| (id, platform)))
+        {
+            settings.platform = platform;
+            settings.quirks = platform.quirks();
+
+            if let Some(overrides) = entry.quirky_platforms.get(id) {
+                overrides.apply(&mut settings.quirks);
+            }
+        }
+
+        if let Some(tickrate) = entry.tickrate {
+            settings.instructions_per_frame = tickrate;
+        }
+
+        if let Some(colors) = &entry.colors {
+            let pixels: Vec<_> = colors
+                .pixels
+                .iter()
+                .filter_map(|c| parse_color(c))
+                .collect();
+            if pixels.len() >= 2 {
+                settings.palette = Some([pixels[0], pixels[1]]);
+            }
+        }
+
+        settings.keys = entry.keys.clone();
+
+        settings
+    }
+}
+
+pub fn sha1_hex(rom: &[u8]) -> String {
+    Sha1::digest(rom)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn parse_color(text: &str) -> Option<(u8, u8, u8)> {
+    let hex = text.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    Some(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+}
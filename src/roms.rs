@@ -0,0 +1,9 @@
+/// ROMs compiled into the binary, selectable as `builtin:<name>`.
+pub const EMBEDDED: &[(&str, &[u8])] = &[("test_opcode", include_bytes!("../test_opcode.ch8"))];
+
+pub fn embedded(name: &str) -> Option<&'static [u8]> {
+    EMBEDDED
+        .iter()
+        .find(|(embedded_name, _)| *embedded_name == name)
+        .map(|(_, rom)| *rom)
+}
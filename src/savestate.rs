@@ -0,0 +1,248 @@
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use crate::chip::{Chip, Fault, Platform, Quirks};
+use crate::keypad::Keypad;
+use crate::megachip::{self, MegaChip};
+use crate::timing::Timing;
+
+/// Bumped whenever the format changes, so old states are refused rather
+/// than misread.
+const VERSION: u32 = 1;
+
+/// Everything a program or the frontend can see of a machine, plus where
+/// `CXNN`'s numbers have got to, so a loaded state carries on exactly as
+/// the saved one would have. Symbols, profiling, coverage and tracing are
+/// tools around the machine and aren't saved.
+#[derive(Serialize, Deserialize)]
+struct SaveState {
+    version: u32,
+    platform: Platform,
+    quirks: Quirks,
+    timing: Timing,
+    load_address: u16,
+    #[serde(with = "hex")]
+    memory: Vec<u8>,
+    registers: [u8; 16],
+    index: u32,
+    pc: u16,
+    sp: u8,
+    stack: [u16; 16],
+    delay_timer: u8,
+    sound_timer: u8,
+    keypad: Keypad,
+    extra_keypad: Keypad,
+    #[serde(with = "hex")]
+    video: Vec<u8>,
+    video_width: usize,
+    video_height: usize,
+    background: usize,
+    #[serde(with = "hex")]
+    colors: Vec<u8>,
+    opcode: u16,
+    fault: Option<Fault>,
+    mega: Option<MegaChip>,
+    rom_len: usize,
+    beep_phase: u32,
+    vip_overrun: u32,
+    rng_seed: [u8; 32],
+    rng_stream: u64,
+    rng_word: u128,
+}
+
+/// Saves `chip` as bytes that `load` turns back into the same machine, in
+/// this process or another. Machines running an emulated VIP can't be
+/// saved.
+pub fn save(chip: &Chip) -> Result<Vec<u8>, Box<dyn Error>> {
+    if chip.vip.is_some() {
+        return Err("can't save a machine running an emulated VIP".into());
+    }
+
+    let state = SaveState {
+        version: VERSION,
+        platform: chip.platform,
+        quirks: chip.quirks,
+        timing: chip.timing,
+        load_address: chip.load_address,
+        memory: chip.memory().to_vec(),
+        registers: chip.registers,
+        index: chip.index,
+        pc: chip.pc,
+        sp: chip.sp,
+        stack: chip.stack,
+        delay_timer: chip.delay_timer,
+        sound_timer: chip.sound_timer,
+        keypad: chip.keypad.clone(),
+        extra_keypad: chip.extra_keypad.clone(),
+        video: chip.video.clone(),
+        video_width: chip.video_width,
+        video_height: chip.video_height,
+        background: chip.background,
+        colors: chip.colors.clone(),
+        opcode: chip.opcode,
+        fault: chip.fault,
+        mega: chip.mega.as_deref().cloned(),
+        rom_len: chip.rom_len,
+        beep_phase: chip.beep_phase,
+        vip_overrun: chip.vip_overrun,
+        rng_seed: chip.rng.get_seed(),
+        rng_stream: chip.rng.get_stream(),
+        rng_word: chip.rng.get_word_pos(),
+    };
+    Ok(serde_json::to_vec(&state)?)
+}
+
+/// Rebuilds a machine from bytes made by `save`, refusing any that don't
+/// describe a machine this build could have saved.
+pub fn load(bytes: &[u8]) -> Result<Chip, Box<dyn Error>> {
+    let state: SaveState = serde_json::from_slice(bytes)?;
+    if state.version != VERSION {
+        return Err(format!(
+            "save state is version {}, this build reads version {}",
+            state.version, VERSION
+        )
+        .into());
+    }
+
+    let pixels = state.video_width * state.video_height;
+    let mega_pixels = megachip::SCREEN_WIDTH * megachip::SCREEN_HEIGHT;
+    let consistent = state.memory.len() == state.platform.memory_size()
+        && (state.load_address as usize) + state.rom_len <= state.memory.len()
+        && state.sp as usize <= state.stack.len()
+        && pixels > 0
+        && state.video.len() == pixels
+        && state.video_width.is_multiple_of(8)
+        && state.colors.len() == pixels / 8
+        && state.colors.iter().all(|color| *color < 8)
+        && state.background < 4
+        && match &state.mega {
+            Some(mega) => mega.is_consistent() && (!mega.enabled || pixels == mega_pixels),
+            None => state.platform != Platform::MegaChip,
+        };
+    if !consistent {
+        return Err("save state is corrupt".into());
+    }
+
+    let mut chip = Chip::with_platform(state.platform);
+    chip.quirks = state.quirks;
+    chip.timing = state.timing;
+    chip.load_address = state.load_address;
+    chip.memory = state.memory;
+    chip.registers = state.registers;
+    chip.index = state.index;
+    chip.pc = state.pc;
+    chip.sp = state.sp;
+    chip.stack = state.stack;
+    chip.delay_timer = state.delay_timer;
+    chip.sound_timer = state.sound_timer;
+    chip.keypad = state.keypad;
+    chip.extra_keypad = state.extra_keypad;
+    chip.video = state.video;
+    chip.video_width = state.video_width;
+    chip.video_height = state.video_height;
+    chip.background = state.background;
+    chip.colors = state.colors;
+    chip.opcode = state.opcode;
+    chip.fault = state.fault;
+    chip.mega = state.mega.map(Box::new);
+    chip.rom_len = state.rom_len;
+    chip.beep_phase = state.beep_phase;
+    chip.vip_overrun = state.vip_overrun;
+    chip.rng = ChaCha12Rng::from_seed(state.rng_seed);
+    chip.rng.set_stream(state.rng_stream);
+    chip.rng.set_word_pos(state.rng_word);
+    Ok(chip)
+}
+
+/// Saves byte buffers as hex strings rather than arrays of numbers, which
+/// would be several times the size.
+mod hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        let mut text = String::with_capacity(bytes.len() * 2);
+        for byte in bytes {
+            text.push(DIGITS[(byte >> 4) as usize] as char);
+            text.push(DIGITS[(byte & 0xF) as usize] as char);
+        }
+        serializer.serialize_str(&text)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        if !text.len().is_multiple_of(2) {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        (0..text.len())
+            .step_by(2)
+            .map(|i| {
+                text.get(i..i + 2)
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                    .ok_or_else(|| D::Error::custom("bad hex byte"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Draws random sprites forever, so any difference in memory, the
+    /// random numbers or the display shows up.
+    const ROM: &[u8] = &[
+        0xC0, 0x3F, // 200: V0 = random & 3F
+        0xC1, 0x1F, // 202: V1 = random & 1F
+        0xC2, 0x0F, // 204: V2 = random & 0F
+        0xF2, 0x29, // 206: I = glyph V2
+        0xD0, 0x15, // 208: draw it at V0, V1
+        0xA3, 0x00, // 20A: I = 300
+        0xF2, 0x55, // 20C: store V0-V2
+        0x12, 0x00, // 20E: loop
+    ];
+
+    fn assert_same(a: &Chip, b: &Chip) {
+        assert_eq!(a.memory(), b.memory());
+        assert_eq!(a.registers, b.registers);
+        assert_eq!(
+            (a.index, a.pc, a.sp, a.stack),
+            (b.index, b.pc, b.sp, b.stack)
+        );
+        assert_eq!(a.video, b.video);
+    }
+
+    #[test]
+    fn loaded_states_carry_on_as_the_saved_machine() {
+        for platform in [Platform::ModernChip8, Platform::Chip8X, Platform::MegaChip] {
+            let mut chip = Chip::with_platform(platform);
+            chip.load_bytes(ROM).unwrap();
+            chip.run_frame_until(50, |_| false);
+            chip.keypad.hold(0b101);
+
+            let mut loaded = load(&save(&chip).unwrap()).unwrap();
+            assert_same(&chip, &loaded);
+            assert!(loaded.keypad.is_down(2));
+            for _ in 0..20 {
+                chip.run_frame_until(50, |_| false);
+                loaded.run_frame_until(50, |_| false);
+            }
+            assert_same(&chip, &loaded);
+        }
+    }
+
+    #[test]
+    fn refuses_corrupt_states() {
+        let mut chip = Chip::new();
+        chip.load_bytes(ROM).unwrap();
+        let saved = String::from_utf8(save(&chip).unwrap()).unwrap();
+
+        assert!(load(b"not a state").is_err());
+        assert!(load(saved.replace("\"version\":1", "\"version\":99").as_bytes()).is_err());
+        let short_video = saved.replace("\"video_height\":32", "\"video_height\":64");
+        assert!(load(short_video.as_bytes()).is_err());
+    }
+}
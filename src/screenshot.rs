@@ -0,0 +1,187 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chip::Chip;
+
+/// Background and foreground used where no palette is given.
+pub const DEFAULT_COLORS: [(u8, u8, u8); 2] = [(0, 0, 0), (255, 255, 255)];
+
+/// Saves the display as PNG in white on black, blown up `scale` times, if
+/// `path` ends in `.png`, and as PBM otherwise.
+pub fn save<P: AsRef<Path>>(chip: &Chip, path: P, scale: usize) -> Result<(), Box<dyn Error>> {
+    let path = path.as_ref();
+    let png = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+
+    match (png, scale) {
+        (true, _) => Image::capture(chip, DEFAULT_COLORS)
+            .scaled(scale)
+            .save_png(path),
+        (false, 1) => Ok(save_pbm(chip, path)?),
+        (false, _) => Err("only PNG screenshots can be scaled".into()),
+    }
+}
+
+/// Writes the display as a binary PBM image, one image pixel per CHIP-8
+/// pixel, lit pixels black.
+fn save_pbm<P: AsRef<Path>>(chip: &Chip, path: P) -> io::Result<()> {
+    let mut contents = format!("P4\n{} {}\n", chip.video_width, chip.video_height).into_bytes();
+
+    for row in chip.video.chunks(chip.video_width) {
+        for pixels in row.chunks(8) {
+            let byte = pixels
+                .iter()
+                .enumerate()
+                .filter(|(_, pixel)| **pixel != 0)
+                .fold(0u8, |byte, (i, _)| byte | 0x80 >> i);
+            contents.push(byte);
+        }
+    }
+
+    fs::write(path, contents)
+}
+
+/// An 8-bit RGB image of the display.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// Red, green and blue of each pixel, row by row.
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    /// The display at one image pixel per CHIP-8 pixel. Monochrome
+    /// platforms are drawn in `palette`'s background and foreground, the
+    /// others in their own colours.
+    pub fn capture(chip: &Chip, palette: [(u8, u8, u8); 2]) -> Image {
+        let colors: Vec<(u8, u8, u8)> = match chip.mega.as_ref().filter(|mega| mega.enabled) {
+            Some(mega) => mega
+                .frame
+                .iter()
+                .map(|argb| ((argb >> 16) as u8, (argb >> 8) as u8, *argb as u8))
+                .collect(),
+            None => {
+                let background = chip.background_color().unwrap_or(palette[0]);
+                chip.video
+                    .iter()
+                    .enumerate()
+                    .map(|(pixel, value)| match value {
+                        0 => background,
+                        _ => chip.foreground_color(pixel).unwrap_or(palette[1]),
+                    })
+                    .collect()
+            }
+        };
+
+        Image {
+            width: chip.video_width,
+            height: chip.video_height,
+            pixels: colors.iter().flat_map(|(r, g, b)| [*r, *g, *b]).collect(),
+        }
+    }
+
+    /// The image blown up `scale` times with square pixels.
+    pub fn scaled(&self, scale: usize) -> Image {
+        let width = self.width * scale;
+        let mut pixels = Vec::with_capacity(width * self.height * scale * 3);
+
+        for row in self.pixels.chunks(self.width * 3) {
+            let line: Vec<u8> = row.chunks(3).flat_map(|rgb| rgb.repeat(scale)).collect();
+            for _ in 0..scale {
+                pixels.extend_from_slice(&line);
+            }
+        }
+
+        Image {
+            width,
+            height: self.height * scale,
+            pixels,
+        }
+    }
+
+    /// The image stretched or squeezed to `width` by `height`, taking the
+    /// nearest pixel.
+    pub fn resized(&self, width: usize, height: usize) -> Image {
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let from = (y * self.height / height * self.width + x * self.width / width) * 3;
+                pixels.extend_from_slice(&self.pixels[from..from + 3]);
+            }
+        }
+
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        Ok(())
+    }
+}
+
+/// `<rom>-<YYYYMMDD>-<HHMMSS>-<mmm>` in the current directory, for naming
+/// files saved together. `rom` is the ROM's file name without its
+/// extension and the time is UTC to the millisecond, so stems taken a frame
+/// or more apart never clash.
+pub fn auto_stem(rom: &str) -> String {
+    let name = match rom {
+        "-" => "stdin",
+        rom => rom.strip_prefix("builtin:").unwrap_or(rom),
+    };
+    let stem = Path::new(name)
+        .file_stem()
+        .map_or("screenshot".into(), |stem| stem.to_string_lossy());
+
+    format!("{}-{}", stem, timestamp())
+}
+
+/// The current UTC time as `YYYYMMDD-HHMMSS-mmm`.
+fn timestamp() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    let seconds = millis / 1000;
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        millis % 1000
+    )
+}
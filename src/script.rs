@@ -0,0 +1,332 @@
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, Position, AST};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::chip::{Chip, MEMORY_SIZE};
+use crate::keypad::KeyEvent;
+use crate::screenshot;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// A Rhai script driving the emulator. The script runs from the top each
+/// time a ROM starts and registers hooks for later:
+///
+/// - `on_frame(f)`: call `f()` after every frame.
+/// - `on_address(addr, f)`: call `f()` before the instruction at `addr`,
+///   which may also be a label.
+///
+/// Both take a closure or a `Fn("name")` pointer. Inside the script:
+///
+/// - `pc()`, `i()`, `sp()`, `v(n)`, `delay_timer()` and `sound_timer()`
+///   read registers, `set_pc(x)`, `set_i(x)`, `set_v(n, x)`,
+///   `set_delay_timer(x)` and `set_sound_timer(x)` write them.
+/// - `peek(addr)`, `poke(addr, byte)` and `pixel(x, y)` access memory and
+///   the display. `address(label)` looks up a label.
+/// - `press(key)`, `release(key)` and `is_pressed(key)` drive keys 0-F.
+///   `was_pressed(key)` and `was_released(key)` tell whether the key went
+///   down or came up during the last frame.
+/// - `frame()` counts frames since the ROM started.
+/// - `screenshot(path)` saves the display as a PNG image if `path` ends in
+///   `.png` and as PBM otherwise. `screenshot(path, scale)` saves a PNG
+///   blown up `scale` times.
+/// - `assert(condition, message)` stops the emulator with an error.
+/// - `quit(code)` stops the emulator with an exit status.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    state: Rc<RefCell<State>>,
+    error: Option<String>,
+}
+
+/// What the registered functions share. The machine is swapped in for the
+/// duration of each call into the script.
+struct State {
+    chip: Chip,
+    frame: u64,
+    frame_hooks: Vec<FnPtr>,
+    address_hooks: BTreeMap<u16, Vec<FnPtr>>,
+    exit_code: Option<i32>,
+}
+
+impl Script {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Script, Box<dyn Error>> {
+        let state = Rc::new(RefCell::new(State {
+            chip: Chip::new(),
+            frame: 0,
+            frame_hooks: Vec::new(),
+            address_hooks: BTreeMap::new(),
+            exit_code: None,
+        }));
+
+        let mut engine = Engine::new();
+        register_api(&mut engine, &state);
+        let ast = engine.compile_file(path.as_ref().to_path_buf())?;
+
+        Ok(Script {
+            engine,
+            ast,
+            state,
+            error: None,
+        })
+    }
+
+    /// Forgets the hooks of the previous run and runs the script from the
+    /// top against a newly started ROM.
+    pub fn start(&mut self, chip: &mut Chip) -> Result<(), Box<dyn Error>> {
+        {
+            let mut state = self.state.borrow_mut();
+            state.frame = 0;
+            state.frame_hooks.clear();
+            state.address_hooks.clear();
+        }
+
+        self.call(chip, |engine, ast| engine.run_ast(ast))
+    }
+
+    /// Runs the hooks registered for `chip.pc`. Errors are kept until
+    /// `end_frame`, so this can be called from inside a frame.
+    pub fn on_instruction(&mut self, chip: &mut Chip) {
+        if self.error.is_some() || self.exit_code().is_some() {
+            return;
+        }
+
+        let hooks = match self.state.borrow().address_hooks.get(&chip.pc) {
+            Some(hooks) => hooks.clone(),
+            None => return,
+        };
+
+        for hook in hooks {
+            if let Err(err) = self.call(chip, |engine, ast| hook.call::<Dynamic>(engine, ast, ())) {
+                self.error = Some(err.to_string());
+                return;
+            }
+        }
+    }
+
+    /// Runs the frame hooks, reporting any error raised during the frame.
+    pub fn end_frame(&mut self, chip: &mut Chip) -> Result<(), Box<dyn Error>> {
+        if let Some(err) = self.error.take() {
+            return Err(err.into());
+        }
+
+        let hooks = {
+            let mut state = self.state.borrow_mut();
+            state.frame += 1;
+            state.frame_hooks.clone()
+        };
+
+        for hook in hooks {
+            if self.exit_code().is_some() {
+                break;
+            }
+            self.call(chip, |engine, ast| hook.call::<Dynamic>(engine, ast, ()))?;
+        }
+
+        Ok(())
+    }
+
+    /// The status passed to `quit`, once the script has called it.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.state.borrow().exit_code
+    }
+
+    fn call<T>(
+        &mut self,
+        chip: &mut Chip,
+        f: impl FnOnce(&Engine, &AST) -> ScriptResult<T>,
+    ) -> Result<(), Box<dyn Error>> {
+        std::mem::swap(chip, &mut self.state.borrow_mut().chip);
+        let result = f(&self.engine, &self.ast);
+        std::mem::swap(chip, &mut self.state.borrow_mut().chip);
+
+        match result.map_err(|err| *err) {
+            Ok(_) | Err(EvalAltResult::ErrorTerminated(..)) => Ok(()),
+            Err(err) => Err(format!("script: {}", err).into()),
+        }
+    }
+}
+
+fn register_api(engine: &mut Engine, state: &Rc<RefCell<State>>) {
+    let s = state.clone();
+    engine.register_fn("on_frame", move |hook: FnPtr| {
+        s.borrow_mut().frame_hooks.push(hook);
+    });
+    let s = state.clone();
+    engine.register_fn(
+        "on_address",
+        move |address: i64, hook: FnPtr| -> ScriptResult<()> {
+            let address = index(address, MEMORY_SIZE, "address")? as u16;
+            s.borrow_mut()
+                .address_hooks
+                .entry(address)
+                .or_default()
+                .push(hook);
+            Ok(())
+        },
+    );
+    let s = state.clone();
+    engine.register_fn(
+        "on_address",
+        move |label: &str, hook: FnPtr| -> ScriptResult<()> {
+            let address = resolve(&s, label)?;
+            s.borrow_mut()
+                .address_hooks
+                .entry(address)
+                .or_default()
+                .push(hook);
+            Ok(())
+        },
+    );
+
+    let s = state.clone();
+    engine.register_fn("pc", move || s.borrow().chip.pc as i64);
+    let s = state.clone();
+    engine.register_fn("set_pc", move |value: i64| -> ScriptResult<()> {
+        s.borrow_mut().chip.pc = index(value, MEMORY_SIZE, "address")? as u16;
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("i", move || s.borrow().chip.index as i64);
+    let s = state.clone();
+    engine.register_fn("set_i", move |value: i64| {
+        s.borrow_mut().chip.index = value as u32;
+    });
+    let s = state.clone();
+    engine.register_fn("sp", move || s.borrow().chip.sp as i64);
+    let s = state.clone();
+    engine.register_fn("v", move |register: i64| -> ScriptResult<i64> {
+        Ok(s.borrow().chip.registers[index(register, 16, "register")?] as i64)
+    });
+    let s = state.clone();
+    engine.register_fn(
+        "set_v",
+        move |register: i64, value: i64| -> ScriptResult<()> {
+            s.borrow_mut().chip.registers[index(register, 16, "register")?] = value as u8;
+            Ok(())
+        },
+    );
+    let s = state.clone();
+    engine.register_fn("delay_timer", move || s.borrow().chip.delay_timer as i64);
+    let s = state.clone();
+    engine.register_fn("set_delay_timer", move |value: i64| {
+        s.borrow_mut().chip.delay_timer = value as u8;
+    });
+    let s = state.clone();
+    engine.register_fn("sound_timer", move || s.borrow().chip.sound_timer as i64);
+    let s = state.clone();
+    engine.register_fn("set_sound_timer", move |value: i64| {
+        s.borrow_mut().chip.sound_timer = value as u8;
+    });
+
+    let s = state.clone();
+    engine.register_fn("peek", move |address: i64| -> ScriptResult<i64> {
+        Ok(s.borrow().chip.memory()[index(address, MEMORY_SIZE, "address")?] as i64)
+    });
+    let s = state.clone();
+    engine.register_fn(
+        "poke",
+        move |address: i64, value: i64| -> ScriptResult<()> {
+            let address = index(address, MEMORY_SIZE, "address")? as u16;
+            s.borrow_mut().chip.write_memory(address, &[value as u8]);
+            Ok(())
+        },
+    );
+    let s = state.clone();
+    engine.register_fn("pixel", move |x: i64, y: i64| -> ScriptResult<bool> {
+        let chip = &s.borrow().chip;
+        let x = index(x, chip.video_width, "x")?;
+        let y = index(y, chip.video_height, "y")?;
+        Ok(chip.video[y * chip.video_width + x] != 0)
+    });
+    let s = state.clone();
+    engine.register_fn("address", move |label: &str| -> ScriptResult<i64> {
+        resolve(&s, label).map(|address| address as i64)
+    });
+
+    let s = state.clone();
+    engine.register_fn("press", move |key: i64| -> ScriptResult<()> {
+        let key = index(key, 16, "key")? as u8;
+        s.borrow_mut().chip.keypad.apply(KeyEvent::Press(key));
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("release", move |key: i64| -> ScriptResult<()> {
+        let key = index(key, 16, "key")? as u8;
+        s.borrow_mut().chip.keypad.apply(KeyEvent::Release(key));
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("is_pressed", move |key: i64| -> ScriptResult<bool> {
+        Ok(s.borrow().chip.keypad.is_down(index(key, 16, "key")? as u8))
+    });
+    let s = state.clone();
+    engine.register_fn("was_pressed", move |key: i64| -> ScriptResult<bool> {
+        Ok(s.borrow()
+            .chip
+            .keypad
+            .was_pressed(index(key, 16, "key")? as u8))
+    });
+    let s = state.clone();
+    engine.register_fn("was_released", move |key: i64| -> ScriptResult<bool> {
+        Ok(s.borrow()
+            .chip
+            .keypad
+            .was_released(index(key, 16, "key")? as u8))
+    });
+    let s = state.clone();
+    engine.register_fn("frame", move || s.borrow().frame as i64);
+
+    let s = state.clone();
+    engine.register_fn("screenshot", move |path: &str| -> ScriptResult<()> {
+        screenshot::save(&s.borrow().chip, path, 1).map_err(|err| runtime_error(err.to_string()))
+    });
+    let s = state.clone();
+    engine.register_fn(
+        "screenshot",
+        move |path: &str, scale: i64| -> ScriptResult<()> {
+            if !(1..=64).contains(&scale) {
+                return Err(runtime_error(format!("scale {} out of range", scale)));
+            }
+            screenshot::save(&s.borrow().chip, path, scale as usize)
+                .map_err(|err| runtime_error(err.to_string()))
+        },
+    );
+    engine.register_fn(
+        "assert",
+        |condition: bool, message: &str| -> ScriptResult<()> {
+            if condition {
+                Ok(())
+            } else {
+                Err(runtime_error(format!("assertion failed: {}", message)))
+            }
+        },
+    );
+    let s = state.clone();
+    engine.register_fn("quit", move |code: i64| -> ScriptResult<()> {
+        s.borrow_mut().exit_code = Some(code as i32);
+        Err(EvalAltResult::ErrorTerminated(Dynamic::from(code), Position::NONE).into())
+    });
+}
+
+fn index(value: i64, len: usize, what: &str) -> ScriptResult<usize> {
+    usize::try_from(value)
+        .ok()
+        .filter(|value| *value < len)
+        .ok_or_else(|| runtime_error(format!("{} {} out of range", what, value)))
+}
+
+fn resolve(state: &Rc<RefCell<State>>, label: &str) -> ScriptResult<u16> {
+    state
+        .borrow()
+        .chip
+        .symbols
+        .resolve(label)
+        .ok_or_else(|| runtime_error(format!("unknown label '{}'", label)))
+}
+
+fn runtime_error(message: String) -> Box<EvalAltResult> {
+    EvalAltResult::ErrorRuntime(message.into(), Position::NONE).into()
+}
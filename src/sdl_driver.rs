@@ -1,23 +1,53 @@
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
 use sdl2::render::WindowCanvas;
-use sdl2::Sdl;
+use sdl2::{Sdl, TimerSubsystem};
 use std::error::Error;
+use std::path::PathBuf;
 
 use crate::{
-    chip::{Chip, VIDEO_WIDTH},
-    keyboard::key2btn,
+    chip::{Chip, AUDIO_RATE},
+    keyboard::KeyMap,
+    keypad::{KeyEvent, Keypad},
+    launcher::Launcher,
+    memview::MemoryViewer,
+    overlay::{draw_debug_panel, draw_memory_panel},
+    screenshot::Image,
+    text::{draw_text, GLYPH_HEIGHT},
 };
 
+/// Game scale while the debug panel takes up the right side of the window.
+const DEBUG_SCALE: u32 = 9;
+
+pub const DEFAULT_PALETTE: [Color; 2] = [Color::RGB(0, 0, 0), Color::RGB(255, 255, 255)];
+
+/// Window events the frontend cares about besides CHIP-8 keypad input.
+pub enum UiEvent {
+    Quit,
+    KeyDown(Keycode),
+    DropFile(PathBuf),
+}
+
 pub struct SdlDriver {
     pub context: Sdl,
     pub canvas: WindowCanvas,
+    pub keymap: KeyMap,
+    pub palette: [Color; 2],
+    pub debug_overlay: bool,
+    pub memory_viewer: MemoryViewer,
+    held: [u8; 32],
+    /// `None` where there's no audio device.
+    audio: Option<AudioQueue<u8>>,
+    timer: TimerSubsystem,
+    /// SDL ticks at the previous `process_input`.
+    last_poll: u32,
 }
 
 impl SdlDriver {
-    pub fn new() -> Result<SdlDriver, Box<dyn Error>> {
+    pub fn new(keymap: KeyMap) -> Result<SdlDriver, Box<dyn Error>> {
         let sdl_context = sdl2::init()?;
         let video_subsystem = sdl_context.video()?;
 
@@ -32,31 +62,214 @@ impl SdlDriver {
             .build()?;
 
         let canvas = window.into_canvas().accelerated().present_vsync().build()?;
+        let timer = sdl_context.timer()?;
+
+        let spec = AudioSpecDesired {
+            freq: Some(AUDIO_RATE as i32),
+            channels: Some(1),
+            samples: None,
+        };
+        let audio = sdl_context
+            .audio()
+            .and_then(|audio| audio.open_queue(None, &spec))
+            .ok();
+        if let Some(audio) = &audio {
+            audio.resume();
+        }
 
         Ok(SdlDriver {
             context: sdl_context,
             canvas,
+            keymap,
+            palette: DEFAULT_PALETTE,
+            debug_overlay: false,
+            memory_viewer: MemoryViewer::new(),
+            held: [0; 32],
+            audio,
+            last_poll: timer.ticks(),
+            timer,
         })
     }
 
     pub fn render(&mut self, chip: &mut Chip, scale: u32) {
-        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        self.memory_viewer.update(chip);
+
+        let scale = self.display_scale(chip, scale);
+
+        match chip.mega.as_ref().filter(|mega| mega.enabled) {
+            Some(mega) => self.draw_frame(&mega.frame, chip.video_width, scale),
+            None => {
+                let background = chip.background_color().map_or(self.palette[0], Color::from);
+                let palette = self.palette;
+                self.draw_video(&chip.video, chip.video_width, scale, background, |pixel| {
+                    chip.foreground_color(pixel).map_or(palette[1], Color::from)
+                });
+            }
+        }
+
+        if self.debug_overlay {
+            let panel_x = (chip.video_width as u32 * scale) as i32 + 10;
+            self.canvas.set_draw_color(self.palette[1]);
+            let (_, window_height) = self.canvas.output_size().unwrap();
+            self.canvas
+                .draw_line((panel_x - 5, 0), (panel_x - 5, window_height as i32))
+                .unwrap();
+            draw_debug_panel(&mut self.canvas, panel_x, 10, chip, self.palette[1]);
+        }
+
+        if self.memory_viewer.open {
+            let panel_y = (chip.video_height as u32 * scale) as i32 + 10;
+            draw_memory_panel(
+                &mut self.canvas,
+                10,
+                panel_y,
+                chip,
+                &self.memory_viewer,
+                self.palette[1],
+            );
+        }
+
+        self.canvas.present();
+    }
+
+    /// The size of a CHIP-8 pixel on screen when `render` is asked for
+    /// `scale`.
+    pub fn display_scale(&self, chip: &Chip, scale: u32) -> u32 {
+        let scale = if self.debug_overlay || self.memory_viewer.open {
+            scale.min(DEBUG_SCALE)
+        } else {
+            scale
+        };
+        window_scale(chip, scale)
+    }
+
+    /// The display at native resolution in the active palette.
+    pub fn screenshot(&self, chip: &Chip) -> Image {
+        Image::capture(chip, self.palette.map(|color| color.rgb()))
+    }
+
+    /// Draws `video`, `width` pixels to a row, with lit pixels in the colour
+    /// `foreground` gives for their index.
+    fn draw_video(
+        &mut self,
+        video: &[u8],
+        width: usize,
+        scale: u32,
+        background: Color,
+        foreground: impl Fn(usize) -> Color,
+    ) {
+        self.canvas.set_draw_color(background);
         self.canvas.clear();
-        self.canvas.set_draw_color(Color::RGB(255, 255, 255));
-        for (i, value) in chip.video.iter().enumerate() {
+        for (i, value) in video.iter().enumerate() {
             if *value != 0 {
-                let x = (i % VIDEO_WIDTH as usize) as u32;
-                let y = (i / VIDEO_WIDTH as usize) as u32;
+                let x = (i % width) as u32;
+                let y = (i / width) as u32;
 
+                self.canvas.set_draw_color(foreground(i));
                 let rect = Rect::new((x * scale) as i32, (y * scale) as i32, scale, scale);
                 self.canvas.fill_rect(rect).unwrap();
             }
         }
+    }
+
+    /// Draws full-colour ARGB pixels, `width` to a row, through a texture.
+    fn draw_frame(&mut self, frame: &[u32], width: usize, scale: u32) {
+        let height = frame.len() / width;
+        let creator = self.canvas.texture_creator();
+        let mut texture = creator
+            .create_texture_streaming(PixelFormatEnum::ARGB8888, width as u32, height as u32)
+            .unwrap();
+        let pixels: Vec<u8> = frame.iter().flat_map(|pixel| pixel.to_ne_bytes()).collect();
+        texture.update(None, &pixels, width * 4).unwrap();
+
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+        let target = Rect::new(0, 0, width as u32 * scale, height as u32 * scale);
+        self.canvas.copy(&texture, None, target).unwrap();
+    }
+
+    /// Queues a frame of `Chip::audio_frame` samples.
+    pub fn play_sound(&mut self, samples: &[u8]) {
+        let Some(audio) = &self.audio else {
+            return;
+        };
+
+        // Drop the frame rather than let latency build up behind a slow
+        // device.
+        if audio.size() < AUDIO_RATE / 10 {
+            if let Err(err) = audio.queue_audio(samples) {
+                eprintln!("Could not queue audio: {}", err);
+            }
+        }
+    }
+
+    pub fn render_launcher(&mut self, launcher: &Launcher) {
+        let scale = 3;
+        let line_height = (GLYPH_HEIGHT * scale + 4) as i32;
+        let (_, window_height) = self.canvas.output_size().unwrap();
+        let visible = (window_height as i32 / line_height - 3).max(1) as usize;
+        let first = launcher.selected.saturating_sub(visible / 2);
+
+        self.canvas.set_draw_color(self.palette[0]);
+        self.canvas.clear();
+
+        let foreground = self.palette[1];
+        draw_text(
+            &mut self.canvas,
+            10,
+            10,
+            scale,
+            foreground,
+            "UP/DOWN + ENTER TO PLAY, OR DROP A ROM ON THE WINDOW",
+        );
+
+        if launcher.entries.is_empty() {
+            draw_text(
+                &mut self.canvas,
+                10,
+                10 + 2 * line_height,
+                scale,
+                foreground,
+                "NO ROMS FOUND",
+            );
+        }
+
+        for (row, (i, entry)) in launcher
+            .entries
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(visible)
+            .enumerate()
+        {
+            let marker = if i == launcher.selected { ">" } else { " " };
+            let section = if entry.recent { "RECENT" } else { "      " };
+            let line = format!("{} {} {}", marker, section, entry.title);
+
+            let y = 10 + (row as i32 + 2) * line_height;
+            draw_text(&mut self.canvas, 10, y, scale, foreground, &line);
+        }
+
         self.canvas.present();
     }
 
-    pub fn process_input(&self, keys: &mut [u8; 16]) -> bool {
-        let mut quit: bool = false;
+    /// Queues keypad input on `keypad`, or `extra_keypad` for buttons bound
+    /// to the second keypad, and returns the other window events. Each key
+    /// event is placed as far into the coming frame as it happened into the
+    /// time since the previous call.
+    pub fn process_input(
+        &mut self,
+        keypad: &mut Keypad,
+        extra_keypad: &mut Keypad,
+    ) -> Vec<UiEvent> {
+        let mut events = Vec::new();
+
+        let now = self.timer.ticks();
+        let since = self.last_poll;
+        self.last_poll = now;
+        let at = |timestamp: u32| {
+            timestamp.saturating_sub(since) as f32 / now.saturating_sub(since).max(1) as f32
+        };
 
         let mut event_pump = self.context.event_pump().unwrap();
         for event in event_pump.poll_iter() {
@@ -65,26 +278,69 @@ impl SdlDriver {
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => quit = true,
+                } => events.push(UiEvent::Quit),
+                Event::DropFile { filename, .. } => {
+                    events.push(UiEvent::DropFile(PathBuf::from(filename)))
+                }
                 Event::KeyDown {
-                    keycode: Some(key), ..
+                    timestamp,
+                    keycode,
+                    scancode,
+                    repeat,
+                    ..
                 } => {
-                    if let Some(key) = key2btn(key) {
-                        println!("Key pressed: {}", key);
-                        keys[key] = 1;
+                    if let Some(keycode) = keycode {
+                        events.push(UiEvent::KeyDown(keycode));
+                    }
+
+                    // The memory editor takes over the keyboard while it's open.
+                    if repeat || self.memory_viewer.open {
+                        continue;
+                    }
+
+                    if let Some(key) = self.keymap.key2btn(keycode, scancode) {
+                        self.held[key] += 1;
+                        if self.held[key] == 1 {
+                            let (keypad, key) = match key {
+                                0..16 => (&mut *keypad, key),
+                                _ => (&mut *extra_keypad, key - 16),
+                            };
+                            keypad.queue(at(timestamp), KeyEvent::Press(key as u8));
+                        }
                     }
                 }
                 Event::KeyUp {
-                    keycode: Some(key), ..
+                    timestamp,
+                    keycode,
+                    scancode,
+                    ..
                 } => {
-                    if let Some(key) = key2btn(key) {
-                        println!("Key released: {}", key);
-                        keys[key] = 0;
+                    if let Some(key) = self.keymap.key2btn(keycode, scancode) {
+                        // Several host keys can share a button; only release it
+                        // once the last of them goes up. Keys pressed while the
+                        // memory editor had the keyboard were never held.
+                        if self.held[key] == 0 {
+                            continue;
+                        }
+                        self.held[key] -= 1;
+                        if self.held[key] == 0 {
+                            let (keypad, key) = match key {
+                                0..16 => (&mut *keypad, key),
+                                _ => (&mut *extra_keypad, key - 16),
+                            };
+                            keypad.queue(at(timestamp), KeyEvent::Release(key as u8));
+                        }
                     }
                 }
                 _ => {}
             }
         }
-        quit
+        events
     }
 }
+
+/// The size of a CHIP-8 pixel in a window without panels when asked for
+/// `scale`, shrinking screens taller than 64x32 to fit.
+pub fn window_scale(chip: &Chip, scale: u32) -> u32 {
+    scale.min(scale * 32 / chip.video_height as u32).max(1)
+}
@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Labels for ROM addresses, as written by CHIP-8 assemblers.
+///
+/// Plain text files hold one label per line as `label = address`,
+/// `label: address` or whitespace separated in either order, with `#` or
+/// `;` comments, e.g. `draw_player = 0x2A4`. Addresses are hex. JSON files
+/// map labels to addresses, either at the top level or under a `labels`
+/// key as Octo exports them.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    by_name: HashMap<String, u16>,
+    by_address: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SymbolTable, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        SymbolTable::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<SymbolTable, Box<dyn Error>> {
+        if contents.trim_start().starts_with('{') {
+            return SymbolTable::parse_json(contents);
+        }
+
+        let mut table = SymbolTable::default();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split(['#', ';']).next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = || format!("line {}: expected 'label address'", number + 1);
+
+            let (label, address) = match line.split_once(['=', ':']) {
+                Some((label, address)) => (label.trim(), parse_address(address.trim())),
+                None => match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                    [first, second] => match parse_address(first) {
+                        Some(address) if parse_address(second).is_none() => {
+                            (*second, Some(address))
+                        }
+                        _ => (*first, parse_address(second)),
+                    },
+                    _ => return Err(error().into()),
+                },
+            };
+            let address = address.ok_or_else(error)?;
+
+            table.insert(label, address);
+        }
+
+        Ok(table)
+    }
+
+    fn parse_json(contents: &str) -> Result<SymbolTable, Box<dyn Error>> {
+        let value: serde_json::Value = serde_json::from_str(contents)?;
+        let labels = value.get("labels").unwrap_or(&value);
+        let labels = labels.as_object().ok_or("expected an object of labels")?;
+
+        let mut table = SymbolTable::default();
+        for (label, address) in labels {
+            let address = match address {
+                serde_json::Value::Number(number) => number.as_u64().map(|n| n as u16),
+                serde_json::Value::String(text) => parse_address(text),
+                _ => None,
+            }
+            .ok_or_else(|| format!("bad address for label '{}'", label))?;
+
+            table.insert(label, address);
+        }
+
+        Ok(table)
+    }
+
+    pub fn insert(&mut self, label: &str, address: u16) {
+        self.by_name.insert(label.to_owned(), address);
+        self.by_address
+            .entry(address)
+            .or_insert_with(|| label.to_owned());
+    }
+
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.by_address.get(&address).map(String::as_str)
+    }
+
+    /// Resolves a label, or failing that a hex address such as `2A4`,
+    /// `0x2A4` or `$2A4`.
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        self.by_name
+            .get(text)
+            .copied()
+            .or_else(|| parse_address(text))
+    }
+
+    /// Formats `address` as its label if it has one, as hex otherwise.
+    pub fn format_address(&self, address: u16) -> String {
+        match self.label(address) {
+            Some(label) => label.to_owned(),
+            None => format!("{:03X}", address),
+        }
+    }
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix('$'))
+        .unwrap_or(text);
+
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    u16::from_str_radix(digits, 16).ok()
+}
+
+/// Source line to address mapping for assembled ROMs, one `FILE LINE ADDR`
+/// entry per line with the address in hex, e.g. `game.8o 12 2A4`. Files are
+/// matched by name so the map works wherever the sources live.
+#[derive(Debug, Clone, Default)]
+pub struct LineMap {
+    lines: BTreeMap<(String, u32), u16>,
+    addresses: BTreeMap<u16, (String, u32)>,
+}
+
+impl LineMap {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<LineMap, Box<dyn Error>> {
+        let mut map = LineMap::default();
+
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let entry = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [file, source_line, address] => source_line
+                    .parse::<u32>()
+                    .ok()
+                    .zip(parse_address(address))
+                    .map(|(source_line, address)| (file_name(file), source_line, address)),
+                _ => None,
+            };
+            let (file, source_line, address) =
+                entry.ok_or_else(|| format!("line {}: expected 'FILE LINE ADDR'", number + 1))?;
+
+            map.lines.insert((file.clone(), source_line), address);
+            map.addresses.entry(address).or_insert((file, source_line));
+        }
+
+        Ok(map)
+    }
+
+    /// The address of the first instruction on or after `line` in `file`.
+    pub fn address(&self, file: &str, line: u32) -> Option<u16> {
+        let file = file_name(file);
+        self.lines
+            .range((file.clone(), line)..)
+            .next()
+            .filter(|((found, _), _)| *found == file)
+            .map(|(_, address)| *address)
+    }
+
+    /// The source line of the closest mapped address at or before `address`.
+    pub fn line(&self, address: u16) -> Option<(&str, u32)> {
+        self.addresses
+            .range(..=address)
+            .next_back()
+            .map(|(_, (file, line))| (file.as_str(), *line))
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_owned())
+}
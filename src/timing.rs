@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+/// How much work fits in a 60 Hz frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Timing {
+    /// A fixed number of instructions per frame, whatever they are.
+    #[default]
+    Fixed,
+    /// Instructions cost what they took on a COSMAC VIP, and draws wait for
+    /// the next frame as the VIP interpreter waits for the display
+    /// interrupt.
+    Vip,
+}
+
+/// Microseconds of a 60 Hz frame left to the VIP interpreter once the
+/// CDP1861's display DMA (about 4650 µs) and interrupt routine (about
+/// 210 µs) have taken theirs.
+pub const VIP_FRAME_MICROS: u32 = 16_667 - 4_650 - 210;
+
+/// Approximate VIP execution time of `opcode` in microseconds. `skipped`
+/// says whether a skip instruction took its skip, which costs a little
+/// more. A `DXYN` costs its `vip_draw_cost`, so callers shouldn't add
+/// that again.
+pub fn vip_cost(opcode: u16, skipped: bool) -> u32 {
+    let skip = if skipped { 9 } else { 0 };
+
+    match (opcode & 0xF000, opcode & 0x00FF, opcode & 0x000F) {
+        (0x0000, 0xE0, _) => 109,
+        (0x0000, 0xEE, _) => 105,
+        (0x1000, _, _) | (0x2000, _, _) | (0xB000, _, _) => 105,
+        (0x3000, _, _) | (0x4000, _, _) => 55 + skip,
+        (0x5000, _, _) | (0x9000, _, _) => 73 + skip,
+        (0x6000, _, _) => 27,
+        (0x7000, _, _) => 45,
+        (0x8000, _, _) => 200,
+        (0xA000, _, _) => 55,
+        (0xC000, _, _) => 164,
+        (0xD000, _, rows) => vip_draw_cost(rows),
+        (0xE000, _, _) => 73 + skip,
+        (0xF000, 0x1E, _) => 86,
+        (0xF000, 0x29, _) => 91,
+        (0xF000, 0x33, _) => 927,
+        (0xF000, 0x55, _) | (0xF000, 0x65, _) => 605,
+        _ => 45,
+    }
+}
+
+/// Approximate time to draw a sprite of `rows` rows once the display wait
+/// is over, in microseconds.
+pub fn vip_draw_cost(rows: u16) -> u32 {
+    340 + 140 * rows as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::Chip;
+
+    #[test]
+    fn skips_cost_more_when_taken() {
+        assert_eq!((vip_cost(0x3A12, false), vip_cost(0x3A12, true)), (55, 64));
+        assert_eq!((vip_cost(0x4A12, false), vip_cost(0x4A12, true)), (55, 64));
+        assert_eq!((vip_cost(0x5AB0, false), vip_cost(0x5AB0, true)), (73, 82));
+        assert_eq!((vip_cost(0x9AB0, false), vip_cost(0x9AB0, true)), (73, 82));
+        assert_eq!((vip_cost(0xEA9E, false), vip_cost(0xEA9E, true)), (73, 82));
+    }
+
+    #[test]
+    fn draws_cost_their_rows() {
+        assert_eq!(vip_cost(0xD120, false), 340);
+        assert_eq!(vip_cost(0xD125, false), 340 + 140 * 5);
+        assert_eq!(vip_cost(0xD12F, false), vip_draw_cost(15));
+    }
+
+    #[test]
+    fn only_skips_are_charged_as_skips() {
+        let mut chip = Chip::new();
+        chip.load_bytes(&[
+            0x12, 0x04, // 200: jump to 204
+            0x00, 0x00, // 202
+            0x30, 0x00, // 204: skip if V0 = 0, which it is
+            0x00, 0x00, // 206
+            0x30, 0x01, // 208: skip if V0 = 1, which it isn't
+        ])
+        .unwrap();
+
+        for (pc, skipped) in [(0x204, false), (0x208, true), (0x20A, false)] {
+            chip.cycle();
+            assert_eq!((chip.pc, chip.skipped), (pc, skipped));
+        }
+    }
+}
@@ -0,0 +1,773 @@
+use std::error::Error;
+
+use crate::chip::{Chip, VIDEO_WIDTH};
+
+/// RAM fitted to the emulated VIP, the 4K expansion most CHIP-8 games need.
+pub const RAM_SIZE: usize = 0x1000;
+/// Where the CHIP-8 interpreter expects programs.
+pub const PROGRAM_ADDRESS: usize = 0x200;
+const INTERPRETER_SIZE: usize = 0x200;
+const MONITOR_SIZE: usize = 0x200;
+
+// CDP1861 timing, in 1802 machine cycles and scan lines.
+const CYCLES_PER_LINE: i32 = 14;
+const LINES_PER_FRAME: u32 = 262;
+const DISPLAY_START: u32 = 80;
+const DISPLAY_LINES: u32 = 128;
+/// Cycles of each displayed line left to the CPU; the other 8 are DMA.
+const CYCLES_BEFORE_DMA: i32 = 6;
+/// The 1861 interrupts two lines before the display starts.
+const INTERRUPT_LINE: u32 = DISPLAY_START - 2;
+/// EF1 is asserted for the 4 lines before the display and its last 4.
+const EF1_LINES: u32 = 4;
+
+/// Where the interpreter keeps V0-VF, just below the display page.
+const VARIABLES_ADDRESS: usize = RAM_SIZE - 0x110;
+/// `LDA R5`, with which the interpreter fetches each instruction.
+const FETCH: u8 = 0x45;
+
+/// What the 1802 sees on its memory and I/O lines.
+trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    fn output(&mut self, port: u8, value: u8);
+    fn input(&mut self, port: u8) -> u8;
+    /// Whether flag input EF1-EF4 is asserted.
+    fn flag(&self, n: u8) -> bool;
+}
+
+/// An RCA CDP1802 CPU.
+#[derive(Debug, Clone, Default)]
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    pub d: u8,
+    pub df: bool,
+    pub p: u8,
+    pub x: u8,
+    pub t: u8,
+    pub ie: bool,
+    pub q: bool,
+    pub idle: bool,
+}
+
+impl Cdp1802 {
+    /// The state after a hardware reset.
+    pub fn new() -> Cdp1802 {
+        Cdp1802 {
+            ie: true,
+            ..Cdp1802::default()
+        }
+    }
+
+    /// Takes an interrupt if they're enabled: the return point goes to T,
+    /// and R1 becomes the program counter with R2 as the stack.
+    pub fn interrupt(&mut self) {
+        if self.ie {
+            self.t = self.x << 4 | self.p;
+            self.p = 1;
+            self.x = 2;
+            self.ie = false;
+            self.idle = false;
+        }
+    }
+
+    /// Runs one instruction and returns how many machine cycles it took.
+    fn step(&mut self, bus: &mut impl Bus) -> i32 {
+        if self.idle {
+            return 1;
+        }
+
+        let opcode = self.fetch(bus);
+        let n = (opcode & 0x0F) as usize;
+        let x = self.x as usize;
+
+        match opcode >> 4 {
+            0x0 if n == 0 => self.idle = true,
+            0x0 => self.d = bus.read(self.r[n]),
+            0x1 => self.r[n] = self.r[n].wrapping_add(1),
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1),
+            0x3 => {
+                let target = self.fetch(bus);
+                if self.short_condition(n as u8, bus) {
+                    let p = self.p as usize;
+                    self.r[p] = (self.r[p] & 0xFF00) | target as u16;
+                }
+            }
+            0x4 => {
+                self.d = bus.read(self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            0x5 => bus.write(self.r[n], self.d),
+            0x6 => match n {
+                0x0 => self.r[x] = self.r[x].wrapping_add(1),
+                0x1..=0x7 => {
+                    let value = bus.read(self.r[x]);
+                    bus.output(n as u8, value);
+                    self.r[x] = self.r[x].wrapping_add(1);
+                }
+                0x8 => {}
+                _ => {
+                    self.d = bus.input(n as u8 - 8);
+                    bus.write(self.r[x], self.d);
+                }
+            },
+            0x7 => self.execute_7n(n, bus),
+            0x8 => self.d = self.r[n] as u8,
+            0x9 => self.d = (self.r[n] >> 8) as u8,
+            0xA => self.r[n] = (self.r[n] & 0xFF00) | self.d as u16,
+            0xB => self.r[n] = (self.r[n] & 0x00FF) | (self.d as u16) << 8,
+            0xC => {
+                self.execute_long(n as u8, bus);
+                return 3;
+            }
+            0xD => self.p = n as u8,
+            0xE => self.x = n as u8,
+            _ => self.execute_fn(n, bus),
+        }
+
+        2
+    }
+
+    fn fetch(&mut self, bus: &mut impl Bus) -> u8 {
+        let p = self.p as usize;
+        let byte = bus.read(self.r[p]);
+        self.r[p] = self.r[p].wrapping_add(1);
+        byte
+    }
+
+    /// The condition of short branch `3N`.
+    fn short_condition(&self, n: u8, bus: &impl Bus) -> bool {
+        let condition = match n & 0x7 {
+            0x0 => true,
+            0x1 => self.q,
+            0x2 => self.d == 0,
+            0x3 => self.df,
+            flag => bus.flag(flag - 3),
+        };
+
+        // 38-3F are the inverses: SKP, BNQ, BNZ, BNF and BN1-BN4.
+        condition != (n & 0x8 != 0)
+    }
+
+    fn execute_7n(&mut self, n: usize, bus: &mut impl Bus) {
+        let x = self.x as usize;
+
+        match n {
+            0x0 | 0x1 => {
+                let t = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+                self.x = t >> 4;
+                self.p = t & 0x0F;
+                self.ie = n == 0x0;
+            }
+            0x2 => {
+                self.d = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+            }
+            0x3 => {
+                bus.write(self.r[x], self.d);
+                self.r[x] = self.r[x].wrapping_sub(1);
+            }
+            0x4 => {
+                let value = bus.read(self.r[x]);
+                self.add(value, self.df as u8);
+            }
+            0x5 => {
+                let value = bus.read(self.r[x]);
+                self.subtract(value, self.d, !self.df);
+            }
+            0x6 => {
+                let carry = self.d & 0x01 != 0;
+                self.d = self.d >> 1 | (self.df as u8) << 7;
+                self.df = carry;
+            }
+            0x7 => {
+                let value = bus.read(self.r[x]);
+                self.subtract(self.d, value, !self.df);
+            }
+            0x8 => bus.write(self.r[x], self.t),
+            0x9 => {
+                self.t = self.x << 4 | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            }
+            0xA => self.q = false,
+            0xB => self.q = true,
+            0xC => {
+                let value = self.fetch(bus);
+                self.add(value, self.df as u8);
+            }
+            0xD => {
+                let value = self.fetch(bus);
+                self.subtract(value, self.d, !self.df);
+            }
+            0xE => {
+                let carry = self.d & 0x80 != 0;
+                self.d = self.d << 1 | self.df as u8;
+                self.df = carry;
+            }
+            _ => {
+                let value = self.fetch(bus);
+                self.subtract(self.d, value, !self.df);
+            }
+        }
+    }
+
+    /// Long branches and skips, `CN`.
+    fn execute_long(&mut self, n: u8, bus: &mut impl Bus) {
+        let p = self.p as usize;
+        let condition = match n & 0x3 {
+            0x0 => n & 0x4 == 0,
+            0x1 => self.q,
+            0x2 => self.d == 0,
+            _ => self.df,
+        };
+
+        match n {
+            // LBR, LBQ, LBZ, LBDF and, inverted, LSKP, LBNQ, LBNZ, LBNF.
+            0x0..=0x3 | 0x8..=0xB => {
+                if condition != (n & 0x8 != 0) {
+                    let high = bus.read(self.r[p]);
+                    let low = bus.read(self.r[p].wrapping_add(1));
+                    self.r[p] = (high as u16) << 8 | low as u16;
+                } else {
+                    self.r[p] = self.r[p].wrapping_add(2);
+                }
+            }
+            // NOP, LSNQ, LSNZ, LSNF, LSIE, LSQ, LSZ and LSDF.
+            _ => {
+                let skip = match n {
+                    0x4 => false,
+                    0xC => self.ie,
+                    0x5..=0x7 => !condition,
+                    _ => condition,
+                };
+                if skip {
+                    self.r[p] = self.r[p].wrapping_add(2);
+                }
+            }
+        }
+    }
+
+    /// Logic and arithmetic, `FN`.
+    fn execute_fn(&mut self, n: usize, bus: &mut impl Bus) {
+        // The shifts F6 and FE take no operand.
+        match n {
+            0x6 => {
+                self.df = self.d & 0x01 != 0;
+                self.d >>= 1;
+                return;
+            }
+            0xE => {
+                self.df = self.d & 0x80 != 0;
+                self.d <<= 1;
+                return;
+            }
+            _ => {}
+        }
+
+        // F0-F7 work on M(R(X)), F8-FF on the immediate byte.
+        let value = if n < 0x8 {
+            bus.read(self.r[self.x as usize])
+        } else {
+            self.fetch(bus)
+        };
+
+        match n & 0x7 {
+            0x0 => self.d = value,
+            0x1 => self.d |= value,
+            0x2 => self.d &= value,
+            0x3 => self.d ^= value,
+            0x4 => self.add(value, 0),
+            0x5 => self.subtract(value, self.d, false),
+            _ => self.subtract(self.d, value, false),
+        }
+    }
+
+    fn add(&mut self, value: u8, carry: u8) {
+        let sum = self.d as u16 + value as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    /// `D = minuend - subtrahend - borrow`, setting DF when nothing was
+    /// borrowed.
+    fn subtract(&mut self, minuend: u8, subtrahend: u8, borrow: bool) {
+        let difference = minuend as i16 - subtrahend as i16 - borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+}
+
+/// The VIP's I/O lines and latches, and its monitor ROM.
+#[derive(Debug, Clone)]
+struct Io {
+    monitor: Option<Vec<u8>>,
+    /// After reset the monitor ROM also appears at 0000 until the CPU first
+    /// addresses the upper half of memory.
+    monitor_at_zero: bool,
+    display_on: bool,
+    ef1: bool,
+    key_latch: u8,
+    keypad: [bool; 16],
+}
+
+impl Io {
+    /// What the CPU would read at `address` with `ram` fitted, without the
+    /// side effects of reading it.
+    fn peek(&self, ram: &[u8], address: u16) -> u8 {
+        match &self.monitor {
+            Some(monitor) if address & 0x8000 != 0 || self.monitor_at_zero => {
+                monitor[address as usize % MONITOR_SIZE]
+            }
+            _ if address & 0x8000 != 0 => 0xFF,
+            _ => ram[address as usize % RAM_SIZE],
+        }
+    }
+}
+
+/// Memory and I/O of the VIP around the CPU.
+struct VipBus<'a> {
+    ram: &'a mut [u8],
+    io: &'a mut Io,
+}
+
+impl Bus for VipBus<'_> {
+    fn read(&mut self, address: u16) -> u8 {
+        if address & 0x8000 != 0 {
+            self.io.monitor_at_zero = false;
+        }
+        self.io.peek(self.ram, address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address & 0x8000 == 0 {
+            self.ram[address as usize % RAM_SIZE] = value;
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.io.display_on = false,
+            2 => self.io.key_latch = value & 0x0F,
+            _ => {}
+        }
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.io.display_on = true;
+        }
+        0xFF
+    }
+
+    fn flag(&self, n: u8) -> bool {
+        match n {
+            1 => self.io.ef1,
+            3 => self.io.keypad[self.io.key_latch as usize],
+            _ => false,
+        }
+    }
+}
+
+/// The CHIP-8 state the interpreter keeps, as last copied into the `Chip`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Shown {
+    registers: [u8; 16],
+    index: u32,
+    pc: u16,
+    delay_timer: u8,
+    sound_timer: u8,
+}
+
+/// A COSMAC VIP running the original CHIP-8 interpreter in place of a
+/// `Chip`'s instruction set: a CDP1802 with the monitor ROM at 8000, a
+/// CDP1861 display fed by DMA and the hex keypad.
+///
+/// The machine's `memory` is the VIP's RAM, the display is shown in
+/// `video` and keys are read from `keypad`, so frontends drive it like any
+/// other `Chip`. V0-VF, I, PC and the timers are copied out of the
+/// interpreter's work area whenever the machine stops and at the end of
+/// each frame, and changes made to them are copied back before it runs on.
+/// The call stack isn't.
+///
+/// The interpreter image (512 bytes, loaded at 0000) and monitor ROM aren't
+/// distributed with the emulator. Without the monitor the machine starts
+/// the interpreter directly, with R1 pointing at the top page of RAM as
+/// the monitor leaves it.
+#[derive(Debug, Clone)]
+pub struct Vip {
+    pub cpu: Cdp1802,
+    io: Io,
+    /// The scan line the frame has reached, whether it has been set up,
+    /// and whether it ends in display DMA.
+    line: u32,
+    line_started: bool,
+    dma: bool,
+    /// Machine cycles left to the CPU in the current line, negative when
+    /// the last instruction ran past them.
+    budget: i32,
+    shown: Shown,
+}
+
+impl Vip {
+    /// Fits `chip`, which must load programs at 0x200, with a VIP running
+    /// `interpreter`, copied to the bottom of memory.
+    pub fn install(
+        chip: &mut Chip,
+        interpreter: &[u8],
+        monitor: Option<Vec<u8>>,
+    ) -> Result<(), Box<dyn Error>> {
+        if interpreter.len() > INTERPRETER_SIZE {
+            return Err(format!(
+                "interpreter image is {} bytes, at most {} fit below the program",
+                interpreter.len(),
+                INTERPRETER_SIZE
+            )
+            .into());
+        }
+        if let Some(monitor) = &monitor {
+            if monitor.len() != MONITOR_SIZE {
+                return Err(format!("monitor ROM must be {} bytes", MONITOR_SIZE).into());
+            }
+        }
+        if chip.load_address as usize != PROGRAM_ADDRESS || chip.memory.len() < RAM_SIZE {
+            return Err(format!("VIP programs load at {:03X}", PROGRAM_ADDRESS).into());
+        }
+
+        chip.write_memory(0, interpreter);
+        chip.video.fill(0);
+
+        let mut cpu = Cdp1802::new();
+        if monitor.is_none() {
+            cpu.r[1] = (RAM_SIZE - 0x100) as u16;
+        }
+
+        chip.vip = Some(Box::new(Vip {
+            cpu,
+            io: Io {
+                monitor_at_zero: monitor.is_some(),
+                monitor,
+                display_on: false,
+                ef1: false,
+                key_latch: 0,
+                keypad: [false; 16],
+            },
+            line: 0,
+            line_started: false,
+            dma: false,
+            budget: 0,
+            shown: Shown::default(),
+        }));
+        Ok(())
+    }
+
+    /// Runs the rest of the frame's 262 scan lines, applying queued key
+    /// events as the frame reaches them. `stop` is called whenever the
+    /// interpreter is about to fetch a CHIP-8 instruction. If it returns
+    /// true the frame is left there for the next call to pick up, and this
+    /// returns true.
+    pub fn run_frame_until<F: FnMut(&mut Chip) -> bool>(
+        &mut self,
+        chip: &mut Chip,
+        mut stop: F,
+    ) -> bool {
+        self.copy_in(chip);
+
+        while self.line < LINES_PER_FRAME {
+            if !self.line_started {
+                self.start_line(chip);
+            }
+
+            while self.budget > 0 {
+                if self.at_instruction(chip) {
+                    self.copy_out(chip);
+                    if stop(chip) {
+                        return true;
+                    }
+                    self.copy_in(chip);
+                }
+
+                let mut bus = VipBus {
+                    ram: &mut chip.memory[..RAM_SIZE],
+                    io: &mut self.io,
+                };
+                self.budget -= self.cpu.step(&mut bus);
+            }
+
+            if self.dma {
+                self.dma_line(chip, self.line - DISPLAY_START);
+            }
+            self.line += 1;
+            self.line_started = false;
+        }
+
+        self.line = 0;
+        chip.keypad.apply_until(1.0);
+        if !self.io.display_on {
+            chip.video.fill(0);
+        }
+        self.copy_out(chip);
+        false
+    }
+
+    /// Runs until the interpreter has carried out a CHIP-8 instruction and
+    /// is about to fetch the next, giving up after two frames.
+    pub fn step_instruction(&mut self, chip: &mut Chip) {
+        let mut fetched = false;
+        for _ in 0..2 {
+            if self.run_frame_until(chip, |_| std::mem::replace(&mut fetched, true)) {
+                return;
+            }
+        }
+    }
+
+    fn start_line(&mut self, chip: &mut Chip) {
+        if self.line == 0 {
+            chip.keypad.begin_frame();
+        }
+        chip.keypad
+            .apply_until(self.line as f32 / LINES_PER_FRAME as f32);
+        self.io.keypad = std::array::from_fn(|key| chip.keypad.is_down(key as u8));
+
+        let display_end = DISPLAY_START + DISPLAY_LINES;
+        self.io.ef1 = self.io.display_on
+            && ((DISPLAY_START - EF1_LINES..DISPLAY_START).contains(&self.line)
+                || (display_end - EF1_LINES..display_end).contains(&self.line));
+
+        if self.line == INTERRUPT_LINE && self.io.display_on {
+            self.cpu.interrupt();
+        }
+
+        self.dma = self.io.display_on && (DISPLAY_START..display_end).contains(&self.line);
+        self.budget += if self.dma {
+            CYCLES_BEFORE_DMA
+        } else {
+            CYCLES_PER_LINE
+        };
+        self.line_started = true;
+    }
+
+    /// Whether the interpreter is about to fetch the first byte of a CHIP-8
+    /// instruction, which it does with `LDA R5` while R5 holds the
+    /// instruction's even address.
+    fn at_instruction(&self, chip: &Chip) -> bool {
+        let next = self.cpu.r[self.cpu.p as usize];
+        !self.cpu.idle
+            && self.cpu.r[5] & 1 == 0
+            && self.io.peek(&chip.memory[..RAM_SIZE], next) == FETCH
+    }
+
+    /// Shows the interpreter's V0-VF, I, PC and timers in `chip`.
+    fn copy_out(&mut self, chip: &mut Chip) {
+        let mut registers = [0; 16];
+        registers.copy_from_slice(&chip.memory[VARIABLES_ADDRESS..VARIABLES_ADDRESS + 16]);
+        self.shown = Shown {
+            registers,
+            index: self.cpu.r[0xA] as u32,
+            pc: self.cpu.r[5],
+            delay_timer: (self.cpu.r[8] >> 8) as u8,
+            sound_timer: self.cpu.r[8] as u8,
+        };
+
+        chip.registers = self.shown.registers;
+        chip.index = self.shown.index;
+        chip.pc = self.shown.pc;
+        chip.delay_timer = self.shown.delay_timer;
+        chip.sound_timer = self.shown.sound_timer;
+    }
+
+    /// Hands whatever was changed in `chip` since `copy_out` back to the
+    /// interpreter.
+    fn copy_in(&mut self, chip: &mut Chip) {
+        if chip.registers != self.shown.registers {
+            chip.memory[VARIABLES_ADDRESS..VARIABLES_ADDRESS + 16].copy_from_slice(&chip.registers);
+        }
+        if chip.index != self.shown.index {
+            self.cpu.r[0xA] = chip.index as u16;
+        }
+        if chip.pc != self.shown.pc {
+            self.cpu.r[5] = chip.pc;
+        }
+        if chip.delay_timer != self.shown.delay_timer {
+            self.cpu.r[8] = (self.cpu.r[8] & 0x00FF) | (chip.delay_timer as u16) << 8;
+        }
+        if chip.sound_timer != self.shown.sound_timer {
+            self.cpu.r[8] = (self.cpu.r[8] & 0xFF00) | chip.sound_timer as u16;
+        }
+    }
+
+    /// The 1861 fetches a line of 8 bytes from R0. The interpreter shows
+    /// each of its 32 rows on 4 lines, so every fourth line is kept.
+    fn dma_line(&mut self, chip: &mut Chip, line: u32) {
+        self.cpu.idle = false;
+
+        let mut bus = VipBus {
+            ram: &mut chip.memory[..RAM_SIZE],
+            io: &mut self.io,
+        };
+        for byte_index in 0..8 {
+            let byte = bus.read(self.cpu.r[0]);
+            self.cpu.r[0] = self.cpu.r[0].wrapping_add(1);
+
+            if line.is_multiple_of(4) {
+                let row = (line / 4) as usize * VIDEO_WIDTH as usize;
+                for bit in 0..8 {
+                    let lit = byte & (0x80 >> bit) != 0;
+                    chip.video[row + byte_index * 8 + bit] = if lit { 0xFF } else { 0 };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 64K of flat RAM and no I/O.
+    struct TestBus {
+        ram: Vec<u8>,
+    }
+
+    impl Bus for TestBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.ram[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.ram[address as usize] = value;
+        }
+
+        fn output(&mut self, _port: u8, _value: u8) {}
+
+        fn input(&mut self, _port: u8) -> u8 {
+            0
+        }
+
+        fn flag(&self, _n: u8) -> bool {
+            false
+        }
+    }
+
+    /// Runs `program` from 0000 with X pointing at `memory_operand`, and
+    /// returns D, DF and the program counter after each instruction.
+    fn run(program: &[u8], memory_operand: u8, steps: usize) -> Vec<(u8, bool, u16)> {
+        let mut bus = TestBus {
+            ram: vec![0; 0x10000],
+        };
+        bus.ram[..program.len()].copy_from_slice(program);
+        bus.ram[0x100] = memory_operand;
+
+        let mut cpu = Cdp1802::new();
+        cpu.x = 2;
+        cpu.r[2] = 0x100;
+        (0..steps)
+            .map(|_| {
+                cpu.step(&mut bus);
+                (cpu.d, cpu.df, cpu.r[0])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shifts_take_no_operand() {
+        let program = [
+            0xF8, 0x81, // LDI 81
+            0xFE, // SHL
+            0xF8, 0x81, // LDI 81
+            0xF6, // SHR
+            0xF8, 0x40, // LDI 40
+            0xFE, // SHL
+        ];
+        let states = run(&program, 0, 6);
+        assert_eq!(states[1], (0x02, true, 3));
+        assert_eq!(states[3], (0x40, true, 6));
+        assert_eq!(states[5], (0x80, false, 9));
+    }
+
+    #[test]
+    fn subtractions_set_df_when_nothing_is_borrowed() {
+        let program = [
+            0xF8, 0x03, // LDI 03
+            0xF5, // SD: M - D = 05 - 03
+            0xF8, 0x07, // LDI 07
+            0xF5, // SD: 05 - 07
+            0xF8, 0x07, // LDI 07
+            0xF7, // SM: D - M = 07 - 05
+            0xF8, 0x03, // LDI 03
+            0xF7, // SM: 03 - 05
+            0xF8, 0x09, // LDI 09
+            0xFF, 0x04, // SMI 04: 09 - 04
+            0xF8, 0x04, // LDI 04
+            0xFD, 0x09, // SDI 09: 09 - 04
+        ];
+        let states = run(&program, 0x05, 12);
+        assert_eq!(states[1], (0x02, true, 3));
+        assert_eq!(states[3], (0xFE, false, 6));
+        assert_eq!(states[5], (0x02, true, 9));
+        assert_eq!(states[7], (0xFE, false, 12));
+        assert_eq!(states[9], (0x05, true, 16));
+        assert_eq!(states[11], (0x05, true, 20));
+    }
+
+    /// A stand-in for the interpreter that runs only `6XKK`, fetching the
+    /// way the real one does.
+    #[rustfmt::skip]
+    const LOAD_ONLY_INTERPRETER: [u8; 19] = [
+        0xF8, 0x02, 0xB5, // R5.1 = 02
+        0xF8, 0x00, 0xA5, // R5.0 = 00
+        0xF8, 0x0E, 0xB6, // R6.1 = 0E
+        0x45, 0xFA, 0x0F, // fetch 6X, keep X
+        0xFC, 0xF0, 0xA6, // R6.0 = F0 + X
+        0x45, 0x56, // fetch KK into VX
+        0x30, 0x09, // loop
+    ];
+
+    fn vip_chip() -> Chip {
+        let mut chip = Chip::default();
+        chip.load_bytes(&[0x6A, 0x12, 0x6B, 0x34, 0x6D, 0x56])
+            .unwrap();
+        Vip::install(&mut chip, &LOAD_ONLY_INTERPRETER, None).unwrap();
+        chip
+    }
+
+    #[test]
+    fn stops_before_each_instruction_with_state_copied_out() {
+        let mut chip = vip_chip();
+        let mut stops = Vec::new();
+        let stopped = chip.run_frame_until(0, |chip| {
+            stops.push(chip.pc);
+            stops.len() == 3
+        });
+
+        assert!(stopped);
+        assert_eq!(stops, [0x200, 0x202, 0x204]);
+        assert_eq!(chip.registers[0xA..0xC], [0x12, 0x34]);
+    }
+
+    #[test]
+    fn changes_to_the_chip_reach_the_interpreter() {
+        let mut chip = vip_chip();
+        chip.run_frame_until(0, |chip| chip.pc == 0x204);
+
+        chip.pc = 0x202;
+        chip.registers[0xA] = 0;
+        chip.registers[0xC] = 0x99;
+        chip.cycle();
+
+        assert_eq!(chip.pc, 0x204);
+        assert_eq!(chip.registers[0xA..0xD], [0x00, 0x34, 0x99]);
+        assert_eq!(chip.memory[VARIABLES_ADDRESS + 0xC], 0x99);
+    }
+
+    #[test]
+    fn cycle_runs_one_instruction() {
+        let mut chip = vip_chip();
+        chip.cycle();
+        assert_eq!((chip.pc, chip.registers[0xA]), (0x202, 0x12));
+        chip.cycle();
+        assert_eq!((chip.pc, chip.registers[0xB]), (0x204, 0x34));
+    }
+}